@@ -0,0 +1,97 @@
+//! Exponential backoff with jitter for retrying failed connections.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Computes delays that grow exponentially with each attempt, up to a maximum.
+///
+/// Each delay is picked at random between half and all of the exponential delay, so that a fleet
+/// of runners that lost their connection at the same time don't all reconnect at the same time.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// The delay before the first retry.
+    initial: Duration,
+    /// The maximum delay between retries.
+    max: Duration,
+    /// The number of retries since the last reset.
+    attempt: u32,
+}
+
+impl Backoff {
+    /// Creates a new backoff that starts at `initial` and never exceeds `max`.
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Returns the number of retries since the last reset.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Returns the delay before the next retry and counts it as an attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.ceiling();
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = delay / 2;
+
+        half + rand::thread_rng().gen_range(Duration::from_secs(0)..=half)
+    }
+
+    /// Resets the delay to the initial delay, e.g. after a successful connection.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Returns the delay before the next retry without jitter.
+    fn ceiling(&self) -> Duration {
+        let factor = 2u32.checked_pow(self.attempt).unwrap_or(u32::MAX);
+
+        self.initial
+            .checked_mul(factor)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_grow_exponentially() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+        for ceiling in [1, 2, 4, 8, 16, 32, 60, 60].iter() {
+            let ceiling = Duration::from_secs(*ceiling);
+            let delay = backoff.next_delay();
+
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn it_should_not_overflow() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= Duration::from_secs(60));
+        }
+    }
+
+    #[test]
+    fn it_should_reset() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+
+        assert_eq!(backoff.attempt(), 0);
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
@@ -1,5 +1,23 @@
 use structopt::StructOpt;
 
+/// Parses a number of seconds that must be at least 1.
+fn parse_secs(value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(secs) => Ok(secs),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Parses a number of tasks, which must be at least 1.
+fn parse_tasks(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(tasks) => Ok(tasks),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub struct Opts {
     /// gRPC host to connect to
@@ -13,4 +31,63 @@ pub struct Opts {
     /// The runners authorization token
     #[structopt(long = "grpc-token", env = "WEBALERT_GRPC_TOKEN", required = true)]
     pub grpc_token: String,
+
+    /// The number of seconds between heartbeats sent to the server
+    #[structopt(
+        long = "heartbeat-interval",
+        env = "WEBALERT_HEARTBEAT_INTERVAL",
+        default_value = "15",
+        parse(try_from_str = parse_secs)
+    )]
+    pub heartbeat_interval_secs: u64,
+
+    /// The maximum number of seconds to wait between attempts at reconnecting to the server
+    #[structopt(
+        long = "max-reconnect-delay",
+        env = "WEBALERT_MAX_RECONNECT_DELAY",
+        default_value = "60",
+        parse(try_from_str = parse_secs)
+    )]
+    pub max_reconnect_delay_secs: u64,
+
+    /// The number of seconds running tasks are given to finish when shutting down
+    #[structopt(
+        long = "shutdown-grace-period",
+        env = "WEBALERT_SHUTDOWN_GRACE_PERIOD",
+        default_value = "30"
+    )]
+    pub shutdown_grace_period_secs: u64,
+
+    /// The maximum number of tasks to run at a time
+    #[structopt(
+        long = "max-tasks",
+        env = "WEBALERT_MAX_TASKS",
+        default_value = "1",
+        parse(try_from_str = parse_tasks)
+    )]
+    pub max_tasks: u32,
+
+    /// The number of seconds a page fetched without a browser is given to load
+    #[structopt(
+        long = "http-timeout",
+        env = "WEBALERT_HTTP_TIMEOUT",
+        default_value = "30"
+    )]
+    pub http_timeout_secs: u64,
+
+    /// The maximum number of redirects followed when fetching a page without a browser
+    #[structopt(
+        long = "http-max-redirects",
+        env = "WEBALERT_HTTP_MAX_REDIRECTS",
+        default_value = "10"
+    )]
+    pub http_max_redirects: usize,
+
+    /// The maximum size in bytes of a page fetched without a browser
+    #[structopt(
+        long = "http-max-body-size",
+        env = "WEBALERT_HTTP_MAX_BODY_SIZE",
+        default_value = "10485760"
+    )]
+    pub http_max_body_size: usize,
 }
@@ -10,6 +10,32 @@ pub struct Error {
     source: TracedError<Kind>,
 }
 
+impl Error {
+    /// Describes the error along with the errors that caused it, e.g.
+    /// `Could not load page: timeout`.
+    pub fn describe(&self) -> String {
+        let mut description = self.to_string();
+        // Skip the traced error and its span trace, which lead to the source of the kind
+        let mut source = std::error::Error::source(self)
+            .and_then(|traced| traced.source())
+            .and_then(|span_trace| span_trace.source());
+
+        while let Some(error) = source {
+            let message = error.to_string();
+
+            // Many errors already include their source in their message
+            if !description.contains(&message) {
+                description.push_str(": ");
+                description.push_str(&message);
+            }
+
+            source = error.source();
+        }
+
+        description
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Display::fmt(&self.source, fmt)
@@ -36,10 +62,9 @@ pub enum Kind {
     /// Occurs when there's a general error from the tonic transport layer.
     #[error("RPC transport error")]
     RpcTransportError(#[from] tonic::transport::Error),
-    /// This error occurs when the runner tries to send an announcement message but it fails for a
-    /// non-specific reason.
-    #[error("Could not send announce rpc message")]
-    RpcAnnounceFailed,
+    /// Occurs when the server refuses the runner, e.g. because its token is invalid.
+    #[error("The server refused the runner")]
+    RpcRefused(#[source] tonic::Status),
     /// Occurs when unable to spawn a new chromedriver process
     #[error("Could not spawn a new chromedriver process")]
     CouldNotSpawnChromeDriver(#[source] io::Error),
@@ -49,9 +74,74 @@ pub enum Kind {
     /// Occurs when trying to spawn a chromedriver and the previous child process is still alive
     #[error("chromedriver is already running")]
     ChromeDriverAlreadyRunning,
+    /// Occurs when trying to run a task before chromedriver has been spawned
+    #[error("chromedriver is not running")]
+    ChromeDriverNotRunning,
+    /// Occurs when the webdriver fails to load the page of a task
+    #[error("Could not load page")]
+    PageLoadFailed(#[source] thirtyfour::error::WebDriverError),
+    /// Occurs when no element on the page of a task matches a selector of its alert
+    #[error("No element matches the selector `{0}`")]
+    ElementNotFound(String),
+    /// Occurs when a selector of a task has an unknown kind or extraction, or lacks an attribute
+    #[error("The selector `{0}` is invalid")]
+    InvalidSelector(String),
+    /// Occurs when a selector of a task can't be evaluated on its page, e.g. an XPath selector on
+    /// a page that was fetched over HTTP
+    #[error("The selector `{0}` can't be used on this page")]
+    UnsupportedSelector(String),
+    /// Occurs when a task with JSONPath selectors fetches a page that isn't a JSON document
+    #[error("The page isn't a valid JSON document")]
+    InvalidJson(#[source] serde_json::Error),
+    /// Occurs when the page of a task of a feed alert isn't well-formed XML
+    #[error("The page isn't a valid feed")]
+    InvalidFeed(#[source] roxmltree::Error),
+    /// Occurs when the page of a task of a feed alert is XML, but not an RSS or Atom feed
+    #[error("The page isn't an RSS or Atom feed, but a `{0}` document")]
+    UnknownFeedFormat(String),
+    /// Occurs when the webdriver fails to extract the content of the element of a task
+    #[error("Could not extract the content of the element")]
+    ExtractionFailed(#[source] thirtyfour::error::WebDriverError),
+    /// Occurs when the webdriver fails to take a screenshot of the page or element of a task
+    #[error("Could not take a screenshot")]
+    ScreenshotFailed(#[source] thirtyfour::error::WebDriverError),
+    /// Occurs when the HTTP client used to fetch pages can't be created
+    #[error("Could not create the HTTP client")]
+    HttpClientUnavailable(#[source] reqwest::Error),
+    /// Occurs when the page of a task can't be fetched over HTTP
+    #[error("Could not fetch page")]
+    FetchFailed(#[source] reqwest::Error),
+    /// Occurs when the body of a page fetched over HTTP is larger than the maximum size
+    #[error("The page is larger than {0} bytes")]
+    BodyTooLarge(usize),
     #[error("Could not get the hostname")]
     HostnameUnavailable,
     /// Non-specialized IO error
     #[error("IO error")]
     IoError(#[from] io::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_describe_sources() {
+        let error = Error::from(Kind::CouldNotSpawnChromeDriver(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no such file",
+        )));
+
+        assert_eq!(
+            error.describe(),
+            "Could not spawn a new chromedriver process: no such file"
+        );
+    }
+
+    #[test]
+    fn it_should_describe_errors_without_sources() {
+        let error = Error::from(Kind::ChromeDriverNotRunning);
+
+        assert_eq!(error.describe(), "chromedriver is not running");
+    }
+}
@@ -0,0 +1,456 @@
+//! Extracting values from pages with the selectors of tasks
+//!
+//! Pages loaded in a browser can be queried with CSS, XPath and JavaScript selectors, while pages
+//! fetched over HTTP are parsed and queried with CSS selectors, or with JSONPath selectors if
+//! they're JSON documents.
+
+use scraper::{Html, Selector as CssSelector};
+use serde_json::{Map, Value};
+use thirtyfour::prelude::ScriptRet;
+use thirtyfour::{By, WebDriver, WebDriverCommands, WebElement};
+
+use crate::grpc::runner::{
+    selector::{Extraction, Kind as SelectorKind},
+    ExtractedValue, Selector,
+};
+use crate::Kind;
+
+/// What the selectors of a task extracted from a page.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Extracted {
+    /// The value extracted by each selector, in order.
+    pub values: Vec<ExtractedValue>,
+    /// The values as text, which is the value itself if there's a single selector, or a line per
+    /// selector prefixed by its name if there are more.
+    pub text: String,
+    /// The HTML of the elements the values were extracted from, with values that weren't
+    /// extracted from an element included as `<output>` elements.
+    pub html: String,
+}
+
+/// What a selector matched on a page.
+enum Matched<'a> {
+    /// The selector matched elements, in document order.
+    Elements(Vec<WebElement<'a>>),
+    /// A JavaScript expression evaluated to something other than elements.
+    Value(Value),
+}
+
+/// Extracts a value with each of `selectors` from the page loaded in `driver`.
+pub async fn extract(driver: &WebDriver, selectors: &[Selector]) -> Result<Extracted, Kind> {
+    let mut extracted = Vec::with_capacity(selectors.len());
+
+    for selector in selectors {
+        extracted.push(extract_one(driver, selector).await?);
+    }
+
+    Ok(assemble(selectors, extracted))
+}
+
+/// Extracts a value with each of `selectors` from the HTML `document` of a page that was fetched
+/// without a browser.
+///
+/// Only CSS selectors are supported, and the text of elements is their text content with runs of
+/// whitespace collapsed, as there's no rendering to go by.
+pub fn extract_document(document: &str, selectors: &[Selector]) -> Result<Extracted, Kind> {
+    let document = Html::parse_document(document);
+    let extracted = selectors
+        .iter()
+        .map(|selector| extract_one_from_document(&document, selector))
+        .collect::<Result<_, _>>()?;
+
+    Ok(assemble(selectors, extracted))
+}
+
+/// Extracts a value with each of `selectors`, which must all be JSONPath selectors, from the JSON
+/// `document` of a page that was fetched without a browser.
+///
+/// The HTML of what was extracted is a JSON object of the values extracted by each selector,
+/// by the name of the selector.
+pub fn extract_json(document: &str, selectors: &[Selector]) -> Result<Extracted, Kind> {
+    let document: Value = serde_json::from_str(document).map_err(Kind::InvalidJson)?;
+    let mut values = Vec::with_capacity(selectors.len());
+    let mut extracted = Map::new();
+
+    for selector in selectors {
+        let selected = select_json(&document, selector)?;
+        let value = match &selected {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+
+        values.push(ExtractedValue {
+            name: selector.name.clone(),
+            value,
+        });
+        extracted.insert(selector.name.clone(), selected);
+    }
+
+    Ok(Extracted {
+        text: to_text(&values),
+        html: Value::Object(extracted).to_string(),
+        values,
+    })
+}
+
+/// Returns the value of the JSONPath `selector` in `document`, which is the selected value if
+/// there's one, an array of them if there are several, or their number for count extraction.
+fn select_json(document: &Value, selector: &Selector) -> Result<Value, Kind> {
+    let invalid = || Kind::InvalidSelector(selector.name.clone());
+    let extraction = extraction(selector)?;
+
+    match SelectorKind::from_i32(selector.kind) {
+        Some(SelectorKind::Jsonpath) => {}
+        Some(SelectorKind::Unspecified) | None => return Err(invalid()),
+        Some(_) => return Err(Kind::UnsupportedSelector(selector.name.clone())),
+    }
+
+    let mut selected = jsonpath_lib::select(document, &selector.expression)
+        .map_err(|_| invalid())?
+        .into_iter()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    match extraction {
+        Extraction::Count => Ok(Value::from(selected.len())),
+        Extraction::Attribute => Err(invalid()),
+        _ if selected.is_empty() => Err(Kind::ElementNotFound(selector.expression.clone())),
+        _ if selected.len() == 1 => Ok(selected.remove(0)),
+        _ => Ok(Value::Array(selected)),
+    }
+}
+
+/// Returns what `selectors` extracted, given the value and markup each of them extracted.
+fn assemble(selectors: &[Selector], extracted: Vec<(String, String)>) -> Extracted {
+    let mut values = Vec::with_capacity(selectors.len());
+    let mut html = Vec::with_capacity(selectors.len());
+
+    for (selector, (value, markup)) in selectors.iter().zip(extracted) {
+        values.push(ExtractedValue {
+            name: selector.name.clone(),
+            value,
+        });
+        html.push(markup);
+    }
+
+    Extracted {
+        text: to_text(&values),
+        html: html.join("\n"),
+        values,
+    }
+}
+
+/// Returns the extraction mode of `selector`.
+fn extraction(selector: &Selector) -> Result<Extraction, Kind> {
+    Extraction::from_i32(selector.extraction)
+        .filter(|extraction| *extraction != Extraction::Unspecified)
+        .ok_or_else(|| Kind::InvalidSelector(selector.name.clone()))
+}
+
+/// Extracts the value of `selector` from the page loaded in `driver`, and returns it along with
+/// the HTML it was extracted from.
+async fn extract_one(driver: &WebDriver, selector: &Selector) -> Result<(String, String), Kind> {
+    let invalid = || Kind::InvalidSelector(selector.name.clone());
+    let extraction = extraction(selector)?;
+
+    let expression = selector.expression.as_str();
+    let script_ret;
+    let matched = match SelectorKind::from_i32(selector.kind) {
+        Some(SelectorKind::Css) => Matched::Elements(
+            driver
+                .find_elements(By::Css(expression))
+                .await
+                .map_err(Kind::ExtractionFailed)?,
+        ),
+        Some(SelectorKind::Xpath) => Matched::Elements(
+            driver
+                .find_elements(By::XPath(expression))
+                .await
+                .map_err(Kind::ExtractionFailed)?,
+        ),
+        Some(SelectorKind::Javascript) => {
+            script_ret = driver
+                .execute_script(&format!("return ({});", expression))
+                .await
+                .map_err(Kind::ExtractionFailed)?;
+
+            script_matched(&script_ret)
+        }
+        Some(SelectorKind::Jsonpath) => {
+            return Err(Kind::UnsupportedSelector(selector.name.clone()))
+        }
+        Some(SelectorKind::Unspecified) | None => return Err(invalid()),
+    };
+
+    let elements = match (matched, extraction) {
+        (Matched::Elements(elements), Extraction::Count) => {
+            let count = elements.len().to_string();
+            let markup = output(&selector.name, &count);
+
+            return Ok((count, markup));
+        }
+        (Matched::Value(value), Extraction::Count) => {
+            let count = match value {
+                Value::Array(values) => values.len(),
+                Value::Null => 0,
+                _ => 1,
+            };
+            let count = count.to_string();
+            let markup = output(&selector.name, &count);
+
+            return Ok((count, markup));
+        }
+        (Matched::Value(value), _) => {
+            let value = match value {
+                Value::String(value) => value,
+                Value::Null => String::new(),
+                value => value.to_string(),
+            };
+            let markup = output(&selector.name, &value);
+
+            return Ok((value, markup));
+        }
+        (Matched::Elements(elements), _) => elements,
+    };
+
+    let element = elements
+        .first()
+        .ok_or_else(|| Kind::ElementNotFound(selector.expression.clone()))?;
+    let markup = element.outer_html().await.map_err(Kind::ExtractionFailed)?;
+
+    let value = match extraction {
+        Extraction::Text => element.text().await.map_err(Kind::ExtractionFailed)?,
+        Extraction::Html => markup.clone(),
+        Extraction::Attribute if selector.attribute.is_empty() => return Err(invalid()),
+        Extraction::Attribute => element
+            .get_attribute(&selector.attribute)
+            .await
+            .map_err(Kind::ExtractionFailed)?
+            .unwrap_or_default(),
+        Extraction::Count | Extraction::Unspecified => unreachable!("handled above"),
+    };
+
+    Ok((value, markup))
+}
+
+/// Extracts the value of `selector` from the parsed HTML `document`, and returns it along with the
+/// HTML it was extracted from.
+fn extract_one_from_document(
+    document: &Html,
+    selector: &Selector,
+) -> Result<(String, String), Kind> {
+    let invalid = || Kind::InvalidSelector(selector.name.clone());
+    let extraction = extraction(selector)?;
+
+    let css = match SelectorKind::from_i32(selector.kind) {
+        Some(SelectorKind::Css) => {
+            CssSelector::parse(&selector.expression).map_err(|_| invalid())?
+        }
+        Some(SelectorKind::Xpath)
+        | Some(SelectorKind::Javascript)
+        | Some(SelectorKind::Jsonpath) => {
+            return Err(Kind::UnsupportedSelector(selector.name.clone()))
+        }
+        Some(SelectorKind::Unspecified) | None => return Err(invalid()),
+    };
+    let mut elements = document.select(&css);
+
+    if extraction == Extraction::Count {
+        let count = elements.count().to_string();
+        let markup = output(&selector.name, &count);
+
+        return Ok((count, markup));
+    }
+
+    let element = elements
+        .next()
+        .ok_or_else(|| Kind::ElementNotFound(selector.expression.clone()))?;
+    let markup = element.html();
+
+    let value = match extraction {
+        Extraction::Text => element
+            .text()
+            .flat_map(str::split_whitespace)
+            .collect::<Vec<_>>()
+            .join(" "),
+        Extraction::Html => markup.clone(),
+        Extraction::Attribute if selector.attribute.is_empty() => return Err(invalid()),
+        Extraction::Attribute => element
+            .value()
+            .attr(&selector.attribute)
+            .unwrap_or_default()
+            .to_string(),
+        Extraction::Count | Extraction::Unspecified => unreachable!("handled above"),
+    };
+
+    Ok((value, markup))
+}
+
+/// Returns what the result of evaluating a JavaScript selector refers to.
+///
+/// The expression can evaluate to a list of elements, a single element or a value.
+fn script_matched<'a>(ret: &'a ScriptRet<'_>) -> Matched<'a> {
+    if let Ok(elements) = ret.get_elements() {
+        if !elements.is_empty() {
+            return Matched::Elements(elements);
+        }
+    }
+
+    match ret.get_element() {
+        Ok(element) => Matched::Elements(vec![element]),
+        Err(_) => Matched::Value(ret.value().clone()),
+    }
+}
+
+/// Returns `values` as text.
+fn to_text(values: &[ExtractedValue]) -> String {
+    match values {
+        [value] => value.value.clone(),
+        values => values
+            .iter()
+            .map(|value| format!("{}: {}", value.name, value.value))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// Returns an `<output>` element that holds the `value` of the selector with the given `name`.
+fn output(name: &str, value: &str) -> String {
+    format!(
+        "<output name=\"{}\">{}</output>",
+        escape(name),
+        escape(value)
+    )
+}
+
+/// Escapes `text` for use in HTML text and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(name: &str, value: &str) -> ExtractedValue {
+        ExtractedValue {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn it_should_join_values() {
+        assert_eq!(to_text(&[value("content", "Hello")]), "Hello");
+        assert_eq!(
+            to_text(&[value("price", "10 EUR"), value("stock", "3")]),
+            "price: 10 EUR\nstock: 3"
+        );
+    }
+
+    #[test]
+    fn it_should_extract_from_documents() {
+        let selector =
+            |name: &str, kind: SelectorKind, expression: &str, extraction, attr: &str| Selector {
+                name: name.to_string(),
+                kind: kind as i32,
+                expression: expression.to_string(),
+                extraction: extraction as i32,
+                attribute: attr.to_string(),
+            };
+        let document = "<html><body><h1>  Widget\n  </h1><a href=\"/buy\">Buy</a>\
+                        <li>a</li><li>b</li></body></html>";
+
+        let extracted = extract_document(
+            document,
+            &[
+                selector("title", SelectorKind::Css, "h1", Extraction::Text, ""),
+                selector(
+                    "link",
+                    SelectorKind::Css,
+                    "a",
+                    Extraction::Attribute,
+                    "href",
+                ),
+                selector("items", SelectorKind::Css, "li", Extraction::Count, ""),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(extracted.text, "title: Widget\nlink: /buy\nitems: 2");
+        assert_eq!(
+            extracted.html,
+            "<h1>  Widget\n  </h1>\n<a href=\"/buy\">Buy</a>\n<output name=\"items\">2</output>"
+        );
+        assert!(matches!(
+            extract_document(
+                document,
+                &[selector(
+                    "x",
+                    SelectorKind::Xpath,
+                    "//h1",
+                    Extraction::Text,
+                    ""
+                )]
+            ),
+            Err(Kind::UnsupportedSelector(_))
+        ));
+    }
+
+    #[test]
+    fn it_should_extract_from_json() {
+        let selector = |name: &str, expression: &str, extraction: Extraction| Selector {
+            name: name.to_string(),
+            kind: SelectorKind::Jsonpath as i32,
+            expression: expression.to_string(),
+            extraction: extraction as i32,
+            attribute: String::new(),
+        };
+        let document = r#"{"releases": [{"version": "1.2.0", "assets": 3},
+                                        {"version": "1.1.0", "assets": 2}]}"#;
+
+        let extracted = extract_json(
+            document,
+            &[
+                selector("latest", "$.releases[0].version", Extraction::Text),
+                selector("assets", "$.releases[*].assets", Extraction::Text),
+                selector("count", "$.releases[*]", Extraction::Count),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(extracted.text, "latest: 1.2.0\nassets: [3,2]\ncount: 2");
+        assert_eq!(
+            extracted.html,
+            r#"{"latest":"1.2.0","assets":[3,2],"count":2}"#
+        );
+        assert!(matches!(
+            extract_json(document, &[selector("x", "$.missing", Extraction::Text)]),
+            Err(Kind::ElementNotFound(_))
+        ));
+        assert!(matches!(
+            extract_json("<html>", &[]),
+            Err(Kind::InvalidJson(_))
+        ));
+    }
+
+    #[test]
+    fn it_should_escape_outputs() {
+        assert_eq!(
+            output("a\"b", "1 < 2 & 3"),
+            "<output name=\"a&quot;b\">1 &lt; 2 &amp; 3</output>"
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! Parsing the items of RSS and Atom feeds, for tasks of feed alerts
+
+use roxmltree::{Document, Node};
+use scraper::Html;
+use serde_json::{json, Map, Value};
+
+use crate::extract::Extracted;
+use crate::Kind;
+
+/// The namespace of Atom elements.
+const ATOM_NAMESPACE: &str = "http://www.w3.org/2005/Atom";
+
+/// An item of a feed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Item {
+    /// The GUID of the item, or its link or title if it doesn't have one.
+    pub id: String,
+    pub title: String,
+    pub link: String,
+    /// The description of the item, or its summary or content in Atom feeds, as text.
+    pub summary: String,
+    /// The publication date of the item as written in the feed.
+    pub published: Option<String>,
+}
+
+impl Item {
+    /// Returns the item as a JSON object, as sent to the server.
+    fn to_json(&self) -> Value {
+        let mut item = Map::new();
+
+        item.insert("id".to_string(), json!(self.id));
+        item.insert("title".to_string(), json!(self.title));
+        item.insert("link".to_string(), json!(self.link));
+        item.insert("summary".to_string(), json!(self.summary));
+
+        if let Some(published) = &self.published {
+            item.insert("published".to_string(), json!(published));
+        }
+
+        Value::Object(item)
+    }
+}
+
+/// Returns the child elements of `node` with the given local `name`, in any namespace.
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+/// Returns the trimmed text of the first child element of `node` with one of the given local
+/// `names`, in order of preference, that has any.
+fn child_text(node: Node, names: &[&str]) -> Option<String> {
+    names.iter().find_map(|name| {
+        children(node, name)
+            .map(|child| {
+                child
+                    .descendants()
+                    .filter(|node| node.is_text())
+                    .filter_map(|node| node.text())
+                    .collect::<String>()
+                    .trim()
+                    .to_string()
+            })
+            .find(|text| !text.is_empty())
+    })
+}
+
+/// Returns `html` as text, with runs of whitespace collapsed to single spaces.
+fn to_plain_text(html: &str) -> String {
+    let fragment = Html::parse_fragment(html);
+    let text: String = fragment.root_element().text().collect();
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Returns the link of the Atom `entry`, which is the `href` of its alternate link.
+fn atom_link(entry: Node) -> Option<String> {
+    children(entry, "link")
+        .find(|link| matches!(link.attribute("rel"), None | Some("alternate")))
+        .and_then(|link| link.attribute("href"))
+        .map(|href| href.trim().to_string())
+}
+
+/// Returns the item with the given `title`, `link` and raw HTML `summary`, identified by `guid`
+/// if it has one, or `None` if there's nothing to identify it by.
+fn item(
+    guid: Option<String>,
+    title: Option<String>,
+    link: Option<String>,
+    summary: Option<String>,
+    published: Option<String>,
+) -> Option<Item> {
+    let title = title.map(|title| to_plain_text(&title)).unwrap_or_default();
+    let link = link.unwrap_or_default();
+    let id = guid
+        .or_else(|| Some(link.clone()).filter(|link| !link.is_empty()))
+        .or_else(|| Some(title.clone()).filter(|title| !title.is_empty()))?;
+
+    Some(Item {
+        id,
+        title,
+        link,
+        summary: summary
+            .map(|summary| to_plain_text(&summary))
+            .unwrap_or_default(),
+        published,
+    })
+}
+
+/// Parses the items of the RSS 2.0, RSS 1.0 or Atom feed `document`.
+///
+/// Items are identified by their GUID, or by their link or their title if they don't have one,
+/// and items without any of them are left out.
+pub fn parse(document: &str) -> Result<Vec<Item>, Kind> {
+    let document =
+        Document::parse(document.trim_start_matches('\u{feff}')).map_err(Kind::InvalidFeed)?;
+    let root = document.root_element();
+
+    let items = match root.tag_name().name() {
+        // RSS 2.0 and its predecessors
+        "rss" => children(root, "channel")
+            .flat_map(|channel| children(channel, "item"))
+            .filter_map(|node| {
+                item(
+                    child_text(node, &["guid"]),
+                    child_text(node, &["title"]),
+                    child_text(node, &["link"]),
+                    child_text(node, &["description", "encoded"]),
+                    child_text(node, &["pubDate", "date"]),
+                )
+            })
+            .collect(),
+        // RSS 1.0, whose items are siblings of the channel
+        "RDF" => children(root, "item")
+            .filter_map(|node| {
+                item(
+                    node.attribute(("http://www.w3.org/1999/02/22-rdf-syntax-ns#", "about"))
+                        .map(str::to_string),
+                    child_text(node, &["title"]),
+                    child_text(node, &["link"]),
+                    child_text(node, &["description", "encoded"]),
+                    child_text(node, &["date"]),
+                )
+            })
+            .collect(),
+        "feed" if root.tag_name().namespace() == Some(ATOM_NAMESPACE) => children(root, "entry")
+            .filter_map(|node| {
+                item(
+                    child_text(node, &["id"]),
+                    child_text(node, &["title"]),
+                    atom_link(node),
+                    child_text(node, &["summary", "content"]),
+                    child_text(node, &["published", "updated"]),
+                )
+            })
+            .collect(),
+        name => return Err(Kind::UnknownFeedFormat(name.to_string())),
+    };
+
+    Ok(items)
+}
+
+/// Extracts the items of the feed `document` of a page that was fetched without a browser.
+///
+/// The HTML of what was extracted is a JSON object with the `items` of the feed, and the text has
+/// the title and link of each item.
+pub fn extract(document: &str) -> Result<Extracted, Kind> {
+    let items = parse(document)?;
+    let text = items
+        .iter()
+        .map(|item| format!("{}\n{}\n", item.title, item.link))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let html = json!({ "items": items.iter().map(Item::to_json).collect::<Vec<_>>() });
+
+    Ok(Extracted {
+        values: Vec::new(),
+        text,
+        html: html.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_rss_feeds() {
+        let items = parse(
+            r#"<?xml version="1.0"?>
+            <rss version="2.0"><channel><title>Releases</title>
+              <item>
+                <title>Release 1.1</title><link>https://example.com/1.1</link>
+                <guid isPermaLink="false">release-1.1</guid>
+                <description><![CDATA[<p>Fixes  <b>everything</b></p>]]></description>
+                <pubDate>Mon, 16 Aug 2021 10:00:00 GMT</pubDate>
+              </item>
+              <item><title>Release 1.0</title><link>https://example.com/1.0</link></item>
+              <item><description>Nothing to tell them apart by</description></item>
+            </channel></rss>"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            items,
+            vec![
+                Item {
+                    id: "release-1.1".to_string(),
+                    title: "Release 1.1".to_string(),
+                    link: "https://example.com/1.1".to_string(),
+                    summary: "Fixes everything".to_string(),
+                    published: Some("Mon, 16 Aug 2021 10:00:00 GMT".to_string()),
+                },
+                Item {
+                    id: "https://example.com/1.0".to_string(),
+                    title: "Release 1.0".to_string(),
+                    link: "https://example.com/1.0".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_parse_atom_feeds() {
+        let items = parse(
+            r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>Releases</title>
+              <entry>
+                <id>urn:uuid:1</id><title type="html">Release &lt;b&gt;1.1&lt;/b&gt;</title>
+                <link rel="self" href="https://example.com/1.1.atom"/>
+                <link href="https://example.com/1.1"/>
+                <updated>2021-08-16T10:00:00Z</updated>
+                <summary>Fixes</summary>
+              </entry>
+            </feed>"#,
+        )
+        .unwrap();
+
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].id, "urn:uuid:1");
+        assert_eq!(items[0].title, "Release 1.1");
+        assert_eq!(items[0].link, "https://example.com/1.1");
+        assert_eq!(items[0].published.as_deref(), Some("2021-08-16T10:00:00Z"));
+        assert!(matches!(
+            parse("<html><body></body></html>"),
+            Err(Kind::UnknownFeedFormat(name)) if name == "html"
+        ));
+        assert!(matches!(parse("{}"), Err(Kind::InvalidFeed(_))));
+    }
+}
@@ -0,0 +1,194 @@
+//! Fetching pages with plain HTTP requests, for tasks that don't need a browser
+
+use std::time::Duration;
+
+use encoding_rs::{Encoding, UTF_8};
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::redirect::Policy;
+use reqwest::Client;
+
+use crate::{Error, Kind};
+
+/// The number of bytes at the start of a body that are searched for a `<meta>` charset.
+const CHARSET_SNIFF_LENGTH: usize = 1024;
+
+/// The settings of a [`Fetcher`].
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The time a request is given to finish, including reading the body.
+    pub timeout: Duration,
+    /// The maximum number of redirects followed.
+    pub max_redirects: usize,
+    /// The maximum size of a body in bytes, after decompressing it.
+    pub max_body_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            timeout: Duration::from_secs(30),
+            max_redirects: 10,
+            max_body_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// A fetched page.
+#[derive(Debug, Clone)]
+pub struct Page {
+    /// The URL of the page after following redirects.
+    pub final_url: String,
+    /// The HTTP status of the response.
+    pub http_status: u16,
+    /// The body of the response, decoded as text.
+    pub body: String,
+}
+
+/// Fetches pages over HTTP.
+///
+/// Fetchers are cheap to clone, and clones share their connection pool.
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    client: Client,
+    max_body_size: usize,
+}
+
+impl Fetcher {
+    /// Creates a new fetcher with the given `config`.
+    pub fn new(config: &Config) -> Result<Fetcher, Error> {
+        let client = Client::builder()
+            .user_agent(concat!("webalert-runner/", env!("CARGO_PKG_VERSION")))
+            .timeout(config.timeout)
+            .redirect(Policy::limited(config.max_redirects))
+            .gzip(true)
+            .brotli(true)
+            .deflate(true)
+            .build()
+            .map_err(Kind::HttpClientUnavailable)?;
+
+        Ok(Fetcher {
+            client,
+            max_body_size: config.max_body_size,
+        })
+    }
+
+    /// Fetches the page at `url`, following redirects.
+    ///
+    /// Responses with error statuses are pages too, so only failing to get a response at all is
+    /// an error, as is a body that's larger than the maximum size.
+    pub async fn fetch(&self, url: &str) -> Result<Page, Kind> {
+        let mut response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(Kind::FetchFailed)?;
+
+        // The length is of the compressed body, if it's compressed, so it's only a lower bound
+        if let Some(length) = response.content_length() {
+            if length > self.max_body_size as u64 {
+                return Err(Kind::BodyTooLarge(self.max_body_size));
+            }
+        }
+
+        let final_url = response.url().to_string();
+        let http_status = response.status().as_u16();
+        let headers = response.headers().clone();
+        let mut body = Vec::new();
+
+        while let Some(chunk) = response.chunk().await.map_err(Kind::FetchFailed)? {
+            if body.len() + chunk.len() > self.max_body_size {
+                return Err(Kind::BodyTooLarge(self.max_body_size));
+            }
+
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(Page {
+            final_url,
+            http_status,
+            body: decode(&body, &headers),
+        })
+    }
+}
+
+/// Returns the value of the `charset` parameter in `text`, e.g. a `Content-Type` header or the
+/// start of an HTML document.
+fn charset_param(text: &str) -> Option<&str> {
+    let start = text.to_ascii_lowercase().find("charset=")? + "charset=".len();
+    let charset = text[start..].trim_start_matches(['"', '\'']);
+    let end = charset
+        .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':')))
+        .unwrap_or(charset.len());
+
+    Some(&charset[..end]).filter(|charset| !charset.is_empty())
+}
+
+/// Decodes `body` as text.
+///
+/// The encoding is taken from a byte order mark, the charset of the `Content-Type` in `headers`,
+/// or a `<meta>` charset near the start of the body, in that order, and defaults to UTF-8.
+/// Malformed sequences are replaced with the replacement character.
+fn decode(body: &[u8], headers: &HeaderMap) -> String {
+    let from_header = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(charset_param)
+        .and_then(|label| Encoding::for_label(label.as_bytes()));
+    let from_meta = || {
+        let start = &body[..body.len().min(CHARSET_SNIFF_LENGTH)];
+
+        charset_param(&String::from_utf8_lossy(start))
+            .and_then(|label| Encoding::for_label(label.as_bytes()))
+    };
+    let encoding = from_header.or_else(from_meta).unwrap_or(UTF_8);
+
+    // Decoding sniffs the byte order mark, which takes precedence
+    let (text, _, _) = encoding.decode(body);
+
+    text.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use reqwest::header::HeaderValue;
+
+    fn content_type(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn it_should_find_charsets() {
+        assert_eq!(
+            charset_param("text/html; charset=ISO-8859-1"),
+            Some("ISO-8859-1")
+        );
+        assert_eq!(
+            charset_param("<meta charset=\"windows-1252\">"),
+            Some("windows-1252")
+        );
+        assert_eq!(charset_param("text/html"), None);
+    }
+
+    #[test]
+    fn it_should_decode_bodies() {
+        let latin1 = b"<p>Pris: 10 \xe6bler</p>";
+
+        assert_eq!(
+            decode(latin1, &content_type("text/html; charset=iso-8859-1")),
+            "<p>Pris: 10 æbler</p>"
+        );
+        assert_eq!(
+            decode(
+                b"<meta charset=\"windows-1252\"><p>\x80 5</p>",
+                &HeaderMap::new()
+            ),
+            "<meta charset=\"windows-1252\"><p>€ 5</p>"
+        );
+        assert_eq!(decode("<p>æ</p>".as_bytes(), &HeaderMap::new()), "<p>æ</p>");
+    }
+}
@@ -1,20 +1,32 @@
 #![warn(missing_docs)]
 #![allow(clippy::match_like_matches_macro)]
+#![allow(clippy::result_large_err)]
+#![allow(clippy::io_other_error)]
 
 //! A scalable webalert runner that performs actions through a WebDriver.
 
 use std::env;
+use std::io;
+use std::time::Duration;
 
 use color_eyre::{eyre::WrapErr, Report};
 use structopt::StructOpt;
-use tracing::{debug, trace};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{debug, error, info, trace};
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{layer::SubscriberExt, EnvFilter};
 
+mod backoff;
 mod cli;
 mod error;
+mod extract;
+mod feed;
+mod fetch;
 mod grpc;
 pub mod runner;
+mod screenshot;
+mod task;
 mod util;
 mod webdriver;
 
@@ -31,15 +43,53 @@ async fn async_main() -> Result<(), Report> {
 
     debug!("Starting runner");
 
-    let mut runner = Runner::new(opts.grpc_url, opts.grpc_token)?;
+    let config = runner::Config {
+        heartbeat_interval: Duration::from_secs(opts.heartbeat_interval_secs),
+        max_reconnect_delay: Duration::from_secs(opts.max_reconnect_delay_secs),
+        shutdown_grace_period: Duration::from_secs(opts.shutdown_grace_period_secs),
+        max_tasks: opts.max_tasks,
+        fetch: fetch::Config {
+            timeout: Duration::from_secs(opts.http_timeout_secs),
+            max_redirects: opts.http_max_redirects,
+            max_body_size: opts.http_max_body_size,
+        },
+    };
+    let mut runner = Runner::new(opts.grpc_url, opts.grpc_token, config)?;
+
+    // Request a graceful shutdown of the runner on SIGTERM or SIGINT
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::spawn(async move {
+        if let Err(error) = wait_for_shutdown_signal(&mut sigterm).await {
+            error!(%error, "Could not listen for shutdown signals");
+
+            return;
+        }
+
+        let _ = shutdown_tx.send(true);
+    });
 
     // Spawn the chromedriver process
-    runner
-        .announce()
-        .await
-        .map_err(|_| Error::from(Kind::RpcAnnounceFailed))?;
     runner.spawn_chromedriver()?;
-    runner.poll().await?;
+
+    let result = runner.run(shutdown_rx).await;
+
+    runner.stop().await?;
+    result?;
+
+    Ok(())
+}
+
+/// Waits for the process to receive either SIGTERM or SIGINT.
+async fn wait_for_shutdown_signal(sigterm: &mut tokio::signal::unix::Signal) -> io::Result<()> {
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            info!("Received SIGINT");
+        }
+    }
 
     Ok(())
 }
@@ -1,52 +1,142 @@
 //! Asynchronous runner that talks to a server
 
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use futures::FutureExt;
 use http::HeaderValue;
-use tonic::{transport::Channel, Status};
-use tracing::{debug, error, instrument};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Channel, Code, Status, Streaming};
+use tracing::{debug, error, info, instrument, trace, warn};
 
-use crate::grpc::{runner::AnnounceRequest, AuthService, RunnerClient};
+use crate::backoff::Backoff;
+use crate::fetch::{self, Fetcher};
+use crate::grpc::{
+    runner::{
+        session_request, session_response::Event, task_progress::Stage, AnnounceRequest,
+        CancelTask, DeregisterRequest, HeartbeatRequest, SessionCredit, SessionHello,
+        SessionRequest, SessionResponse, Task, TaskProgress,
+    },
+    AuthService, RunnerClient,
+};
+use crate::task;
 use crate::util::system;
 use crate::webdriver::ChromeDriver;
 use crate::{Error, Kind};
 
+/// The session the server started when the runner announced itself.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// The id of the runner.
+    pub runner_id: i64,
+    /// The id of the session.
+    pub session_id: String,
+}
+
+/// The delay before the first attempt at reconnecting to the server.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// The maximum time to wait for the server to acknowledge that the runner is leaving.
+const DEREGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximum time to wait for chromedriver to exit before killing it.
+const CHROMEDRIVER_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The interval at which the runner checks whether tasks or processes have finished while
+/// shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of messages that can be waiting to be sent to the server on a session stream.
+const SESSION_BUFFER: usize = 16;
+
+/// Settings that control the behaviour of a [`Runner`].
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The interval at which heartbeats are sent to the server.
+    pub heartbeat_interval: Duration,
+    /// The maximum delay between attempts at reconnecting to the server.
+    pub max_reconnect_delay: Duration,
+    /// The time tasks are given to finish once the runner is shutting down.
+    pub shutdown_grace_period: Duration,
+    /// The maximum number of tasks to run at a time.
+    pub max_tasks: u32,
+    /// The settings of the HTTP client that fetches pages for tasks that don't need a browser.
+    pub fetch: fetch::Config,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            heartbeat_interval: Duration::from_secs(15),
+            max_reconnect_delay: Duration::from_secs(60),
+            shutdown_grace_period: Duration::from_secs(30),
+            max_tasks: 1,
+            fetch: fetch::Config::default(),
+        }
+    }
+}
+
 /// Asynchronous client that communicates with a gRPC server and receives tasks to run in a
 /// webdriver.
 pub struct Runner {
     /// The host and port of the webalert gRPC server
     grpc_url: String,
-    /// The authorization token to access the gRPC server
-    grpc_token: String,
     /// A handle to the webdriver child process once launched
-    chromedriver: Option<ChromeDriver>,
+    chromedriver: Option<Arc<ChromeDriver>>,
+    /// Fetches pages for tasks that don't need a browser
+    fetcher: Fetcher,
     // The inner gRPC client
     client: RunnerClient<AuthService<Channel>>,
+    /// The current session, once the runner has announced itself
+    session: Option<Session>,
+    /// The runner settings
+    config: Config,
+    /// The time the runner was created
+    started_at: Instant,
+    /// The number of tasks that are currently running
+    active_tasks: Arc<AtomicU32>,
+    /// The senders that cancel each running task, by task id
+    cancellations: Arc<Mutex<HashMap<i64, oneshot::Sender<()>>>>,
+    /// Sends the progress of running tasks to the current session
+    progress_tx: mpsc::UnboundedSender<TaskProgress>,
+    /// Receives the progress of running tasks
+    progress_rx: mpsc::UnboundedReceiver<TaskProgress>,
 }
 
 impl Runner {
-    /// Creates a new runner with the given `grpc_url`, `grpc_token` and `webdriver_url`.
+    /// Creates a new runner with the given `grpc_url`, `grpc_token` and `config`.
     #[instrument(skip(grpc_token))]
-    pub fn new(grpc_url: String, grpc_token: String) -> Result<Runner, Error> {
+    pub fn new(grpc_url: String, grpc_token: String, config: Config) -> Result<Runner, Error> {
         let channel = Channel::from_shared(grpc_url.clone())
             .map_err(|_| Error::from(Kind::InvalidRpcUrl))?
             .connect_timeout(Duration::from_secs(30))
             .connect_lazy()?;
         let token = HeaderValue::from_str(&format!("Bearer {}", grpc_token)).unwrap();
         let client = RunnerClient::new(AuthService::new(channel, Arc::new(token)));
+        let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+        let fetcher = Fetcher::new(&config.fetch)?;
 
         Ok(Runner {
             grpc_url,
-            grpc_token,
             chromedriver: None,
+            fetcher,
             client,
+            session: None,
+            config,
+            started_at: Instant::now(),
+            active_tasks: Arc::new(AtomicU32::new(0)),
+            cancellations: Arc::new(Mutex::new(HashMap::new())),
+            progress_tx,
+            progress_rx,
         })
     }
 
     /// Spawns a new `chromedriver` process in the background.
     pub fn spawn_chromedriver(&mut self) -> Result<(), Error> {
-        if let Some(ref mut chromedriver) = self.chromedriver {
+        if let Some(ref chromedriver) = self.chromedriver {
             // If we can't get an exit status from `try_wait()` it means the process hasn't exited
             if !chromedriver.has_exited() {
                 return Err(Error::from(Kind::ChromeDriverAlreadyRunning));
@@ -54,71 +144,420 @@ impl Runner {
         }
 
         let chromedriver = ChromeDriver::new("chromedriver", 4444)?;
-        self.chromedriver = Some(chromedriver);
+        self.chromedriver = Some(Arc::new(chromedriver));
 
         Ok(())
     }
 
-    /// Continually polls the server for new tasks.
-    #[instrument(skip(self))]
-    pub async fn poll(&mut self) -> Result<(), Error> {
-        let mut stream = self.client.poll(()).await.unwrap().into_inner();
+    /// Returns the current session, if the runner has announced itself.
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    /// Runs the runner until the server refuses it or `shutdown` is set to `true`.
+    ///
+    /// The runner announces itself and opens a session with the server, over which it receives
+    /// tasks whenever it has room for more. Whenever the connection to the server is lost, the
+    /// runner announces itself again and reopens the session, waiting longer between each failed
+    /// attempt, up to
+    /// [`Config::max_reconnect_delay`]. The chromedriver process is kept alive across reconnects.
+    ///
+    /// Once shutdown is requested the runner stops accepting new tasks, gives the tasks that are
+    /// running [`Config::shutdown_grace_period`] to finish, and deregisters itself from the server.
+    /// Call [`Runner::stop`] afterwards to stop chromedriver.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with kind [`Kind::RpcRefused`] if the server refuses the runner's token.
+    #[instrument(skip(self, shutdown), fields(grpc_url = %self.grpc_url))]
+    pub async fn run(&mut self, mut shutdown: watch::Receiver<bool>) -> Result<(), Error> {
+        let mut backoff = Backoff::new(INITIAL_RECONNECT_DELAY, self.config.max_reconnect_delay);
 
         loop {
-            let msg = stream.message().await;
+            let connection = async {
+                self.announce().await?;
 
-            match msg {
-                Ok(Some(msg)) => {
-                    println!("{:?}", msg);
-                }
-                Ok(None) => unreachable!(),
-                Err(err) => {
-                    error!(?err);
+                let (outbound, stream) = self.open_session().await?;
 
-                    break;
+                info!("Connected to server");
+                backoff.reset();
+
+                self.process_tasks(outbound, stream).await
+            };
+
+            // Dropping the connection closes the session, so no new tasks are accepted
+            let result = tokio::select! {
+                result = connection => result,
+                _ = shutdown_requested(&mut shutdown) => break,
+            };
+
+            match result {
+                Ok(()) => warn!("Server closed the session"),
+                Err(status) if is_fatal(&status) => {
+                    error!(?status, "Server refused the runner");
+
+                    return Err(Error::from(Kind::RpcRefused(status)));
                 }
+                Err(status) => warn!(?status, "Lost connection to server"),
+            }
+
+            let delay = backoff.next_delay();
+
+            info!(
+                attempt = backoff.attempt(),
+                ?delay,
+                "Reconnecting to server"
+            );
+
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown_requested(&mut shutdown) => break,
             }
         }
 
-        debug!("Polling stream ended");
+        info!("Shutting down runner");
 
-        if let Some(ref mut chromedriver) = self.chromedriver {
-            if !chromedriver.has_exited() {
-                debug!(?chromedriver, "Killing webdriver process");
+        self.finish_tasks().await;
+
+        if let Err(status) = self.deregister().await {
+            warn!(?status, "Could not deregister runner");
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the running tasks to finish, for at most [`Config::shutdown_grace_period`].
+    async fn finish_tasks(&self) {
+        let deadline = Instant::now() + self.config.shutdown_grace_period;
+
+        loop {
+            let active_tasks = self.active_tasks.load(Ordering::Relaxed);
 
-                chromedriver.kill();
+            if active_tasks == 0 {
+                break;
             }
+
+            if Instant::now() >= deadline {
+                warn!(
+                    active_tasks,
+                    "Abandoning tasks that didn't finish within the grace period"
+                );
+                break;
+            }
+
+            debug!(active_tasks, "Waiting for tasks to finish");
+            tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
         }
+    }
+
+    /// Tells the server that the runner is leaving and ends the current session, if any.
+    #[instrument(skip(self))]
+    async fn deregister(&mut self) -> Result<(), Status> {
+        let session = match self.session.take() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let request = DeregisterRequest {
+            runner_id: session.runner_id,
+            session_id: session.session_id,
+        };
+
+        tokio::time::timeout(DEREGISTER_TIMEOUT, self.client.deregister(request))
+            .await
+            .map_err(|_| Status::deadline_exceeded("timed out deregistering the runner"))??;
+
+        info!(runner_id = session.runner_id, "Deregistered runner");
 
         Ok(())
     }
 
+    /// Opens a session stream with the server for the current session, granting the server credit
+    /// for as many tasks as the runner has room for.
+    ///
+    /// Returns the sender for messages to the server along with the stream of messages from it.
+    async fn open_session(
+        &mut self,
+    ) -> Result<(mpsc::Sender<SessionRequest>, Streaming<SessionResponse>), Status> {
+        let session = self
+            .session
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("the runner has not announced itself"))?;
+
+        // Progress from before the connection was lost is of no use to the new session, and the
+        // tasks that are still running are accounted for in the initial credit
+        while let Some(Some(_)) = self.progress_rx.recv().now_or_never() {}
+
+        let active_tasks = self.active_tasks.load(Ordering::Relaxed);
+        let credit = self.config.max_tasks.saturating_sub(active_tasks);
+        let (outbound, rx) = mpsc::channel(SESSION_BUFFER);
+
+        let hello = session_request::Event::Hello(SessionHello {
+            runner_id: session.runner_id,
+            session_id: session.session_id.clone(),
+            max_tasks: self.config.max_tasks,
+        });
+        let _ = outbound.send(SessionRequest { event: Some(hello) }).await;
+
+        if credit > 0 {
+            let _ = outbound.send(credit_request(credit)).await;
+        }
+
+        let stream = self
+            .client
+            .session(ReceiverStream::new(rx))
+            .await?
+            .into_inner();
+
+        Ok((outbound, stream))
+    }
+
+    /// Receives tasks from `stream` until it ends, while sending heartbeats to the server and the
+    /// progress of tasks to `outbound`.
+    ///
+    /// A unit of credit is granted to the server whenever a task finishes.
+    ///
+    /// Returns `Ok(())` if the server closed or drained the stream, or an error if the connection
+    /// was lost or the session has ended.
+    async fn process_tasks(
+        &mut self,
+        outbound: mpsc::Sender<SessionRequest>,
+        mut stream: Streaming<SessionResponse>,
+    ) -> Result<(), Status> {
+        let session = self
+            .session
+            .clone()
+            .ok_or_else(|| Status::failed_precondition("the runner has not announced itself"))?;
+
+        // Keep telling the server that we're alive for as long as we're polling
+        let heartbeat = send_heartbeats(
+            self.client.clone(),
+            session.clone(),
+            self.config.heartbeat_interval,
+            self.started_at,
+            self.active_tasks.clone(),
+        );
+        tokio::pin!(heartbeat);
+
+        let result = loop {
+            tokio::select! {
+                msg = stream.message() => match msg {
+                    Ok(Some(SessionResponse { event: Some(Event::Drain(_)) })) => {
+                        info!("Server is shutting down");
+                        break Ok(());
+                    }
+                    Ok(Some(SessionResponse { event: Some(Event::Task(task)) })) => {
+                        debug!(?task, "Received task");
+
+                        self.spawn_task(task, &session);
+                    }
+                    Ok(Some(SessionResponse { event: Some(Event::Cancel(cancel)) })) => {
+                        self.cancel_task(cancel);
+                    }
+                    Ok(Some(msg)) => debug!(?msg, "Ignoring unknown message"),
+                    Ok(None) => break Ok(()),
+                    Err(status) => break Err(status),
+                },
+                Some(progress) = self.progress_rx.recv() => {
+                    let finished = progress.stage() == Stage::Finished;
+                    let progress = session_request::Event::Progress(progress);
+
+                    if outbound.send(SessionRequest { event: Some(progress) }).await.is_err()
+                        || (finished && outbound.send(credit_request(1)).await.is_err())
+                    {
+                        break Err(Status::unavailable("session stream was closed"));
+                    }
+                }
+                status = &mut heartbeat => break Err(status),
+            }
+        };
+
+        debug!("Session ended");
+
+        result
+    }
+
+    /// Runs `task` in the background, keeping track of the number of active tasks.
+    ///
+    /// The task reports that it has finished once it's done, which grants the server credit for
+    /// another task.
+    fn spawn_task(&self, task: Task, session: &Session) {
+        let task_id = task.id;
+        let active_tasks = self.active_tasks.clone();
+        let cancellations = self.cancellations.clone();
+        let progress = self.progress_tx.clone();
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let run = task::run(
+            self.client.clone(),
+            session.clone(),
+            task,
+            self.chromedriver.clone(),
+            self.fetcher.clone(),
+            progress.clone(),
+            cancel_rx,
+        );
+
+        active_tasks.fetch_add(1, Ordering::Relaxed);
+        cancellations.lock().unwrap().insert(task_id, cancel_tx);
+
+        tokio::spawn(async move {
+            run.await;
+
+            cancellations.lock().unwrap().remove(&task_id);
+            active_tasks.fetch_sub(1, Ordering::Relaxed);
+            task::report_progress(&progress, task_id, Stage::Finished);
+        });
+    }
+
+    /// Stops working on the task in `cancel`, if it's still running.
+    fn cancel_task(&self, cancel: CancelTask) {
+        let sender = self.cancellations.lock().unwrap().remove(&cancel.task_id);
+
+        match sender {
+            Some(sender) => {
+                info!(task.id = cancel.task_id, reason = %cancel.reason, "Server cancelled task");
+
+                let _ = sender.send(());
+            }
+            None => debug!(
+                task.id = cancel.task_id,
+                "Ignoring cancellation of finished task"
+            ),
+        }
+    }
+
     /// Stops the chromedriver process.
+    ///
+    /// Chromedriver is asked to exit, and killed if it hasn't done so within a few seconds.
     pub async fn stop(&mut self) -> Result<(), Error> {
-        if let Some(ref mut chromedriver) = self.chromedriver {
-            chromedriver.kill();
+        let chromedriver = match self.chromedriver.take() {
+            Some(chromedriver) => chromedriver,
+            None => return Ok(()),
+        };
+
+        debug!("Stopping chromedriver");
+        chromedriver.terminate()?;
+
+        let deadline = Instant::now() + CHROMEDRIVER_STOP_TIMEOUT;
+
+        while !chromedriver.has_exited() {
+            if Instant::now() >= deadline {
+                warn!("Killing chromedriver because it didn't exit in time");
+                chromedriver.kill()?;
+                break;
+            }
+
+            tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
         }
 
         Ok(())
     }
 
-    /// Announces to the gRPC server that this runner is alive and running.
+    /// Announces to the gRPC server that this runner is alive and running, and starts a new
+    /// session or resumes the current one.
+    ///
+    /// Resuming the session keeps the leases on the tasks that are still running, so they can be
+    /// reported once the runner has reconnected.
     ///
     /// See also [`RunnerClient::announce`]
     #[instrument(skip(self))]
-    pub async fn announce(&mut self) -> Result<(), Status> {
+    pub async fn announce(&mut self) -> Result<&Session, Status> {
         let hostname =
             system::get_hostname().map_err(|error| Status::internal(error.to_string()))?;
 
-        self.client
+        let response = self
+            .client
             .announce(AnnounceRequest {
                 os: system::get_os(),
                 arch: system::get_arch(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
                 hostname,
+                session_id: self
+                    .session
+                    .as_ref()
+                    .map(|session| session.session_id.clone())
+                    .unwrap_or_default(),
             })
-            .await?;
+            .await?
+            .into_inner();
 
-        Ok(())
+        debug!(
+            runner_id = response.runner_id,
+            session_id = %response.session_id,
+            "Announced runner"
+        );
+
+        let session = self.session.insert(Session {
+            runner_id: response.runner_id,
+            session_id: response.session_id,
+        });
+
+        Ok(&*session)
+    }
+}
+
+/// Returns a message that grants the server credit for `tasks` more tasks.
+fn credit_request(tasks: u32) -> SessionRequest {
+    SessionRequest {
+        event: Some(session_request::Event::Credit(SessionCredit { tasks })),
+    }
+}
+
+/// Returns true if `status` means that retrying won't help.
+fn is_fatal(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unauthenticated | Code::PermissionDenied
+    )
+}
+
+/// Resolves once `shutdown` has been set to `true`.
+///
+/// Never resolves if the sender has been dropped without requesting shutdown.
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Sends a heartbeat for `session` to the server every `interval`.
+///
+/// Returns the error from the server once the session is no longer valid.
+#[instrument(skip(client, started_at, active_tasks))]
+async fn send_heartbeats(
+    mut client: RunnerClient<AuthService<Channel>>,
+    session: Session,
+    interval: Duration,
+    started_at: Instant,
+    active_tasks: Arc<AtomicU32>,
+) -> Status {
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        let uptime = started_at.elapsed();
+        let request = HeartbeatRequest {
+            runner_id: session.runner_id,
+            session_id: session.session_id.clone(),
+            load: system::get_load().unwrap_or_default(),
+            active_tasks: active_tasks.load(Ordering::Relaxed),
+            uptime: Some(prost_types::Duration {
+                seconds: uptime.as_secs() as i64,
+                nanos: uptime.subsec_nanos() as i32,
+            }),
+        };
+
+        match client.heartbeat(request).await {
+            Ok(_) => trace!("Sent heartbeat"),
+            Err(status) if matches!(status.code(), Code::NotFound | Code::FailedPrecondition) => {
+                warn!(?status, "Session has ended");
+
+                return status;
+            }
+            Err(status) => warn!(?status, "Could not send heartbeat"),
+        }
     }
 }
 
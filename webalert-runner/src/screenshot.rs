@@ -0,0 +1,76 @@
+//! Taking screenshots of pages, for tasks of screenshot alerts
+
+use serde_json::{json, Value};
+use thirtyfour::extensions::chrome::ChromeDevTools;
+use thirtyfour::{By, WebDriver, WebDriverCommands};
+use tracing::debug;
+
+use crate::grpc::runner::{selector::Kind as SelectorKind, Selector};
+use crate::Kind;
+
+/// Takes a PNG screenshot of the page loaded in `driver`, or of the first element matched by
+/// `selector` if there is one.
+///
+/// Screenshots of pages cover the whole page when Chrome can capture beyond the viewport, and
+/// only the viewport when it can't.
+pub async fn capture(driver: &WebDriver, selector: Option<&Selector>) -> Result<Vec<u8>, Kind> {
+    let selector = match selector {
+        Some(selector) => selector,
+        None => return capture_page(driver).await,
+    };
+
+    let by = match SelectorKind::from_i32(selector.kind) {
+        Some(SelectorKind::Css) => By::Css(&selector.expression),
+        Some(SelectorKind::Xpath) => By::XPath(&selector.expression),
+        Some(SelectorKind::Javascript) | Some(SelectorKind::Jsonpath) => {
+            return Err(Kind::UnsupportedSelector(selector.name.clone()))
+        }
+        Some(SelectorKind::Unspecified) | None => {
+            return Err(Kind::InvalidSelector(selector.name.clone()))
+        }
+    };
+
+    let element = driver
+        .find_elements(by)
+        .await
+        .map_err(Kind::ScreenshotFailed)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Kind::ElementNotFound(selector.expression.clone()))?;
+
+    element
+        .screenshot_as_png()
+        .await
+        .map_err(Kind::ScreenshotFailed)
+}
+
+/// Takes a PNG screenshot of the whole page loaded in `driver`.
+async fn capture_page(driver: &WebDriver) -> Result<Vec<u8>, Kind> {
+    let dev_tools = ChromeDevTools::new(driver.session());
+    let captured = dev_tools
+        .execute_cdp_with_params(
+            "Page.captureScreenshot",
+            json!({"format": "png", "captureBeyondViewport": true}),
+        )
+        .await;
+
+    let full_page = match &captured {
+        Ok(captured) => match captured.get("data").and_then(Value::as_str) {
+            Some(data) => base64::decode(data).ok(),
+            None => None,
+        },
+        Err(error) => {
+            debug!(%error, "Could not capture the whole page");
+
+            None
+        }
+    };
+
+    match full_page {
+        Some(png) => Ok(png),
+        None => driver
+            .screenshot_as_png()
+            .await
+            .map_err(Kind::ScreenshotFailed),
+    }
+}
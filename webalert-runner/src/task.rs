@@ -0,0 +1,454 @@
+//! Running the tasks that the server hands out
+
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use sha2::{Digest, Sha256};
+use thirtyfour::{WebDriver, WebDriverCommands};
+use tokio::sync::{mpsc, oneshot};
+use tonic::{transport::Channel, Code, Status};
+use tracing::{debug, instrument, warn};
+
+use crate::extract::{self, Extracted};
+use crate::feed;
+use crate::fetch::Fetcher;
+use crate::grpc::{
+    runner::{
+        selector::Kind as SelectorKind,
+        task::{FetchMode, Format},
+        task_error,
+        task_progress::Stage,
+        ExtendLeaseRequest, NackRequest, ReportResultRequest, Task, TaskError, TaskProgress,
+        TaskResult, Timings,
+    },
+    AuthService, RunnerClient,
+};
+use crate::runner::Session;
+use crate::screenshot;
+use crate::webdriver::ChromeDriver;
+use crate::{Error, Kind};
+
+/// The minimum time between attempts at extending the lease on a task.
+const MIN_LEASE_EXTENSION_DELAY: Duration = Duration::from_secs(1);
+
+/// Script that returns the HTTP status of the current page, or 0 if the browser doesn't know it.
+const HTTP_STATUS_SCRIPT: &str = "const entry = performance.getEntriesByType('navigation')[0]; \
+                                  return (entry && entry.responseStatus) || 0;";
+
+/// Runs `task` and reports the result to the server.
+///
+/// The page of the task is either loaded in a new webdriver session of `chromedriver`, or fetched
+/// with `fetcher` if the task asks for a plain HTTP request.
+///
+/// Problems with the page itself, e.g. a missing element, are part of the result. If the task
+/// couldn't be run at all, e.g. because chromedriver isn't running, it's rejected instead so the
+/// server can hand it out again.
+///
+/// The lease on the task is extended whenever half of it has passed, for as long as the task is
+/// running. If the lease is lost, e.g. because the session has ended, or the server cancels the
+/// task through `cancelled`, the task is abandoned since the server has handed it out again.
+///
+/// The progress of the task is sent to `progress`.
+#[instrument(
+    skip(client, session, task, chromedriver, fetcher, progress, cancelled),
+    fields(task.id = task.id, task.attempt = task.attempt)
+)]
+pub async fn run(
+    mut client: RunnerClient<AuthService<Channel>>,
+    session: Session,
+    task: Task,
+    chromedriver: Option<Arc<ChromeDriver>>,
+    fetcher: Fetcher,
+    progress: mpsc::UnboundedSender<TaskProgress>,
+    mut cancelled: oneshot::Receiver<()>,
+) {
+    report_progress(&progress, task.id, Stage::Started);
+
+    let check = check(chromedriver.as_deref(), &fetcher, &task, &progress);
+    tokio::pin!(check);
+
+    let mut lease_remaining = remaining(task.lease_expire_time.as_ref());
+
+    let result = loop {
+        let extension_delay = (lease_remaining / 2).max(MIN_LEASE_EXTENSION_DELAY);
+
+        tokio::select! {
+            result = &mut check => break result,
+            _ = &mut cancelled => {
+                warn!("Abandoning task because the server cancelled it");
+
+                return;
+            }
+            _ = tokio::time::sleep(extension_delay) => {
+                let request = ExtendLeaseRequest {
+                    runner_id: session.runner_id,
+                    session_id: session.session_id.clone(),
+                    task_id: task.id,
+                };
+
+                match client.extend_lease(request).await {
+                    Ok(response) => {
+                        lease_remaining =
+                            remaining(response.into_inner().lease_expire_time.as_ref());
+
+                        debug!(?lease_remaining, "Extended lease on task");
+                    }
+                    Err(status) if is_lease_lost(&status) => {
+                        warn!(?status, "Abandoning task because its lease was lost");
+
+                        return;
+                    }
+                    Err(status) => {
+                        warn!(?status, "Could not extend lease on task");
+
+                        lease_remaining = lease_remaining.saturating_sub(extension_delay);
+                    }
+                }
+            }
+        }
+    };
+
+    let response = match result {
+        Ok(result) => {
+            let failed = result.error.is_some();
+
+            match &result.error {
+                Some(error) => warn!(reason = %error.message, "Task failed"),
+                None => debug!(content_hash = %result.content_hash, "Task succeeded"),
+            }
+
+            client
+                .report_result(ReportResultRequest {
+                    runner_id: session.runner_id,
+                    session_id: session.session_id,
+                    task_id: task.id,
+                    result: Some(result),
+                })
+                .await
+                .map(|response| {
+                    if failed && !response.into_inner().will_retry {
+                        warn!("Server gave up on task");
+                    }
+                })
+        }
+        Err(error) => {
+            let reason = error.describe();
+
+            warn!(%reason, "Task failed");
+
+            client
+                .nack(NackRequest {
+                    runner_id: session.runner_id,
+                    session_id: session.session_id,
+                    task_id: task.id,
+                    reason,
+                })
+                .await
+                .map(|response| {
+                    if !response.into_inner().will_retry {
+                        warn!("Server gave up on task");
+                    }
+                })
+        }
+    };
+
+    if let Err(status) = response {
+        warn!(?status, "Could not report the outcome of task");
+    }
+}
+
+/// Checks the page of `task` the way the task asks for and extracts the values of its selectors.
+///
+/// Tasks that don't say how to fetch their page are checked in a browser. Feeds and JSON documents
+/// are always fetched over HTTP, since browsers don't hand them over as they were served, and
+/// screenshots are always taken in a browser.
+async fn check(
+    chromedriver: Option<&ChromeDriver>,
+    fetcher: &Fetcher,
+    task: &Task,
+    progress: &mpsc::UnboundedSender<TaskProgress>,
+) -> Result<TaskResult, Error> {
+    if task.format == Format::Feed as i32 || task.format == Format::Json as i32 {
+        return Ok(check_over_http(fetcher, task, progress).await);
+    }
+
+    if task.format == Format::Screenshot as i32 {
+        return check_in_browser(chromedriver, task, progress).await;
+    }
+
+    match FetchMode::from_i32(task.fetch_mode) {
+        Some(FetchMode::Http) => Ok(check_over_http(fetcher, task, progress).await),
+        Some(FetchMode::Browser) | Some(FetchMode::Unspecified) | None => {
+            check_in_browser(chromedriver, task, progress).await
+        }
+    }
+}
+
+/// Opens the page of `task` in a new webdriver session and extracts the values of its
+/// selectors.
+async fn check_in_browser(
+    chromedriver: Option<&ChromeDriver>,
+    task: &Task,
+    progress: &mpsc::UnboundedSender<TaskProgress>,
+) -> Result<TaskResult, Error> {
+    let chromedriver = chromedriver.ok_or(Kind::ChromeDriverNotRunning)?;
+    let driver = chromedriver.webdriver().await?;
+
+    let result = inspect(&driver, task, progress).await;
+
+    // End the browser session even if the page couldn't be loaded
+    if let Err(error) = driver.quit().await {
+        warn!(%error, "Could not end webdriver session");
+    }
+
+    Ok(result)
+}
+
+/// Loads the page of `task` in `driver` and extracts the values of its selectors, or takes a
+/// screenshot of it if the task is for a screenshot.
+async fn inspect(
+    driver: &WebDriver,
+    task: &Task,
+    progress: &mpsc::UnboundedSender<TaskProgress>,
+) -> TaskResult {
+    let started_at = Instant::now();
+    let mut result = TaskResult::default();
+
+    debug!(url = %task.url, "Opening page");
+
+    if let Err(error) = driver.get(task.url.as_str()).await {
+        return navigation_failed(Kind::PageLoadFailed(error), true, started_at);
+    }
+
+    let navigation = started_at.elapsed();
+
+    report_progress(progress, task.id, Stage::PageLoaded);
+
+    result.final_url = driver.current_url().await.unwrap_or_else(|error| {
+        warn!(%error, "Could not get the URL of the page");
+
+        String::new()
+    });
+    result.http_status = match driver.execute_script(HTTP_STATUS_SCRIPT).await {
+        Ok(status) => status.convert().unwrap_or_default(),
+        Err(error) => {
+            warn!(%error, "Could not get the HTTP status of the page");
+
+            0
+        }
+    };
+
+    let extraction_started_at = Instant::now();
+
+    if task.format == Format::Screenshot as i32 {
+        let captured = screenshot::capture(driver, task.selectors.first()).await;
+
+        record_screenshot(&mut result, captured);
+    } else {
+        let extracted = extract::extract(driver, &task.selectors).await;
+
+        record_extraction(&mut result, extracted);
+    }
+
+    result.timings = Some(Timings {
+        total: Some(started_at.elapsed().into()),
+        navigation: Some(navigation.into()),
+        extraction: Some(extraction_started_at.elapsed().into()),
+    });
+
+    result
+}
+
+/// Fetches the page of `task` with a plain HTTP request and extracts the values of its
+/// selectors, parsing the page as JSON if the task is for a JSON document or has JSONPath
+/// selectors, or the items of the page if it's a feed.
+async fn check_over_http(
+    fetcher: &Fetcher,
+    task: &Task,
+    progress: &mpsc::UnboundedSender<TaskProgress>,
+) -> TaskResult {
+    let started_at = Instant::now();
+    let mut result = TaskResult::default();
+
+    debug!(url = %task.url, "Fetching page");
+
+    let page = match fetcher.fetch(&task.url).await {
+        Ok(page) => page,
+        Err(kind) => {
+            // The page won't get any smaller by fetching it again
+            let retryable = !matches!(kind, Kind::BodyTooLarge(_));
+
+            return navigation_failed(kind, retryable, started_at);
+        }
+    };
+
+    let navigation = started_at.elapsed();
+
+    report_progress(progress, task.id, Stage::PageLoaded);
+
+    result.final_url = page.final_url;
+    result.http_status = page.http_status.into();
+
+    let extraction_started_at = Instant::now();
+    let is_json = task.format == Format::Json as i32
+        || task
+            .selectors
+            .iter()
+            .any(|selector| selector.kind == SelectorKind::Jsonpath as i32);
+    let extracted = if task.format == Format::Feed as i32 {
+        feed::extract(&page.body)
+    } else if is_json {
+        extract::extract_json(&page.body, &task.selectors)
+    } else {
+        extract::extract_document(&page.body, &task.selectors)
+    };
+
+    record_extraction(&mut result, extracted);
+
+    result.timings = Some(Timings {
+        total: Some(started_at.elapsed().into()),
+        navigation: Some(navigation.into()),
+        extraction: Some(extraction_started_at.elapsed().into()),
+    });
+
+    result
+}
+
+/// Returns the result of a check whose page couldn't be loaded because of `kind`.
+fn navigation_failed(kind: Kind, retryable: bool, started_at: Instant) -> TaskResult {
+    let error = Error::from(kind);
+
+    TaskResult {
+        error: Some(to_task_error(
+            task_error::Code::NavigationFailed,
+            &error,
+            retryable,
+        )),
+        timings: Some(Timings {
+            total: Some(started_at.elapsed().into()),
+            navigation: Some(started_at.elapsed().into()),
+            extraction: None,
+        }),
+        ..Default::default()
+    }
+}
+
+/// Records what was `extracted` from the page of a check in its `result`.
+fn record_extraction(result: &mut TaskResult, extracted: Result<Extracted, Kind>) {
+    match extracted {
+        Ok(extracted) => {
+            result.content_hash = content_hash(&extracted.html);
+            result.text = extracted.text;
+            result.html = extracted.html;
+            result.values = extracted.values;
+        }
+        Err(kind) => result.error = Some(extraction_error(kind)),
+    }
+}
+
+/// Records the `captured` screenshot of the page of a check in its `result`.
+fn record_screenshot(result: &mut TaskResult, captured: Result<Vec<u8>, Kind>) {
+    match captured {
+        Ok(screenshot) => {
+            result.content_hash = content_hash(&screenshot);
+            result.screenshot = screenshot;
+        }
+        Err(kind) => result.error = Some(extraction_error(kind)),
+    }
+}
+
+/// Returns the error of a check that failed to extract anything from its page because of `kind`.
+fn extraction_error(kind: Kind) -> TaskError {
+    let (code, retryable) = match &kind {
+        Kind::ElementNotFound(_) => (task_error::Code::ElementNotFound, false),
+        Kind::InvalidSelector(_)
+        | Kind::UnsupportedSelector(_)
+        | Kind::InvalidJson(_)
+        | Kind::InvalidFeed(_)
+        | Kind::UnknownFeedFormat(_) => (task_error::Code::ExtractionFailed, false),
+        _ => (task_error::Code::ExtractionFailed, true),
+    };
+
+    to_task_error(code, &Error::from(kind), retryable)
+}
+
+/// Sends the `stage` the task with the given `task_id` has reached to `progress`.
+pub fn report_progress(progress: &mpsc::UnboundedSender<TaskProgress>, task_id: i64, stage: Stage) {
+    let _ = progress.send(TaskProgress {
+        task_id,
+        stage: stage as i32,
+    });
+}
+
+/// Returns a [`TaskError`] with the given `code` that describes `error`.
+fn to_task_error(code: task_error::Code, error: &Error, retryable: bool) -> TaskError {
+    TaskError {
+        code: code as i32,
+        message: error.describe(),
+        retryable,
+    }
+}
+
+/// Returns the hex-encoded SHA-256 hash of `content`.
+fn content_hash(content: impl AsRef<[u8]>) -> String {
+    Sha256::digest(content.as_ref())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Returns true if `status` means that the runner no longer holds the lease on a task.
+fn is_lease_lost(status: &Status) -> bool {
+    matches!(status.code(), Code::FailedPrecondition | Code::NotFound)
+}
+
+/// Returns the time left until `time`, or zero if it has passed or is unknown.
+fn remaining(time: Option<&prost_types::Timestamp>) -> Duration {
+    let time = match time {
+        Some(time) => time,
+        None => return Duration::ZERO,
+    };
+    let time = SystemTime::UNIX_EPOCH
+        + Duration::from_secs(time.seconds.max(0) as u64)
+        + Duration::from_nanos(time.nanos.max(0) as u64);
+
+    time.duration_since(SystemTime::now()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_compute_remaining_lease() {
+        let in_a_minute = SystemTime::now() + Duration::from_secs(60);
+        let since_epoch = in_a_minute.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let timestamp = prost_types::Timestamp {
+            seconds: since_epoch.as_secs() as i64,
+            nanos: since_epoch.subsec_nanos() as i32,
+        };
+
+        let remaining = remaining(Some(&timestamp));
+
+        assert!(remaining > Duration::from_secs(59) && remaining <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn it_should_treat_past_leases_as_expired() {
+        let timestamp = prost_types::Timestamp {
+            seconds: 1,
+            nanos: 0,
+        };
+
+        assert_eq!(remaining(Some(&timestamp)), Duration::ZERO);
+        assert_eq!(remaining(None), Duration::ZERO);
+    }
+
+    #[test]
+    fn it_should_hash_content() {
+        assert_eq!(
+            content_hash("<h1>Hello</h1>"),
+            "e2c6c0ea7c7900c31f953e48d30d5e839801ab90630d751e7c8426ed5859da47"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 pub mod system {
     use std::env::consts;
+    use std::fs;
 
     use crate::error::{Error, Kind};
 
@@ -13,6 +14,13 @@ pub mod system {
         consts::ARCH.to_string()
     }
 
+    /// Returns the system load average over the last minute, or `None` if it's unavailable.
+    pub fn get_load() -> Option<f64> {
+        let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+
+        loadavg.split_whitespace().next()?.parse().ok()
+    }
+
     /// Returns the systems current hostname.
     ///
     /// # Errors
@@ -2,18 +2,21 @@ use std::ffi::OsStr;
 use std::io;
 use std::os::unix::process::CommandExt;
 use std::process::{Child, Command};
+use std::sync::Mutex;
 
 use caps::CapSet;
 use thirtyfour::prelude::DesiredCapabilities;
 use thirtyfour::WebDriver;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{Error, Kind};
 
 /// Spawns a ChromeDriver process
+///
+/// The process can be shared between tasks, which each open their own [`WebDriver`] session.
 #[derive(Debug)]
 pub struct ChromeDriver {
-    child: Child,
+    child: Mutex<Child>,
     port: u16,
 }
 
@@ -42,36 +45,68 @@ impl ChromeDriver {
             .spawn()
             .map_err(|error| Error::from(Kind::CouldNotSpawnChromeDriver(error)))?;
 
-        Ok(ChromeDriver { child, port })
+        Ok(ChromeDriver {
+            child: Mutex::new(child),
+            port,
+        })
     }
 
     /// Opens a new WebDriver connection to the ChromeDriver.
     pub async fn webdriver(&self) -> Result<WebDriver, Error> {
-        let caps = DesiredCapabilities::firefox().set_headless().unwrap();
+        let mut caps = DesiredCapabilities::chrome();
+        caps.set_headless()?;
+
         let driver = WebDriver::new(&format!("http://localhost:{}", self.port), &caps).await?;
 
         Ok(driver)
     }
 
     /// Returns true if the chromedriver process has exited, false otherwise.
-    pub fn has_exited(&mut self) -> bool {
-        if let Ok(None) = self.child.try_wait() {
+    pub fn has_exited(&self) -> bool {
+        if let Ok(None) = self.child.lock().unwrap().try_wait() {
             false
         } else {
             true
         }
     }
 
-    /// Kills the chromedriver process if it's running.
-    pub fn kill(&mut self) {
-        self.child.kill().unwrap()
+    /// Asks the chromedriver process to exit by sending it `SIGTERM`.
+    ///
+    /// This doesn't wait for the process to exit, see [`ChromeDriver::has_exited`].
+    pub fn terminate(&self) -> io::Result<()> {
+        if self.has_exited() {
+            return Ok(());
+        }
+
+        // The pid of a child we haven't waited for yet always fits and can't have been reused
+        let pid = self.child.lock().unwrap().id() as libc::pid_t;
+
+        if unsafe { libc::kill(pid, libc::SIGTERM) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Kills the chromedriver process if it's running and waits for it to exit.
+    pub fn kill(&self) -> io::Result<()> {
+        if self.has_exited() {
+            return Ok(());
+        }
+
+        let mut child = self.child.lock().unwrap();
+
+        child.kill()?;
+        child.wait()?;
+
+        Ok(())
     }
 }
 
 impl Drop for ChromeDriver {
     fn drop(&mut self) {
-        if self.child.try_wait().unwrap().is_none() {
-            self.child.kill().unwrap();
+        if let Err(error) = self.kill() {
+            warn!(%error, "Could not kill chromedriver process");
         }
     }
 }
@@ -2,15 +2,21 @@ use std::env;
 use std::path::PathBuf;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let descriptor_path =
-        PathBuf::from(env::var("OUT_DIR").unwrap()).join("runners_descriptor.bin");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
 
     tonic_build::configure()
         .build_server(true)
         .build_client(false)
-        .file_descriptor_set_path(&descriptor_path)
+        .file_descriptor_set_path(out_dir.join("runners_descriptor.bin"))
         .format(true)
         .compile(&["proto/webalert/runner/v1/runner.proto"], &["proto"])?;
 
+    tonic_build::configure()
+        .build_server(true)
+        .build_client(false)
+        .file_descriptor_set_path(out_dir.join("alerts_descriptor.bin"))
+        .format(true)
+        .compile(&["proto/webalert/alerts/v1/alerts.proto"], &["proto"])?;
+
     Ok(())
 }
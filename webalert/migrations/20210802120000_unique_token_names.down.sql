@@ -0,0 +1 @@
+ALTER TABLE tokens DROP CONSTRAINT tokens_name_key;
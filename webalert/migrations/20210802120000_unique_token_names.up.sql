@@ -0,0 +1 @@
+ALTER TABLE tokens ADD CONSTRAINT tokens_name_key UNIQUE (name);
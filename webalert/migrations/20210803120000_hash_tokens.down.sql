@@ -0,0 +1,25 @@
+-- The raw tokens can't be recovered from their hashes, so every token is given a new random value
+-- that nobody knows. Tokens have to be rotated after reverting this migration.
+ALTER TABLE tokens ADD COLUMN token TEXT;
+UPDATE tokens SET token = md5(random()::text || clock_timestamp()::text || id::text);
+
+ALTER TABLE alerts ADD COLUMN creator_token TEXT;
+UPDATE alerts SET creator_token = tokens.token FROM tokens WHERE alerts.creator_token_id = tokens.id;
+ALTER TABLE alerts DROP COLUMN creator_token_id;
+
+DROP INDEX tokens_prefix_idx;
+ALTER TABLE tokens
+  DROP CONSTRAINT tokens_pkey,
+  DROP COLUMN id,
+  DROP COLUMN prefix,
+  DROP COLUMN salt,
+  DROP COLUMN secret_hash,
+  DROP COLUMN expires_at,
+  DROP COLUMN revoked_at,
+  DROP COLUMN last_used_at,
+  ALTER COLUMN token SET NOT NULL,
+  ADD PRIMARY KEY (token);
+
+ALTER TABLE alerts
+  ALTER COLUMN creator_token SET NOT NULL,
+  ADD CONSTRAINT alerts_creator_token_fkey FOREIGN KEY (creator_token) REFERENCES tokens(token);
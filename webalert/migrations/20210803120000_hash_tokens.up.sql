@@ -0,0 +1,34 @@
+-- Tokens are now identified by a numeric id and looked up by a public prefix, and only a salted
+-- SHA-256 hash of the full token is stored.
+ALTER TABLE tokens
+  ADD COLUMN id           SERIAL,
+  ADD COLUMN prefix       TEXT,
+  ADD COLUMN salt         BYTEA,
+  ADD COLUMN secret_hash  BYTEA,
+  ADD COLUMN expires_at   timestamptz,
+  ADD COLUMN revoked_at   timestamptz,
+  ADD COLUMN last_used_at timestamptz;
+
+-- Hash the existing tokens so they keep working
+UPDATE tokens SET
+  prefix = left(token, 8),
+  salt = decode(md5(random()::text || clock_timestamp()::text), 'hex');
+UPDATE tokens SET secret_hash = sha256(salt || convert_to(token, 'UTF8'));
+
+-- Point alerts at the token id instead of the raw token
+ALTER TABLE alerts ADD COLUMN creator_token_id INTEGER;
+UPDATE alerts SET creator_token_id = tokens.id FROM tokens WHERE alerts.creator_token = tokens.token;
+ALTER TABLE alerts DROP COLUMN creator_token;
+
+ALTER TABLE tokens DROP COLUMN token;
+ALTER TABLE tokens
+  ADD PRIMARY KEY (id),
+  ALTER COLUMN prefix SET NOT NULL,
+  ALTER COLUMN salt SET NOT NULL,
+  ALTER COLUMN secret_hash SET NOT NULL;
+
+CREATE INDEX tokens_prefix_idx ON tokens (prefix);
+
+ALTER TABLE alerts
+  ALTER COLUMN creator_token_id SET NOT NULL,
+  ADD CONSTRAINT alerts_creator_token_id_fkey FOREIGN KEY (creator_token_id) REFERENCES tokens(id);
@@ -0,0 +1,3 @@
+-- Existing tokens all belong to runners, so they're given the scopes a runner needs
+ALTER TABLE tokens ADD COLUMN scopes TEXT[] NOT NULL DEFAULT '{}';
+UPDATE tokens SET scopes = '{runner:announce,runner:poll}';
@@ -0,0 +1,7 @@
+DROP INDEX runners_token_id_hostname_idx;
+
+ALTER TABLE runners
+  DROP COLUMN os,
+  DROP COLUMN version,
+  DROP COLUMN token_id,
+  DROP COLUMN session_id;
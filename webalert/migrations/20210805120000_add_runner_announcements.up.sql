@@ -0,0 +1,8 @@
+ALTER TABLE runners
+  ADD COLUMN os         TEXT NOT NULL DEFAULT '',
+  ADD COLUMN version    TEXT NOT NULL DEFAULT '',
+  ADD COLUMN token_id   INTEGER REFERENCES tokens(id),
+  ADD COLUMN session_id TEXT;
+
+-- A runner is identified by the host it runs on and the token it uses
+CREATE UNIQUE INDEX runners_token_id_hostname_idx ON runners (token_id, hostname);
@@ -0,0 +1,6 @@
+ALTER TABLE runners
+  DROP COLUMN last_seen_at,
+  DROP COLUMN dead_at,
+  DROP COLUMN load,
+  DROP COLUMN active_tasks,
+  DROP COLUMN uptime_secs;
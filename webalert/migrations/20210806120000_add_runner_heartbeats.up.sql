@@ -0,0 +1,10 @@
+-- `updated_at` is the last time the row changed, while `last_seen_at` is the last time the runner
+-- itself was heard from.
+ALTER TABLE runners
+  ADD COLUMN last_seen_at timestamptz,
+  ADD COLUMN dead_at      timestamptz,
+  ADD COLUMN load         DOUBLE PRECISION,
+  ADD COLUMN active_tasks INTEGER,
+  ADD COLUMN uptime_secs  BIGINT;
+
+UPDATE runners SET last_seen_at = updated_at;
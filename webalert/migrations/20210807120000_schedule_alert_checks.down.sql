@@ -0,0 +1,5 @@
+DROP TABLE tasks;
+
+ALTER TABLE alerts
+  DROP COLUMN check_interval_secs,
+  DROP COLUMN next_check_at;
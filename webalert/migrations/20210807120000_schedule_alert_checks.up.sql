@@ -0,0 +1,22 @@
+-- Every alert is checked once per `check_interval_secs`, and `next_check_at` is the next time a
+-- check is due.
+ALTER TABLE alerts
+  ADD COLUMN check_interval_secs INTEGER NOT NULL DEFAULT 300 CHECK (check_interval_secs > 0),
+  ADD COLUMN next_check_at       timestamptz NOT NULL DEFAULT NOW();
+
+-- Checks that are due, waiting to be handed out to runners.
+CREATE TABLE tasks (
+  id            SERIAL PRIMARY KEY,
+  alert_id      INTEGER NOT NULL REFERENCES alerts(id) ON DELETE CASCADE,
+  url           TEXT NOT NULL,
+  selector      TEXT NOT NULL,
+  due_at        timestamptz NOT NULL,
+  runner_id     INTEGER REFERENCES runners(id) ON DELETE SET NULL,
+  dispatched_at timestamptz,
+  created_at    timestamptz DEFAULT NOW(),
+  updated_at    timestamptz DEFAULT NOW(),
+  -- A check that is due at a given time is only ever scheduled once
+  UNIQUE (alert_id, due_at)
+);
+
+CREATE INDEX tasks_pending_idx ON tasks (due_at) WHERE dispatched_at IS NULL;
@@ -0,0 +1,15 @@
+DROP INDEX tasks_leased_idx;
+DROP INDEX tasks_queued_idx;
+
+-- Finished tasks count as handed out, and leased tasks are handed out again
+UPDATE tasks SET leased_at = NULL WHERE state IN ('queued', 'leased');
+
+ALTER TABLE tasks
+  DROP COLUMN state,
+  DROP COLUMN attempts,
+  DROP COLUMN lease_expires_at,
+  DROP COLUMN finished_at,
+  DROP COLUMN last_error;
+ALTER TABLE tasks RENAME COLUMN leased_at TO dispatched_at;
+
+CREATE INDEX tasks_pending_idx ON tasks (due_at) WHERE dispatched_at IS NULL;
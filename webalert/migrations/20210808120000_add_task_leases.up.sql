@@ -0,0 +1,18 @@
+-- Tasks are leased to a runner until it acknowledges them or the lease expires, after which they
+-- are handed out again until they run out of attempts.
+ALTER TABLE tasks RENAME COLUMN dispatched_at TO leased_at;
+ALTER TABLE tasks
+  ADD COLUMN state            TEXT NOT NULL DEFAULT 'queued'
+    CHECK (state IN ('queued', 'leased', 'succeeded', 'failed')),
+  ADD COLUMN attempts         INTEGER NOT NULL DEFAULT 0,
+  ADD COLUMN lease_expires_at timestamptz,
+  ADD COLUMN finished_at      timestamptz,
+  ADD COLUMN last_error       TEXT;
+
+-- Tasks that were handed out before leases existed may never have been run, so hand them out again
+UPDATE tasks SET state = 'leased', attempts = 1, lease_expires_at = NOW()
+  WHERE leased_at IS NOT NULL;
+
+DROP INDEX tasks_pending_idx;
+CREATE INDEX tasks_queued_idx ON tasks (due_at) WHERE state = 'queued';
+CREATE INDEX tasks_leased_idx ON tasks (runner_id, lease_expires_at) WHERE state = 'leased';
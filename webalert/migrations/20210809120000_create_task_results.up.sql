@@ -0,0 +1,19 @@
+-- The results runners report for each attempt at a task.
+CREATE TABLE task_results (
+  id            SERIAL PRIMARY KEY,
+  task_id       INTEGER NOT NULL REFERENCES tasks(id) ON DELETE CASCADE,
+  attempt       INTEGER NOT NULL,
+  runner_id     INTEGER REFERENCES runners(id) ON DELETE SET NULL,
+  final_url     TEXT NOT NULL,
+  http_status   INTEGER,
+  text          TEXT NOT NULL,
+  html          TEXT NOT NULL,
+  content_hash  TEXT NOT NULL,
+  total_ms      BIGINT,
+  navigation_ms BIGINT,
+  extraction_ms BIGINT,
+  error_code    TEXT,
+  error_message TEXT,
+  created_at    timestamptz DEFAULT NOW(),
+  UNIQUE (task_id, attempt)
+);
@@ -0,0 +1,3 @@
+DROP TRIGGER tasks_state_notify ON tasks;
+DROP TRIGGER tasks_queued_notify ON tasks;
+DROP FUNCTION notify_task_queue();
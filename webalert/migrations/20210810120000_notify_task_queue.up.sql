@@ -0,0 +1,18 @@
+-- Tell the server whenever a task is queued or a lease ends, so it can hand out tasks right away.
+-- The payload is left empty so that Postgres folds the notifications of a transaction into one.
+CREATE FUNCTION notify_task_queue() RETURNS trigger AS $$
+BEGIN
+  PERFORM pg_notify('task_queue', '');
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tasks_queued_notify
+  AFTER INSERT ON tasks
+  FOR EACH ROW WHEN (NEW.state = 'queued')
+  EXECUTE FUNCTION notify_task_queue();
+
+CREATE TRIGGER tasks_state_notify
+  AFTER UPDATE OF state ON tasks
+  FOR EACH ROW WHEN (NEW.state = 'queued' OR (OLD.state = 'leased' AND NEW.state <> 'leased'))
+  EXECUTE FUNCTION notify_task_queue();
@@ -0,0 +1,13 @@
+ALTER TABLE task_results
+  ADD COLUMN text TEXT NOT NULL DEFAULT '',
+  ADD COLUMN html TEXT NOT NULL DEFAULT '';
+
+UPDATE task_results SET text = snapshot_contents.text, html = snapshot_contents.html
+  FROM snapshot_contents WHERE snapshot_contents.content_hash = task_results.content_hash;
+
+ALTER TABLE task_results
+  ALTER COLUMN text DROP DEFAULT,
+  ALTER COLUMN html DROP DEFAULT;
+
+DROP TABLE snapshots;
+DROP TABLE snapshot_contents;
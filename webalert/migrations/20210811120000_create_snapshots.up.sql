@@ -0,0 +1,57 @@
+-- The content extracted from pages, stored once per distinct hash no matter how many checks saw it.
+CREATE TABLE snapshot_contents (
+  content_hash TEXT PRIMARY KEY,
+  text         TEXT NOT NULL,
+  html         TEXT NOT NULL,
+  created_at   timestamptz DEFAULT NOW()
+);
+
+-- What the page of an alert looked like over a run of consecutive checks that saw the same content.
+CREATE TABLE snapshots (
+  id              SERIAL PRIMARY KEY,
+  alert_id        INTEGER NOT NULL REFERENCES alerts(id) ON DELETE CASCADE,
+  content_hash    TEXT NOT NULL REFERENCES snapshot_contents(content_hash),
+  final_url       TEXT NOT NULL,
+  http_status     INTEGER,
+  total_ms        BIGINT,
+  checks          INTEGER NOT NULL DEFAULT 1,
+  first_result_id INTEGER REFERENCES task_results(id) ON DELETE SET NULL,
+  last_result_id  INTEGER REFERENCES task_results(id) ON DELETE SET NULL,
+  first_seen_at   timestamptz NOT NULL DEFAULT NOW(),
+  last_seen_at    timestamptz NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX snapshots_alert_idx ON snapshots (alert_id, id);
+
+-- Move the content of the successful results that have been reported so far into snapshots
+CREATE TEMPORARY TABLE successful_results AS
+  SELECT task_results.id, tasks.alert_id, task_results.final_url, task_results.http_status,
+    task_results.total_ms, task_results.text, task_results.html,
+    encode(sha256(convert_to(task_results.html, 'UTF8')), 'hex') AS content_hash,
+    COALESCE(task_results.created_at, NOW()) AS created_at
+  FROM task_results JOIN tasks ON tasks.id = task_results.task_id
+  WHERE task_results.error_code IS NULL;
+
+INSERT INTO snapshot_contents (content_hash, text, html)
+  SELECT DISTINCT ON (content_hash) content_hash, text, html FROM successful_results
+  ORDER BY content_hash, id;
+
+INSERT INTO snapshots (alert_id, content_hash, final_url, http_status, total_ms, checks,
+                       first_result_id, last_result_id, first_seen_at, last_seen_at)
+  SELECT alert_id, content_hash, (array_agg(final_url ORDER BY id))[1],
+    (array_agg(http_status ORDER BY id))[1], (array_agg(total_ms ORDER BY id))[1], COUNT(*),
+    MIN(id), MAX(id), MIN(created_at), MAX(created_at)
+  FROM (
+    -- Consecutive results of an alert with the same content share a run number
+    SELECT *, ROW_NUMBER() OVER (PARTITION BY alert_id ORDER BY id)
+      - ROW_NUMBER() OVER (PARTITION BY alert_id, content_hash ORDER BY id) AS run
+    FROM successful_results
+  ) AS runs
+  GROUP BY alert_id, content_hash, run
+  ORDER BY MIN(id);
+
+DROP TABLE successful_results;
+
+ALTER TABLE task_results
+  DROP COLUMN text,
+  DROP COLUMN html;
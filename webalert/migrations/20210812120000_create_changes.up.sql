@@ -0,0 +1,18 @@
+-- The differences between the content of a snapshot and the snapshot of the alert before it.
+--
+-- Changes are detected as results are recorded, so snapshots captured before this table existed
+-- don't have any.
+CREATE TABLE changes (
+  id                   SERIAL PRIMARY KEY,
+  alert_id             INTEGER NOT NULL REFERENCES alerts(id) ON DELETE CASCADE,
+  snapshot_id          INTEGER NOT NULL UNIQUE REFERENCES snapshots(id) ON DELETE CASCADE,
+  previous_snapshot_id INTEGER NOT NULL REFERENCES snapshots(id) ON DELETE CASCADE,
+  change_ratio         DOUBLE PRECISION NOT NULL,
+  lines_added          INTEGER NOT NULL,
+  lines_removed        INTEGER NOT NULL,
+  unified_diff         TEXT NOT NULL,
+  segments             JSONB NOT NULL,
+  created_at           timestamptz NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX changes_alert_idx ON changes (alert_id, id);
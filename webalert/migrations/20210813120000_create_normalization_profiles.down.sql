@@ -0,0 +1,3 @@
+ALTER TABLE alerts DROP COLUMN normalization_profile_id;
+
+DROP TABLE normalization_profiles;
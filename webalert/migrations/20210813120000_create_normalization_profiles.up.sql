@@ -0,0 +1,30 @@
+-- How the content of pages is normalized before it's hashed and compared, to ignore differences
+-- nobody cares about.
+CREATE TABLE normalization_profiles (
+  id                   SERIAL PRIMARY KEY,
+  name                 TEXT NOT NULL UNIQUE,
+  collapse_whitespace  BOOLEAN NOT NULL DEFAULT FALSE,
+  strip_scripts        BOOLEAN NOT NULL DEFAULT FALSE,
+  strip_styles         BOOLEAN NOT NULL DEFAULT FALSE,
+  strip_comments       BOOLEAN NOT NULL DEFAULT FALSE,
+  sort_attributes      BOOLEAN NOT NULL DEFAULT FALSE,
+  -- Names of attributes to remove, matching by prefix if they end in `*`
+  dropped_attributes   TEXT[] NOT NULL DEFAULT '{}',
+  -- Names of query parameters to remove from URLs, matching by prefix if they end in `*`
+  dropped_query_params TEXT[] NOT NULL DEFAULT '{}',
+  created_at           timestamptz DEFAULT NOW(),
+  updated_at           timestamptz DEFAULT NOW()
+);
+
+INSERT INTO normalization_profiles
+  (name, collapse_whitespace, strip_scripts, strip_styles, strip_comments, sort_attributes,
+   dropped_attributes, dropped_query_params)
+VALUES
+  ('standard', TRUE, TRUE, TRUE, TRUE, TRUE,
+   '{nonce,integrity,data-csrf*}', '{_,v,ver,cb,utm_*}');
+
+-- Alerts without a profile compare the content of their pages as it is, which keeps the hashes
+-- of their existing snapshots valid.
+ALTER TABLE alerts
+  ADD COLUMN normalization_profile_id INTEGER
+    REFERENCES normalization_profiles(id) ON DELETE SET NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE alerts
+  DROP COLUMN ignore_selectors,
+  DROP COLUMN ignore_patterns,
+  DROP COLUMN ignore_lines;
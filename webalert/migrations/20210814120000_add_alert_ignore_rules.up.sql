@@ -0,0 +1,8 @@
+-- Rules that make change detection ignore the parts of a page that change on every check
+ALTER TABLE alerts
+  -- CSS selectors of elements to remove
+  ADD COLUMN ignore_selectors TEXT[] NOT NULL DEFAULT '{}',
+  -- Regexes whose matches are masked
+  ADD COLUMN ignore_patterns  TEXT[] NOT NULL DEFAULT '{}',
+  -- Regexes that remove every line of text they match
+  ADD COLUMN ignore_lines     TEXT[] NOT NULL DEFAULT '{}';
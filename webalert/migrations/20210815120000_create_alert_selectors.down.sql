@@ -0,0 +1,18 @@
+ALTER TABLE task_results DROP COLUMN extracted_values;
+
+-- Only the expression of the first selector can be kept
+ALTER TABLE tasks ADD COLUMN selector TEXT NOT NULL DEFAULT '';
+UPDATE tasks SET selector = COALESCE(selectors -> 0 ->> 'expression', '');
+ALTER TABLE tasks ALTER COLUMN selector DROP DEFAULT;
+ALTER TABLE tasks DROP COLUMN selectors;
+
+ALTER TABLE alerts ADD COLUMN selector TEXT NOT NULL DEFAULT '';
+UPDATE alerts SET selector = first_selectors.expression
+  FROM (
+    SELECT DISTINCT ON (alert_id) alert_id, expression FROM alert_selectors
+    ORDER BY alert_id, position, id
+  ) first_selectors
+  WHERE alerts.id = first_selectors.alert_id;
+ALTER TABLE alerts ALTER COLUMN selector DROP DEFAULT;
+
+DROP TABLE alert_selectors;
@@ -0,0 +1,39 @@
+-- What to extract from the page of an alert. An alert can have several selectors, which are
+-- extracted in order of `position`.
+CREATE TABLE alert_selectors (
+  id         SERIAL PRIMARY KEY,
+  alert_id   INTEGER NOT NULL REFERENCES alerts(id) ON DELETE CASCADE,
+  name       TEXT NOT NULL,
+  kind       TEXT NOT NULL CHECK (kind IN ('css', 'xpath', 'javascript')),
+  expression TEXT NOT NULL,
+  extraction TEXT NOT NULL DEFAULT 'text'
+               CHECK (extraction IN ('text', 'html', 'attribute', 'count')),
+  -- The attribute to extract, which is required by and only allowed for attribute extraction
+  attribute  TEXT CHECK ((extraction = 'attribute') = (attribute IS NOT NULL)),
+  position   INTEGER NOT NULL DEFAULT 0,
+  created_at timestamptz DEFAULT NOW(),
+  updated_at timestamptz DEFAULT NOW(),
+  UNIQUE (alert_id, name)
+);
+
+CREATE INDEX alert_selectors_alert_idx ON alert_selectors (alert_id, position, id);
+
+-- Every alert had a single CSS selector whose text was extracted
+INSERT INTO alert_selectors (alert_id, name, kind, expression, extraction)
+  SELECT id, 'content', 'css', selector, 'text' FROM alerts;
+
+ALTER TABLE alerts DROP COLUMN selector;
+
+-- Tasks keep a copy of the selectors of their alert as of when they were scheduled, like they do
+-- with the URL.
+ALTER TABLE tasks ADD COLUMN selectors JSONB NOT NULL DEFAULT '[]';
+
+UPDATE tasks SET selectors = jsonb_build_array(jsonb_build_object(
+  'name', 'content', 'kind', 'css', 'expression', selector, 'extraction', 'text',
+  'attribute', NULL
+));
+
+ALTER TABLE tasks DROP COLUMN selector;
+
+-- The value extracted by each selector of a task
+ALTER TABLE task_results ADD COLUMN extracted_values JSONB NOT NULL DEFAULT '[]';
@@ -0,0 +1,7 @@
+ALTER TABLE changes
+  DROP COLUMN triggered,
+  DROP COLUMN condition_met;
+
+ALTER TABLE alerts
+  DROP COLUMN condition_met,
+  DROP COLUMN condition;
@@ -0,0 +1,12 @@
+-- Conditions that decide whether a change to the page of an alert triggers a notification
+ALTER TABLE alerts
+  -- The condition as JSON, or NULL if every change triggers a notification
+  ADD COLUMN condition     JSONB,
+  -- If the condition was met by the latest snapshot, or NULL if it hasn't been evaluated
+  ADD COLUMN condition_met BOOLEAN;
+
+ALTER TABLE changes
+  -- If the condition of the alert was met by the new snapshot, or NULL if there was none
+  ADD COLUMN condition_met BOOLEAN,
+  -- If the change triggers a notification
+  ADD COLUMN triggered     BOOLEAN NOT NULL DEFAULT TRUE;
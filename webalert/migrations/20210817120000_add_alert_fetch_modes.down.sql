@@ -0,0 +1,2 @@
+ALTER TABLE tasks DROP COLUMN fetch_mode;
+ALTER TABLE alerts DROP COLUMN fetch_mode;
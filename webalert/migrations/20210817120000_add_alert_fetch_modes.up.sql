@@ -0,0 +1,8 @@
+-- How the pages of alerts are fetched: `browser` loads them in a browser that runs their scripts,
+-- while `http` fetches them with a plain HTTP request, which is much cheaper for static pages.
+ALTER TABLE alerts
+  ADD COLUMN fetch_mode TEXT NOT NULL DEFAULT 'browser' CHECK (fetch_mode IN ('browser', 'http'));
+
+-- Tasks keep a copy of the fetch mode of their alert as of when they were scheduled
+ALTER TABLE tasks
+  ADD COLUMN fetch_mode TEXT NOT NULL DEFAULT 'browser' CHECK (fetch_mode IN ('browser', 'http'));
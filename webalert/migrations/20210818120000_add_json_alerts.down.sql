@@ -0,0 +1,9 @@
+ALTER TABLE changes DROP COLUMN value_changes;
+
+DELETE FROM alert_selectors WHERE kind = 'jsonpath';
+
+ALTER TABLE alert_selectors
+  DROP CONSTRAINT alert_selectors_kind_check,
+  ADD CONSTRAINT alert_selectors_kind_check CHECK (kind IN ('css', 'xpath', 'javascript'));
+
+ALTER TABLE alerts DROP COLUMN format;
@@ -0,0 +1,12 @@
+-- The kind of content the selectors of an alert extract: elements of HTML pages, or values of JSON
+-- documents selected with JSONPath
+ALTER TABLE alerts
+  ADD COLUMN format TEXT NOT NULL DEFAULT 'html' CHECK (format IN ('html', 'json'));
+
+ALTER TABLE alert_selectors
+  DROP CONSTRAINT alert_selectors_kind_check,
+  ADD CONSTRAINT alert_selectors_kind_check
+    CHECK (kind IN ('css', 'xpath', 'javascript', 'jsonpath'));
+
+-- The values that were added, removed or changed by changes to JSON alerts, by JSON pointer
+ALTER TABLE changes ADD COLUMN value_changes JSONB;
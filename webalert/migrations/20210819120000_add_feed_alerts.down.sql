@@ -0,0 +1,17 @@
+DELETE FROM changes WHERE feed_item IS NOT NULL;
+
+DROP INDEX changes_snapshot_idx;
+
+ALTER TABLE changes
+  DROP COLUMN feed_item,
+  ADD CONSTRAINT changes_snapshot_id_key UNIQUE (snapshot_id);
+
+DROP TABLE feed_items;
+
+ALTER TABLE tasks DROP COLUMN format;
+
+DELETE FROM alerts WHERE format = 'feed';
+
+ALTER TABLE alerts
+  DROP CONSTRAINT alerts_format_check,
+  ADD CONSTRAINT alerts_format_check CHECK (format IN ('html', 'json'));
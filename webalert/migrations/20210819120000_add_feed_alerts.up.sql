@@ -0,0 +1,30 @@
+-- Feed alerts watch RSS and Atom feeds, and report every new item of a feed as a change of its own
+ALTER TABLE alerts
+  DROP CONSTRAINT alerts_format_check,
+  ADD CONSTRAINT alerts_format_check CHECK (format IN ('html', 'json', 'feed'));
+
+-- Tasks keep a copy of the format of their alert as of when they were scheduled, as runners
+-- parse feeds before extracting their items
+ALTER TABLE tasks
+  ADD COLUMN format TEXT NOT NULL DEFAULT 'html' CHECK (format IN ('html', 'json', 'feed'));
+
+-- The items of feeds that have been seen by each alert, by their GUID, or their link if they
+-- don't have one
+CREATE TABLE feed_items (
+  id            SERIAL PRIMARY KEY,
+  alert_id      INTEGER NOT NULL REFERENCES alerts(id) ON DELETE CASCADE,
+  guid          TEXT NOT NULL,
+  title         TEXT NOT NULL,
+  link          TEXT NOT NULL,
+  snapshot_id   INTEGER REFERENCES snapshots(id) ON DELETE SET NULL,
+  first_seen_at timestamptz NOT NULL DEFAULT NOW(),
+  UNIQUE (alert_id, guid)
+);
+
+-- A snapshot of a feed has a change for every new item in it
+ALTER TABLE changes
+  DROP CONSTRAINT changes_snapshot_id_key,
+  -- The new item, if the change is of a feed alert
+  ADD COLUMN feed_item JSONB;
+
+CREATE INDEX changes_snapshot_idx ON changes (snapshot_id);
@@ -0,0 +1,18 @@
+ALTER TABLE changes DROP COLUMN diff_image;
+
+ALTER TABLE snapshot_contents DROP COLUMN screenshot;
+
+DELETE FROM tasks WHERE format = 'screenshot';
+
+ALTER TABLE tasks
+  DROP CONSTRAINT tasks_format_check,
+  ADD CONSTRAINT tasks_format_check CHECK (format IN ('html', 'json', 'feed'));
+
+DELETE FROM alerts WHERE format = 'screenshot';
+
+ALTER TABLE alerts
+  DROP COLUMN screenshot_ignore_regions,
+  DROP COLUMN screenshot_pixel_threshold,
+  DROP COLUMN screenshot_threshold,
+  DROP CONSTRAINT alerts_format_check,
+  ADD CONSTRAINT alerts_format_check CHECK (format IN ('html', 'json', 'feed'));
@@ -0,0 +1,27 @@
+-- Screenshot alerts compare what pages look like rather than their content, for pages whose
+-- changes don't show up in their text, e.g. because they're drawn on a canvas
+ALTER TABLE alerts
+  DROP CONSTRAINT alerts_format_check,
+  ADD CONSTRAINT alerts_format_check
+    CHECK (format IN ('html', 'json', 'feed', 'screenshot')),
+  -- The fraction of pixels that must differ between two screenshots for them to be a change
+  ADD COLUMN screenshot_threshold DOUBLE PRECISION NOT NULL DEFAULT 0.001
+    CHECK (screenshot_threshold BETWEEN 0 AND 1),
+  -- How different the colors of a pixel must look, from 0 to 1, for the pixel to differ, so that
+  -- rendering noise isn't taken for a change
+  ADD COLUMN screenshot_pixel_threshold DOUBLE PRECISION NOT NULL DEFAULT 0.1
+    CHECK (screenshot_pixel_threshold BETWEEN 0 AND 1),
+  -- Rectangles of screenshots that aren't compared, e.g. a clock or an ad, as a JSON array of
+  -- objects with the `x`, `y`, `width` and `height` of each rectangle in pixels
+  ADD COLUMN screenshot_ignore_regions JSONB NOT NULL DEFAULT '[]';
+
+ALTER TABLE tasks
+  DROP CONSTRAINT tasks_format_check,
+  ADD CONSTRAINT tasks_format_check CHECK (format IN ('html', 'json', 'feed', 'screenshot'));
+
+-- The PNG screenshot of a snapshot of a screenshot alert, whose content hash is of the screenshot
+ALTER TABLE snapshot_contents ADD COLUMN screenshot BYTEA;
+
+-- The new screenshot with the pixels that differ highlighted, if the change is of a screenshot
+-- alert
+ALTER TABLE changes ADD COLUMN diff_image BYTEA;
@@ -1,12 +1,64 @@
+use std::fmt::Display;
 use std::net::SocketAddr;
+use std::str::FromStr;
 
 use structopt::StructOpt;
 
+use crate::scope::Scope;
+
+/// The longest a runner can go unseen before it's assumed dead, which keeps the time runners must
+/// have been seen since within the range of dates.
+const MAX_RUNNER_DEAD_AFTER_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// The most days a token can be created to expire in, which keeps its expiry time within the
+/// range of dates.
+const MAX_TOKEN_EXPIRES_IN_DAYS: u32 = 100 * 365;
+
+/// Parses a number that must be at least 1.
+fn parse_positive<T>(value: &str) -> Result<T, String>
+where
+    T: FromStr + PartialOrd + From<u8>,
+    T::Err: Display,
+{
+    let number: T = value.parse().map_err(|err: T::Err| err.to_string())?;
+
+    if number < T::from(1) {
+        Err("must be at least 1".to_string())
+    } else {
+        Ok(number)
+    }
+}
+
+/// Parses the number of seconds a runner can go unseen before it's assumed dead.
+fn parse_dead_after(value: &str) -> Result<u64, String> {
+    let secs = parse_positive(value)?;
+
+    if secs > MAX_RUNNER_DEAD_AFTER_SECS {
+        Err(format!("must be at most {}", MAX_RUNNER_DEAD_AFTER_SECS))
+    } else {
+        Ok(secs)
+    }
+}
+
+/// Parses the number of days until a new token expires.
+fn parse_expires_in_days(value: &str) -> Result<u32, String> {
+    let days = value.parse().map_err(|err: std::num::ParseIntError| err.to_string())?;
+
+    if days > MAX_TOKEN_EXPIRES_IN_DAYS {
+        Err(format!("must be at most {}", MAX_TOKEN_EXPIRES_IN_DAYS))
+    } else {
+        Ok(days)
+    }
+}
+
 #[derive(StructOpt, Debug)]
 pub enum Command {
     /// Run the webalert daemon
     #[structopt(alias = "s")]
     Server(ServerOpts),
+    /// Manage the access tokens used by runners and other clients
+    #[structopt(alias = "t")]
+    Token(TokenOpts),
 }
 
 #[derive(StructOpt, Debug)]
@@ -40,4 +92,90 @@ pub struct ServerOpts {
         default_value = "postgresql://webalert@localhost/webalert_development"
     )]
     pub database_url: String,
+
+    /// The number of seconds a runner can go unseen before it's assumed dead, up to a year
+    #[structopt(
+        long = "runner-dead-after",
+        env = "WEBALERT_RUNNER_DEAD_AFTER",
+        default_value = "60",
+        parse(try_from_str = parse_dead_after)
+    )]
+    pub runner_dead_after_secs: u64,
+
+    /// The number of seconds between checks for alerts that are due
+    #[structopt(
+        long = "scheduler-interval",
+        env = "WEBALERT_SCHEDULER_INTERVAL",
+        default_value = "5",
+        parse(try_from_str = parse_positive)
+    )]
+    pub scheduler_interval_secs: u64,
+
+    /// The number of seconds a runner has to finish a task before it's handed out again
+    #[structopt(
+        long = "task-lease",
+        env = "WEBALERT_TASK_LEASE",
+        default_value = "60",
+        parse(try_from_str = parse_positive)
+    )]
+    pub task_lease_secs: u64,
+
+    /// The number of times a task is handed out before it's given up on
+    #[structopt(
+        long = "task-max-attempts",
+        env = "WEBALERT_TASK_MAX_ATTEMPTS",
+        default_value = "3",
+        parse(try_from_str = parse_positive)
+    )]
+    pub task_max_attempts: i32,
+
+    /// The number of seconds to wait for in-flight requests to finish when shutting down
+    #[structopt(
+        long = "drain-timeout",
+        env = "WEBALERT_DRAIN_TIMEOUT",
+        default_value = "30"
+    )]
+    pub drain_timeout_secs: u64,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct TokenOpts {
+    /// PostgreSQL host
+    #[structopt(
+        long,
+        env = "DATABASE_URL",
+        default_value = "postgresql://webalert@localhost/webalert_development"
+    )]
+    pub database_url: String,
+
+    #[structopt(subcommand)]
+    pub command: TokenCommand,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum TokenCommand {
+    /// Create a new token and print its secret
+    Create {
+        /// The human-readable name of the token
+        #[structopt(long)]
+        name: String,
+        /// A scope to grant the token, e.g. `runner:poll`. Can be given multiple times
+        #[structopt(long = "scope", required = true, number_of_values = 1)]
+        scopes: Vec<Scope>,
+        /// The number of days until the token expires. Tokens don't expire by default
+        #[structopt(long, parse(try_from_str = parse_expires_in_days))]
+        expires_in_days: Option<u32>,
+    },
+    /// List all tokens
+    List,
+    /// Revoke a token so it can no longer be used
+    Revoke {
+        /// The name of the token to revoke
+        name: String,
+    },
+    /// Replace the secret of a token and print the new secret
+    Rotate {
+        /// The name of the token to rotate
+        name: String,
+    },
 }
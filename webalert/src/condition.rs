@@ -0,0 +1,377 @@
+//! Conditions that decide whether a change to the page of an alert is worth a notification.
+//!
+//! Most alerts don't care about every change, but about the content matching a predicate, like a
+//! price dropping below some amount or a page no longer saying "Sold out". A condition is
+//! evaluated against the content of every new snapshot of an alert, and a change only triggers a
+//! notification when it makes the condition go from unmet to met.
+//!
+//! Conditions are stored as JSON, e.g.:
+//!
+//! ```json
+//! {"op": "and", "conditions": [
+//!   {"op": "not_contains", "value": "Sold out"},
+//!   {"op": "compare", "selector": "price", "comparison": "less_than", "value": 500,
+//!    "locale": "da"}
+//! ]}
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+
+use regex::RegexBuilder;
+use serde::{Deserialize, Serialize};
+
+/// The languages whose numbers use a decimal comma.
+const DECIMAL_COMMA_LANGUAGES: &[&str] = &[
+    "bg", "ca", "cs", "da", "de", "el", "es", "et", "fi", "fr", "hr", "hu", "id", "is", "it", "lt",
+    "lv", "nb", "nl", "nn", "no", "pl", "pt", "ro", "ru", "sk", "sl", "sr", "sv", "tr", "uk", "vi",
+];
+
+/// The locales that use a decimal point even though their language uses a decimal comma.
+const DECIMAL_POINT_LOCALES: &[&str] = &["de-ch", "de-li", "it-ch"];
+
+/// The characters that separate groups of thousands in numbers, in any locale.
+const GROUP_SEPARATORS: &[char] = &[',', '.', ' ', '\u{a0}', '\u{202f}', '\'', '\u{2019}'];
+
+/// Error returned when a condition can't be evaluated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The regex of a `matches` condition can't be parsed.
+    InvalidPattern(String, String),
+    /// A condition refers to a selector that didn't extract a value.
+    UnknownSelector(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidPattern(pattern, err) => {
+                write!(f, "invalid pattern {:?}: {}", pattern, err)
+            }
+            Error::UnknownSelector(name) => write!(f, "unknown selector {:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// How a number extracted from the content is compared to the value of a condition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    Equal,
+    NotEqual,
+}
+
+/// A predicate on the content of a snapshot.
+///
+/// The conditions that look at content look at the text of the snapshot, unless they name the
+/// `selector` whose extracted value to look at instead.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Condition {
+    /// Met if all of the `conditions` are met, or if there are none.
+    And { conditions: Vec<Condition> },
+    /// Met if any of the `conditions` are met.
+    Or { conditions: Vec<Condition> },
+    /// Met if the `condition` isn't met.
+    Not { condition: Box<Condition> },
+    /// Met if the content contains `value`.
+    Contains {
+        value: String,
+        #[serde(default)]
+        selector: Option<String>,
+        #[serde(default)]
+        ignore_case: bool,
+    },
+    /// Met if the content doesn't contain `value`.
+    NotContains {
+        value: String,
+        #[serde(default)]
+        selector: Option<String>,
+        #[serde(default)]
+        ignore_case: bool,
+    },
+    /// Met if the regex `pattern` matches the content.
+    Matches {
+        pattern: String,
+        #[serde(default)]
+        selector: Option<String>,
+        #[serde(default)]
+        ignore_case: bool,
+    },
+    /// Met if the first number in the content, written the way numbers are in `locale`, compares
+    /// to `value` as given by `comparison`. Content without a number doesn't meet it.
+    Compare {
+        comparison: Comparison,
+        value: f64,
+        #[serde(default)]
+        selector: Option<String>,
+        /// The locale the number is written in, e.g. `en` or `de-DE`. Defaults to English.
+        #[serde(default)]
+        locale: Option<String>,
+    },
+    /// Met if the content differs from the content of the previous snapshot.
+    Changed {
+        #[serde(default)]
+        selector: Option<String>,
+    },
+    /// Met if there's a previous snapshot and the content is the same as its content.
+    Unchanged {
+        #[serde(default)]
+        selector: Option<String>,
+    },
+}
+
+/// The content of a snapshot that conditions are evaluated against.
+#[derive(Debug, Clone, Default)]
+pub struct Content<'a> {
+    /// The text of the snapshot.
+    pub text: &'a str,
+    /// The values extracted by the selectors of the alert, by the name of the selector.
+    pub values: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Content<'a> {
+    /// Returns the value extracted by the selector with the given `name`, or the text if there's
+    /// no `name`.
+    fn get(&self, name: Option<&str>) -> Result<&'a str, Error> {
+        match name {
+            Some(name) => self
+                .values
+                .get(name)
+                .copied()
+                .ok_or_else(|| Error::UnknownSelector(name.to_string())),
+            None => Ok(self.text),
+        }
+    }
+}
+
+/// Returns true if `text` contains `value`.
+fn contains(text: &str, value: &str, ignore_case: bool) -> bool {
+    if ignore_case {
+        text.to_lowercase().contains(&value.to_lowercase())
+    } else {
+        text.contains(value)
+    }
+}
+
+/// Returns the character that separates the integer part of numbers from the fraction in
+/// `locale`.
+fn decimal_separator(locale: &str) -> char {
+    let locale = locale.replace('_', "-").to_lowercase();
+    let language = locale.split('-').next().unwrap_or_default();
+
+    if DECIMAL_COMMA_LANGUAGES.contains(&language)
+        && !DECIMAL_POINT_LOCALES.iter().any(|l| locale.starts_with(l))
+    {
+        ','
+    } else {
+        '.'
+    }
+}
+
+/// Returns the first number in `text`, written the way numbers are in `locale`.
+///
+/// Groups of thousands can be separated by any of the usual separators other than the decimal
+/// separator of the locale, as long as every group has three digits.
+pub fn parse_number(text: &str, locale: &str) -> Option<f64> {
+    let decimal = decimal_separator(locale);
+    let chars: Vec<char> = text.chars().collect();
+    let start = chars.iter().position(|c| c.is_ascii_digit())?;
+    let is_digit = |i: usize| chars.get(i).is_some_and(|c| c.is_ascii_digit());
+
+    let mut number = String::new();
+
+    if start > 0 && matches!(chars[start - 1], '-' | '\u{2212}') {
+        number.push('-');
+    }
+
+    let mut i = start;
+    let mut in_fraction = false;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_ascii_digit() {
+            number.push(c);
+        } else if c == decimal && !in_fraction && is_digit(i + 1) {
+            number.push('.');
+            in_fraction = true;
+        } else if GROUP_SEPARATORS.contains(&c)
+            && !in_fraction
+            && (1..=3).all(|n| is_digit(i + n))
+            && !is_digit(i + 4)
+        {
+            // The separator is dropped, and the group is pushed as digits
+        } else {
+            break;
+        }
+
+        i += 1;
+    }
+
+    number.parse().ok()
+}
+
+impl Comparison {
+    /// Returns true if `number` compares to `value`.
+    fn holds(self, number: f64, value: f64) -> bool {
+        match self {
+            Comparison::LessThan => number < value,
+            Comparison::LessThanOrEqual => number <= value,
+            Comparison::GreaterThan => number > value,
+            Comparison::GreaterThanOrEqual => number >= value,
+            Comparison::Equal => number == value,
+            Comparison::NotEqual => number != value,
+        }
+    }
+}
+
+impl Condition {
+    /// Returns true if the condition is met by the `current` content, given the content of the
+    /// `previous` snapshot if there is one.
+    pub fn evaluate(&self, current: &Content, previous: Option<&Content>) -> Result<bool, Error> {
+        let met = match self {
+            Condition::And { conditions } => {
+                for condition in conditions {
+                    if !condition.evaluate(current, previous)? {
+                        return Ok(false);
+                    }
+                }
+
+                true
+            }
+            Condition::Or { conditions } => {
+                for condition in conditions {
+                    if condition.evaluate(current, previous)? {
+                        return Ok(true);
+                    }
+                }
+
+                false
+            }
+            Condition::Not { condition } => !condition.evaluate(current, previous)?,
+            Condition::Contains {
+                value,
+                selector,
+                ignore_case,
+            } => contains(current.get(selector.as_deref())?, value, *ignore_case),
+            Condition::NotContains {
+                value,
+                selector,
+                ignore_case,
+            } => !contains(current.get(selector.as_deref())?, value, *ignore_case),
+            Condition::Matches {
+                pattern,
+                selector,
+                ignore_case,
+            } => RegexBuilder::new(pattern)
+                .case_insensitive(*ignore_case)
+                .build()
+                .map_err(|err| Error::InvalidPattern(pattern.clone(), err.to_string()))?
+                .is_match(current.get(selector.as_deref())?),
+            Condition::Compare {
+                comparison,
+                value,
+                selector,
+                locale,
+            } => {
+                let text = current.get(selector.as_deref())?;
+                let locale = locale.as_deref().unwrap_or("en");
+
+                parse_number(text, locale).is_some_and(|number| comparison.holds(number, *value))
+            }
+            Condition::Changed { selector } => match previous {
+                Some(previous) => {
+                    current.get(selector.as_deref())? != previous.get(selector.as_deref())?
+                }
+                None => false,
+            },
+            Condition::Unchanged { selector } => match previous {
+                Some(previous) => {
+                    current.get(selector.as_deref())? == previous.get(selector.as_deref())?
+                }
+                None => false,
+            },
+        };
+
+        Ok(met)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content<'a>(text: &'a str, values: &[(&'a str, &'a str)]) -> Content<'a> {
+        Content {
+            text,
+            values: values.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn it_should_parse_numbers() {
+        assert_eq!(parse_number("Price: 1,299.50 USD", "en"), Some(1299.5));
+        assert_eq!(parse_number("Pris: 1.299,50 kr.", "da"), Some(1299.5));
+        assert_eq!(
+            parse_number("Prix : 1\u{202f}299,50 €", "fr-FR"),
+            Some(1299.5)
+        );
+        assert_eq!(parse_number("Preis: CHF 1'299.50", "de-CH"), Some(1299.5));
+        assert_eq!(parse_number("Down -12.5%", "en"), Some(-12.5));
+        assert_eq!(parse_number("Only 3 left.", "en"), Some(3.0));
+        assert_eq!(parse_number("Sold out", "en"), None);
+    }
+
+    #[test]
+    fn it_should_evaluate_conditions() {
+        let condition: Condition = serde_json::from_str(
+            r#"{"op": "and", "conditions": [
+                 {"op": "not_contains", "value": "sold out", "ignore_case": true},
+                 {"op": "compare", "selector": "price", "comparison": "less_than", "value": 500,
+                  "locale": "de"},
+                 {"op": "not", "condition": {"op": "matches", "pattern": "^Pre-?order"}}
+               ]}"#,
+        )
+        .unwrap();
+
+        let in_stock = content("In stock", &[("price", "499,00 €")]);
+        let sold_out = content("Sold Out", &[("price", "499,00 €")]);
+        let expensive = content("In stock", &[("price", "1.499,00 €")]);
+
+        assert_eq!(condition.evaluate(&in_stock, None), Ok(true));
+        assert_eq!(condition.evaluate(&sold_out, None), Ok(false));
+        assert_eq!(condition.evaluate(&expensive, None), Ok(false));
+    }
+
+    #[test]
+    fn it_should_compare_to_the_previous_content() {
+        let changed = Condition::Changed {
+            selector: Some("price".to_string()),
+        };
+        let previous = content("a", &[("price", "10")]);
+        let current = content("b", &[("price", "10")]);
+
+        assert_eq!(changed.evaluate(&current, None), Ok(false));
+        assert_eq!(changed.evaluate(&current, Some(&previous)), Ok(false));
+        assert_eq!(
+            Condition::Changed { selector: None }.evaluate(&current, Some(&previous)),
+            Ok(true)
+        );
+        assert_eq!(
+            Condition::Contains {
+                value: "x".to_string(),
+                selector: Some("stock".to_string()),
+                ignore_case: false
+            }
+            .evaluate(&current, None),
+            Err(Error::UnknownSelector("stock".to_string()))
+        );
+    }
+}
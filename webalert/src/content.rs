@@ -0,0 +1,110 @@
+//! Preparing the content of pages before it's hashed and compared.
+
+use crate::ignore::{self, Rules};
+use crate::json;
+use crate::normalize::Pipeline;
+
+/// The kind of content the selectors of an alert extract.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Elements of HTML pages.
+    #[default]
+    Html,
+    /// Values of JSON documents, which runners send as a JSON object of the value of each
+    /// selector by its name.
+    Json,
+    /// Items of RSS and Atom feeds, which runners send as a JSON document of the items of the
+    /// feed, along with a line of text for each item.
+    Feed,
+    /// Screenshots of pages, which runners send along with empty text and HTML.
+    Screenshot,
+}
+
+impl Format {
+    /// Returns the format with the given `name`, as stored with alerts, if there is one.
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "html" => Some(Format::Html),
+            "json" => Some(Format::Json),
+            "feed" => Some(Format::Feed),
+            "screenshot" => Some(Format::Screenshot),
+            _ => None,
+        }
+    }
+}
+
+/// How the content of the pages of an alert is prepared.
+///
+/// The default filter leaves content as it is.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// The kind of content of the alert.
+    pub format: Format,
+    /// The normalization profile of the alert.
+    pub pipeline: Pipeline,
+    /// The ignore rules of the alert.
+    pub rules: Rules,
+}
+
+/// Content that has been prepared by a [`Filter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prepared {
+    /// The prepared text of the values extracted by the selectors of the alert.
+    pub text: String,
+    /// The prepared HTML of the elements matched by the selectors of the alert.
+    pub html: String,
+}
+
+impl Filter {
+    /// Returns true if content is only told apart by its prepared text, because the ignore rules
+    /// remove lines of text that the prepared HTML still has.
+    pub fn ignores_lines(&self) -> bool {
+        self.format == Format::Html && self.rules.filters_lines()
+    }
+
+    /// Normalizes the `text` and `html` of a page and applies the ignore rules to them.
+    ///
+    /// If the rules exclude elements, the text is derived from the prepared HTML instead, so that
+    /// the text of the excluded elements is left out.
+    ///
+    /// The content of JSON alerts is canonicalized instead, with the compact document as the HTML
+    /// and the pretty-printed document as the text. It's left as it is if it isn't valid JSON. The
+    /// document of the items of feed alerts is canonicalized too, but their text is kept, and the
+    /// content of screenshot alerts is left as it is.
+    pub fn apply(&self, text: &str, html: &str) -> Prepared {
+        match self.format {
+            Format::Json => {
+                return match json::canonical(html) {
+                    Ok((html, text)) => Prepared { text, html },
+                    Err(_) => Prepared {
+                        text: text.to_string(),
+                        html: html.to_string(),
+                    },
+                };
+            }
+            Format::Feed => {
+                return Prepared {
+                    text: text.to_string(),
+                    html: json::canonical(html).map_or_else(|_| html.to_string(), |(html, _)| html),
+                };
+            }
+            Format::Screenshot => {
+                return Prepared {
+                    text: text.to_string(),
+                    html: html.to_string(),
+                };
+            }
+            Format::Html => {}
+        }
+
+        let html = self.rules.html(&self.pipeline.html(html));
+        let text = if self.rules.excludes_elements() {
+            ignore::text_of(&html)
+        } else {
+            text.to_string()
+        };
+        let text = self.rules.text(&self.pipeline.text(&text));
+
+        Prepared { text, html }
+    }
+}
@@ -3,6 +3,17 @@ use sqlx::{
     Error,
 };
 
+pub mod alerts;
+pub mod changes;
+pub mod conditions;
+pub mod normalization_profiles;
+pub mod results;
+pub mod runners;
+pub mod selectors;
+pub mod snapshots;
+pub mod tasks;
+pub mod tokens;
+
 /// The database pool type. We're using Postgres for now.
 pub type DbPool = PgPool;
 
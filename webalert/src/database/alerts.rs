@@ -0,0 +1,57 @@
+//! The alerts that users have set up to watch pages for changes.
+
+use sqlx::{postgres::PgConnection, Error, FromRow};
+
+use super::DbPool;
+use crate::content::Format;
+use crate::ignore::{self, Rules};
+
+/// The ignore rules stored with an alert.
+#[derive(Debug, Clone, FromRow)]
+pub struct IgnoreRules {
+    /// The CSS selectors of elements to remove.
+    pub ignore_selectors: Vec<String>,
+    /// The regexes whose matches are masked.
+    pub ignore_patterns: Vec<String>,
+    /// The regexes that remove every line of text they match.
+    pub ignore_lines: Vec<String>,
+}
+
+impl IgnoreRules {
+    /// Compiles the rules.
+    pub fn compile(&self) -> Result<Rules, ignore::Error> {
+        Rules::new(
+            &self.ignore_selectors,
+            &self.ignore_patterns,
+            &self.ignore_lines,
+        )
+    }
+}
+
+/// Returns true if there's an alert with the given `id`.
+pub async fn exists(pool: &DbPool, id: i32) -> Result<bool, Error> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM alerts WHERE id = $1)")
+        .bind(id)
+        .fetch_one(pool)
+        .await
+}
+
+/// Returns the ignore rules of the alert with the given `id`, if it exists.
+pub async fn ignore_rules(conn: &mut PgConnection, id: i32) -> Result<Option<IgnoreRules>, Error> {
+    sqlx::query_as::<_, IgnoreRules>(
+        "SELECT ignore_selectors, ignore_patterns, ignore_lines FROM alerts WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(conn)
+    .await
+}
+
+/// Returns the format of the content of the alert with the given `id`, if it exists.
+pub async fn format(conn: &mut PgConnection, id: i32) -> Result<Option<Format>, Error> {
+    let format: Option<String> = sqlx::query_scalar("SELECT format FROM alerts WHERE id = $1")
+        .bind(id)
+        .fetch_optional(conn)
+        .await?;
+
+    Ok(format.map(|format| Format::from_name(&format).unwrap_or_default()))
+}
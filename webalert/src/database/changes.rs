@@ -0,0 +1,456 @@
+//! The changes detected between consecutive snapshots of alerts.
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgConnection, types::Json, Error, FromRow};
+use tokio::task;
+use tracing::warn;
+
+use super::DbPool;
+use crate::content::Format;
+use crate::diff::{self, Segment};
+use crate::feed::{Feed, FeedItem};
+use crate::json::{self, ValueChange};
+use crate::screenshot::{self, Comparison, Options, Region};
+
+/// The columns selected when querying for a [`Change`].
+const CHANGE_COLUMNS: &str = "id, alert_id, snapshot_id, previous_snapshot_id, change_ratio, \
+                              lines_added, lines_removed, unified_diff, segments, value_changes, \
+                              feed_item, condition_met, triggered, created_at";
+
+/// A row in the `changes` table.
+#[derive(Debug, Clone, FromRow)]
+pub struct Change {
+    /// The unique id of the change.
+    pub id: i32,
+    /// The id of the alert whose page changed.
+    pub alert_id: i32,
+    /// The id of the snapshot with the new content.
+    pub snapshot_id: i32,
+    /// The id of the snapshot with the old content.
+    pub previous_snapshot_id: i32,
+    /// The fraction of words that changed.
+    pub change_ratio: f64,
+    /// The number of lines that were added.
+    pub lines_added: i32,
+    /// The number of lines that were removed.
+    pub lines_removed: i32,
+    /// The line differences as a unified diff.
+    pub unified_diff: String,
+    /// The content split into added, removed and unchanged word segments.
+    pub segments: Json<Vec<Segment>>,
+    /// The values that were added, removed or changed, if the alert is a JSON alert.
+    pub value_changes: Option<Json<Vec<ValueChange>>>,
+    /// The new item, if the alert is a feed alert.
+    pub feed_item: Option<Json<FeedItem>>,
+    /// If the condition of the alert was met by the new content, or `None` if the alert had no
+    /// condition or it couldn't be evaluated.
+    pub condition_met: Option<bool>,
+    /// If the change triggers a notification.
+    pub triggered: bool,
+    /// The time the change was detected.
+    pub created_at: DateTime<Utc>,
+}
+
+/// The content of a snapshot and the snapshot of the same alert before it.
+#[derive(Debug, Clone, FromRow)]
+struct Contents {
+    format: String,
+    previous_snapshot_id: i32,
+    previous_text: String,
+    previous_html: String,
+    text: String,
+    html: String,
+}
+
+/// The differences found between the content of two snapshots.
+struct Differences {
+    change_ratio: f64,
+    lines_added: usize,
+    lines_removed: usize,
+    unified_diff: String,
+    segments: Vec<Segment>,
+    value_changes: Option<Vec<ValueChange>>,
+}
+
+impl Contents {
+    /// Returns the differences between the previous and the current content, if there are any.
+    ///
+    /// The content of JSON alerts is compared as JSON documents, as long as both versions are
+    /// valid JSON, and the content of other alerts as text.
+    fn compare(&self) -> Option<Differences> {
+        let documents = match Format::from_name(&self.format).unwrap_or_default() {
+            Format::Json => serde_json::from_str::<serde_json::Value>(&self.previous_html)
+                .and_then(|old| Ok((old, serde_json::from_str(&self.html)?)))
+                .ok(),
+            Format::Html | Format::Feed | Format::Screenshot => None,
+        };
+
+        if let Some((old, new)) = documents {
+            let value_changes = json::diff(&old, &new);
+
+            if value_changes.is_empty() {
+                return None;
+            }
+
+            return Some(Differences {
+                change_ratio: json::change_ratio(&old, &new, &value_changes),
+                lines_added: 0,
+                lines_removed: 0,
+                unified_diff: String::new(),
+                segments: Vec::new(),
+                value_changes: Some(value_changes),
+            });
+        }
+
+        let comparison = diff::compare(&self.previous_text, &self.text);
+
+        if !comparison.is_changed() {
+            return None;
+        }
+
+        Some(Differences {
+            change_ratio: comparison.change_ratio,
+            lines_added: comparison.lines_added,
+            lines_removed: comparison.lines_removed,
+            unified_diff: comparison.unified_diff,
+            segments: comparison.segments,
+            value_changes: None,
+        })
+    }
+}
+
+/// The items of the feed of a snapshot, and the snapshot of the same alert before it.
+#[derive(Debug, Clone, FromRow)]
+struct FeedContents {
+    alert_id: i32,
+    previous_snapshot_id: Option<i32>,
+    html: String,
+    seen_before: bool,
+}
+
+/// The baseline screenshot of an alert, along with how the alert compares screenshots to it.
+#[derive(Debug, Clone, FromRow)]
+struct Baseline {
+    screenshot_threshold: f64,
+    screenshot_pixel_threshold: f64,
+    screenshot_ignore_regions: Json<Vec<Region>>,
+    snapshot_id: i32,
+    screenshot: Option<Vec<u8>>,
+}
+
+/// How the screenshot of a check compares to the baseline screenshot of its alert.
+#[derive(Debug, Clone)]
+pub struct Visual {
+    /// The id of the snapshot whose screenshot is the baseline.
+    pub baseline_snapshot_id: i32,
+    /// The outcome of comparing the screenshots.
+    pub comparison: Comparison,
+    /// True if enough pixels differ for the screenshot to be a change.
+    pub changed: bool,
+}
+
+/// Compares the content of the snapshot with the given `snapshot_id` to the content of the
+/// snapshot of the same alert before it, and records a change if they differ.
+///
+/// The text of snapshots is compared, except for JSON alerts whose documents are compared value
+/// by value and feed alerts which have a change for every new item instead. Snapshots whose
+/// content is the same as before, e.g. because only the markup around the text changed, and the
+/// first snapshot of an alert don't have a change. Screenshots are compared before they're
+/// captured instead, see [`compare_screenshot`], so snapshots of screenshot alerts don't have a
+/// change here.
+///
+/// Returns the ids of the changes that were recorded.
+pub async fn detect(conn: &mut PgConnection, snapshot_id: i32) -> Result<Vec<i32>, Error> {
+    let format: String = sqlx::query_scalar(
+        "SELECT alerts.format FROM snapshots JOIN alerts ON alerts.id = snapshots.alert_id \
+         WHERE snapshots.id = $1",
+    )
+    .bind(snapshot_id)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    match Format::from_name(&format) {
+        Some(Format::Feed) => return detect_items(conn, snapshot_id).await,
+        Some(Format::Screenshot) => return Ok(Vec::new()),
+        _ => {}
+    }
+
+    let contents = sqlx::query_as::<_, Contents>(
+        "SELECT alerts.format, previous.id AS previous_snapshot_id, \
+           previous_contents.text AS previous_text, previous_contents.html AS previous_html, \
+           contents.text, contents.html \
+         FROM snapshots \
+         JOIN alerts ON alerts.id = snapshots.alert_id \
+         JOIN snapshot_contents contents ON contents.content_hash = snapshots.content_hash \
+         JOIN LATERAL ( \
+           SELECT id, content_hash FROM snapshots previous \
+           WHERE previous.alert_id = snapshots.alert_id AND previous.id < snapshots.id \
+           ORDER BY previous.id DESC LIMIT 1 \
+         ) previous ON TRUE \
+         JOIN snapshot_contents previous_contents \
+           ON previous_contents.content_hash = previous.content_hash \
+         WHERE snapshots.id = $1",
+    )
+    .bind(snapshot_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let (contents, differences) = match contents {
+        Some(contents) => match contents.compare() {
+            Some(differences) => (contents, differences),
+            None => return Ok(Vec::new()),
+        },
+        None => return Ok(Vec::new()),
+    };
+
+    sqlx::query_scalar(
+        "INSERT INTO changes (alert_id, snapshot_id, previous_snapshot_id, change_ratio, \
+                              lines_added, lines_removed, unified_diff, segments, value_changes) \
+         SELECT alert_id, id, $2, $3, $4, $5, $6, $7, $8 FROM snapshots WHERE id = $1 \
+         RETURNING id",
+    )
+    .bind(snapshot_id)
+    .bind(contents.previous_snapshot_id)
+    .bind(differences.change_ratio)
+    .bind(differences.lines_added as i32)
+    .bind(differences.lines_removed as i32)
+    .bind(&differences.unified_diff)
+    .bind(Json(&differences.segments))
+    .bind(differences.value_changes.as_ref().map(Json))
+    .fetch_one(conn)
+    .await
+    .map(|id| vec![id])
+}
+
+/// Remembers the items of the feed of the snapshot with the given `snapshot_id` as seen by its
+/// alert, and records a change for every item the alert hadn't seen before, in feed order.
+///
+/// Items are told apart by their id, so an item that drops out of the feed and comes back isn't
+/// new. The items of the first snapshot of an alert only serve as a baseline.
+async fn detect_items(conn: &mut PgConnection, snapshot_id: i32) -> Result<Vec<i32>, Error> {
+    let contents = sqlx::query_as::<_, FeedContents>(
+        "SELECT snapshots.alert_id, previous.id AS previous_snapshot_id, contents.html, \
+           EXISTS (SELECT 1 FROM feed_items WHERE alert_id = snapshots.alert_id) AS seen_before \
+         FROM snapshots \
+         JOIN snapshot_contents contents ON contents.content_hash = snapshots.content_hash \
+         LEFT JOIN LATERAL ( \
+           SELECT id FROM snapshots previous \
+           WHERE previous.alert_id = snapshots.alert_id AND previous.id < snapshots.id \
+           ORDER BY previous.id DESC LIMIT 1 \
+         ) previous ON TRUE \
+         WHERE snapshots.id = $1",
+    )
+    .bind(snapshot_id)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let feed = match Feed::parse(&contents.html) {
+        Ok(feed) => feed,
+        Err(err) => {
+            warn!(alert.id = contents.alert_id, %err, "Not detecting items of invalid feed");
+
+            return Ok(Vec::new());
+        }
+    };
+
+    let new: HashSet<String> = sqlx::query_scalar(
+        "INSERT INTO feed_items (alert_id, snapshot_id, guid, title, link) \
+         SELECT $1, $2, items.* FROM UNNEST($3::TEXT[], $4::TEXT[], $5::TEXT[]) AS items \
+         ON CONFLICT (alert_id, guid) DO NOTHING \
+         RETURNING guid",
+    )
+    .bind(contents.alert_id)
+    .bind(snapshot_id)
+    .bind(
+        feed.items
+            .iter()
+            .map(|item| item.id.as_str())
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        feed.items
+            .iter()
+            .map(|item| item.title.as_str())
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        feed.items
+            .iter()
+            .map(|item| item.link.as_str())
+            .collect::<Vec<_>>(),
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .collect();
+
+    let previous_snapshot_id = match contents.previous_snapshot_id {
+        Some(id) if contents.seen_before => id,
+        _ => return Ok(Vec::new()),
+    };
+
+    // A feed that lists an item twice only has one change for it
+    let mut reported = HashSet::new();
+    let new_items: Vec<&FeedItem> = feed
+        .items
+        .iter()
+        .filter(|item| new.contains(&item.id) && reported.insert(&item.id))
+        .collect();
+    let change_ratio = new_items.len() as f64 / feed.items.len() as f64;
+    let mut change_ids = Vec::with_capacity(new_items.len());
+
+    for item in new_items {
+        let change_id = sqlx::query_scalar(
+            "INSERT INTO changes (alert_id, snapshot_id, previous_snapshot_id, change_ratio, \
+                                  lines_added, lines_removed, unified_diff, segments, feed_item) \
+             VALUES ($1, $2, $3, $4, 0, 0, '', '[]', $5) \
+             RETURNING id",
+        )
+        .bind(contents.alert_id)
+        .bind(snapshot_id)
+        .bind(previous_snapshot_id)
+        .bind(change_ratio)
+        .bind(Json(item))
+        .fetch_one(&mut *conn)
+        .await?;
+
+        change_ids.push(change_id);
+    }
+
+    Ok(change_ids)
+}
+
+/// Compares `screenshot` to the baseline screenshot of the alert with the given `alert_id`, which
+/// is the screenshot of its latest snapshot.
+///
+/// A screenshot is a change if at least the threshold of the alert of the compared pixels differ,
+/// and at least one pixel. Screenshots that aren't a change extend the latest snapshot without
+/// being stored, so it stays the baseline until a screenshot is a change. That way gradual drift
+/// adds up to a change, and screenshots that only differ by noise don't each take up space.
+///
+/// Returns `None` if the alert has no baseline yet, or if the screenshots can't be compared.
+pub async fn compare_screenshot(
+    conn: &mut PgConnection,
+    alert_id: i32,
+    screenshot: &[u8],
+) -> Result<Option<Visual>, Error> {
+    let baseline = sqlx::query_as::<_, Baseline>(
+        "SELECT alerts.screenshot_threshold, alerts.screenshot_pixel_threshold, \
+           alerts.screenshot_ignore_regions, latest.id AS snapshot_id, contents.screenshot \
+         FROM alerts \
+         JOIN LATERAL ( \
+           SELECT id, content_hash FROM snapshots WHERE alert_id = alerts.id \
+           ORDER BY id DESC LIMIT 1 \
+           FOR UPDATE \
+         ) latest ON TRUE \
+         JOIN snapshot_contents contents ON contents.content_hash = latest.content_hash \
+         WHERE alerts.id = $1",
+    )
+    .bind(alert_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let mut baseline = match baseline {
+        Some(baseline) => baseline,
+        None => return Ok(None),
+    };
+    let previous = match baseline.screenshot.take() {
+        Some(previous) => previous,
+        None => return Ok(None),
+    };
+    let current = screenshot.to_vec();
+    let options = Options {
+        pixel_threshold: baseline.screenshot_pixel_threshold,
+        ignore_regions: baseline.screenshot_ignore_regions.0,
+    };
+
+    // Comparing large screenshots takes a while, so keep it off the threads that serve requests
+    let compared =
+        task::spawn_blocking(move || screenshot::compare(&previous, &current, &options)).await;
+    let comparison = match compared {
+        Ok(Ok(comparison)) => comparison,
+        Ok(Err(err)) => {
+            warn!(alert.id = alert_id, %err, "Could not compare screenshots");
+
+            return Ok(None);
+        }
+        Err(err) => {
+            warn!(alert.id = alert_id, %err, "Comparing screenshots failed");
+
+            return Ok(None);
+        }
+    };
+    let changed =
+        comparison.pixels_changed > 0 && comparison.change_ratio() >= baseline.screenshot_threshold;
+
+    Ok(Some(Visual {
+        baseline_snapshot_id: baseline.snapshot_id,
+        comparison,
+        changed,
+    }))
+}
+
+/// Records the change of the screenshot of the snapshot with the given `snapshot_id` from the
+/// baseline it was compared to in `visual`, with a diff image.
+pub async fn record_visual(
+    conn: &mut PgConnection,
+    snapshot_id: i32,
+    visual: &Visual,
+) -> Result<Vec<i32>, Error> {
+    sqlx::query_scalar(
+        "INSERT INTO changes (alert_id, snapshot_id, previous_snapshot_id, change_ratio, \
+                              lines_added, lines_removed, unified_diff, segments, diff_image) \
+         SELECT alert_id, id, $2, $3, 0, 0, '', '[]', $4 FROM snapshots WHERE id = $1 \
+         RETURNING id",
+    )
+    .bind(snapshot_id)
+    .bind(visual.baseline_snapshot_id)
+    .bind(visual.comparison.change_ratio())
+    .bind(&visual.comparison.diff_image)
+    .fetch_one(conn)
+    .await
+    .map(|id| vec![id])
+}
+
+/// Returns up to `limit` changes of the alert with the given `alert_id`, newest first, starting
+/// after the change with the id `before_id` if given.
+pub async fn list(
+    pool: &DbPool,
+    alert_id: i32,
+    before_id: Option<i32>,
+    limit: i64,
+) -> Result<Vec<Change>, Error> {
+    sqlx::query_as::<_, Change>(&format!(
+        "SELECT {} FROM changes \
+         WHERE alert_id = $1 AND ($2::INTEGER IS NULL OR id < $2) \
+         ORDER BY id DESC LIMIT $3",
+        CHANGE_COLUMNS
+    ))
+    .bind(alert_id)
+    .bind(before_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// Returns the change with the given `id`, if it exists.
+pub async fn find(pool: &DbPool, id: i32) -> Result<Option<Change>, Error> {
+    sqlx::query_as::<_, Change>(&format!(
+        "SELECT {} FROM changes WHERE id = $1",
+        CHANGE_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Returns the diff image of the change with the given `id`, if it exists and has one.
+pub async fn diff_image(pool: &DbPool, id: i32) -> Result<Option<Vec<u8>>, Error> {
+    sqlx::query_scalar::<_, Option<Vec<u8>>>("SELECT diff_image FROM changes WHERE id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .map(Option::flatten)
+}
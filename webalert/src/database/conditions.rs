@@ -0,0 +1,199 @@
+//! Evaluating the conditions of alerts against their snapshots.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use sqlx::{postgres::PgConnection, types::Json, Error, FromRow};
+use tracing::warn;
+
+use crate::condition::{Condition, Content};
+
+/// A value extracted by a selector, as stored with a result.
+#[derive(Debug, Clone, Deserialize)]
+struct StoredValue {
+    name: String,
+    value: String,
+}
+
+/// The condition of the alert of a snapshot, along with the content of the snapshot and the
+/// snapshot of the same alert before it.
+#[derive(Debug, Clone, FromRow)]
+struct Subject {
+    alert_id: i32,
+    condition: Option<Json<serde_json::Value>>,
+    condition_met: Option<bool>,
+    text: String,
+    values: Json<Vec<StoredValue>>,
+    previous_text: Option<String>,
+    previous_values: Option<Json<Vec<StoredValue>>>,
+}
+
+/// The outcome of evaluating the condition of an alert against a check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Evaluated {
+    /// If the condition was met, or `None` if the alert has no condition or it couldn't be
+    /// evaluated.
+    pub condition_met: Option<bool>,
+    /// If the check triggers a notification.
+    pub triggered: bool,
+}
+
+/// Returns the content that conditions see of a snapshot with the given `text` and `values`.
+fn content<'a>(text: &'a str, values: &'a [StoredValue]) -> Content<'a> {
+    Content {
+        text,
+        values: values
+            .iter()
+            .map(|value| (value.name.as_str(), value.value.as_str()))
+            .collect::<HashMap<_, _>>(),
+    }
+}
+
+/// Judges whether a check whose content is `current` triggers a notification, given the
+/// `condition` of its alert, the content of the check before it and whether the condition was
+/// met by that check.
+///
+/// A check triggers a notification if it makes the condition go from unmet to met, whether or
+/// not it `changed` the content, or if it changed the content of an alert without a condition.
+fn judge(
+    condition: Option<&serde_json::Value>,
+    current: &Content,
+    previous: Option<&Content>,
+    was_met: Option<bool>,
+    changed: bool,
+) -> Result<Evaluated, String> {
+    let condition = match condition {
+        Some(condition) => condition,
+        None => {
+            return Ok(Evaluated {
+                condition_met: None,
+                triggered: changed,
+            })
+        }
+    };
+
+    let met = serde_json::from_value::<Condition>(condition.clone())
+        .map_err(|err| err.to_string())?
+        .evaluate(current, previous)
+        .map_err(|err| err.to_string())?;
+
+    Ok(Evaluated {
+        condition_met: Some(met),
+        triggered: met && was_met != Some(true),
+    })
+}
+
+/// Evaluates the condition of the alert of the snapshot with the given `snapshot_id` against its
+/// content, for a check that captured its content in the snapshot, and remembers whether it was
+/// met.
+///
+/// The check is compared to the check before it, which saw the snapshot before this one if the
+/// check started the snapshot (`is_new`), and this snapshot if not. The changes the check
+/// detected, with the given `change_ids`, are marked with whether the check triggers a
+/// notification, see [`judge`]. No check of an alert with a condition that can't be evaluated
+/// triggers a notification.
+pub async fn evaluate(
+    conn: &mut PgConnection,
+    snapshot_id: i32,
+    is_new: bool,
+    change_ids: &[i32],
+) -> Result<Evaluated, Error> {
+    let subject = sqlx::query_as::<_, Subject>(
+        "SELECT alerts.id AS alert_id, alerts.condition, alerts.condition_met, contents.text, \
+           COALESCE(results.extracted_values, '[]') AS values, \
+           previous_contents.text AS previous_text, \
+           COALESCE(previous_results.extracted_values, '[]') AS previous_values \
+         FROM snapshots \
+         JOIN alerts ON alerts.id = snapshots.alert_id \
+         JOIN snapshot_contents contents ON contents.content_hash = snapshots.content_hash \
+         LEFT JOIN task_results results ON results.id = snapshots.first_result_id \
+         LEFT JOIN LATERAL ( \
+           SELECT content_hash, first_result_id FROM snapshots previous \
+           WHERE previous.alert_id = snapshots.alert_id AND previous.id <= snapshots.id \
+             AND (previous.id < snapshots.id OR NOT $2) \
+           ORDER BY previous.id DESC LIMIT 1 \
+         ) previous ON TRUE \
+         LEFT JOIN snapshot_contents previous_contents \
+           ON previous_contents.content_hash = previous.content_hash \
+         LEFT JOIN task_results previous_results \
+           ON previous_results.id = previous.first_result_id \
+         WHERE snapshots.id = $1 \
+         FOR UPDATE OF alerts",
+    )
+    .bind(snapshot_id)
+    .bind(is_new)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let current = content(&subject.text, &subject.values.0);
+    let previous_values = subject.previous_values.as_ref().map(|values| &values.0[..]);
+    let previous = subject
+        .previous_text
+        .as_deref()
+        .map(|text| content(text, previous_values.unwrap_or_default()));
+    let judged = judge(
+        subject.condition.as_ref().map(|condition| &condition.0),
+        &current,
+        previous.as_ref(),
+        subject.condition_met,
+        !change_ids.is_empty(),
+    );
+
+    let evaluated = judged.unwrap_or_else(|err| {
+        warn!(alert.id = subject.alert_id, %err, "Could not evaluate condition");
+
+        Evaluated {
+            condition_met: None,
+            triggered: false,
+        }
+    });
+
+    sqlx::query("UPDATE alerts SET condition_met = $2 WHERE id = $1")
+        .bind(subject.alert_id)
+        .bind(evaluated.condition_met)
+        .execute(&mut *conn)
+        .await?;
+
+    if !change_ids.is_empty() {
+        sqlx::query("UPDATE changes SET condition_met = $2, triggered = $3 WHERE id = ANY($1)")
+            .bind(change_ids)
+            .bind(evaluated.condition_met)
+            .bind(evaluated.triggered)
+            .execute(conn)
+            .await?;
+    }
+
+    Ok(evaluated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_trigger_when_unchanged_content_meets_the_condition() {
+        let unchanged = serde_json::json!({"op": "unchanged"});
+        let first = content("In stock", &[]);
+        let second = content("In stock", &[]);
+
+        // The first check has nothing to compare to
+        let evaluated = judge(Some(&unchanged), &first, None, None, true).unwrap();
+        assert_eq!(evaluated.condition_met, Some(false));
+        assert!(!evaluated.triggered);
+
+        // The next check sees the same content, so it detects no change but meets the condition
+        let evaluated = judge(Some(&unchanged), &second, Some(&first), Some(false), false).unwrap();
+        assert_eq!(evaluated.condition_met, Some(true));
+        assert!(evaluated.triggered);
+
+        // Checks after it keep meeting the condition without triggering again
+        let evaluated = judge(Some(&unchanged), &second, Some(&second), Some(true), false).unwrap();
+        assert_eq!(evaluated.condition_met, Some(true));
+        assert!(!evaluated.triggered);
+
+        // Without a condition, only changes trigger
+        let unconditional = |changed| judge(None, &second, Some(&first), None, changed).unwrap();
+        assert!(!unconditional(false).triggered);
+        assert!(unconditional(true).triggered);
+    }
+}
@@ -0,0 +1,61 @@
+//! The profiles alerts choose to normalize the content of their pages with.
+
+use sqlx::{postgres::PgConnection, Error, FromRow};
+
+use crate::normalize::Pipeline;
+
+/// A row in the `normalization_profiles` table.
+#[derive(Debug, Clone, FromRow)]
+pub struct NormalizationProfile {
+    /// The unique id of the profile.
+    pub id: i32,
+    /// The unique name of the profile.
+    pub name: String,
+    /// If runs of whitespace are collapsed.
+    pub collapse_whitespace: bool,
+    /// If `<script>` elements are removed.
+    pub strip_scripts: bool,
+    /// If `<style>` elements are removed.
+    pub strip_styles: bool,
+    /// If comments are removed.
+    pub strip_comments: bool,
+    /// If the attributes of elements are sorted by name.
+    pub sort_attributes: bool,
+    /// The names of attributes to remove.
+    pub dropped_attributes: Vec<String>,
+    /// The names of query parameters to remove from URLs.
+    pub dropped_query_params: Vec<String>,
+}
+
+impl From<NormalizationProfile> for Pipeline {
+    fn from(profile: NormalizationProfile) -> Self {
+        Pipeline {
+            collapse_whitespace: profile.collapse_whitespace,
+            strip_scripts: profile.strip_scripts,
+            strip_styles: profile.strip_styles,
+            strip_comments: profile.strip_comments,
+            sort_attributes: profile.sort_attributes,
+            dropped_attributes: profile.dropped_attributes,
+            dropped_query_params: profile.dropped_query_params,
+        }
+    }
+}
+
+/// Returns the normalization profile chosen by the alert with the given `alert_id`, if it has
+/// chosen one.
+pub async fn for_alert(
+    conn: &mut PgConnection,
+    alert_id: i32,
+) -> Result<Option<NormalizationProfile>, Error> {
+    sqlx::query_as::<_, NormalizationProfile>(
+        "SELECT profiles.id, profiles.name, profiles.collapse_whitespace, profiles.strip_scripts, \
+           profiles.strip_styles, profiles.strip_comments, profiles.sort_attributes, \
+           profiles.dropped_attributes, profiles.dropped_query_params \
+         FROM alerts \
+         JOIN normalization_profiles profiles ON profiles.id = alerts.normalization_profile_id \
+         WHERE alerts.id = $1",
+    )
+    .bind(alert_id)
+    .fetch_optional(conn)
+    .await
+}
@@ -0,0 +1,269 @@
+//! The results runners report for the tasks they run.
+
+use serde::Serialize;
+use sqlx::{postgres::PgConnection, types::Json, Error, FromRow};
+
+use super::snapshots::{self, Captured, Page};
+use super::DbPool;
+use super::{alerts, changes, conditions, normalization_profiles};
+use crate::content::Filter;
+use crate::ignore;
+use crate::normalize::Pipeline;
+
+/// The error code of results whose content can't be filtered because the ignore rules of their
+/// alert are invalid.
+const INVALID_IGNORE_RULES: &str = "invalid_ignore_rules";
+
+/// A result reported by a runner that hasn't been stored yet.
+#[derive(Debug, Clone)]
+pub struct NewResult<'a> {
+    /// The URL of the page after following redirects.
+    pub final_url: &'a str,
+    /// The HTTP status of the page, if known.
+    pub http_status: Option<i32>,
+    /// The values extracted by the selectors of the alert, as text.
+    pub text: &'a str,
+    /// The HTML of the elements matched by the selectors of the alert.
+    pub html: &'a str,
+    /// The hex-encoded SHA-256 hash of `html`, or of `screenshot` if there is one, as reported by
+    /// the runner.
+    pub content_hash: &'a str,
+    /// The PNG screenshot of the page, if the alert is a screenshot alert.
+    pub screenshot: Option<&'a [u8]>,
+    /// The number of milliseconds checking the page took.
+    pub total_ms: Option<i64>,
+    /// The number of milliseconds spent loading the page.
+    pub navigation_ms: Option<i64>,
+    /// The number of milliseconds spent extracting the content of the element.
+    pub extraction_ms: Option<i64>,
+    /// The value extracted by each selector of the task.
+    pub values: Vec<ExtractedValue<'a>>,
+    /// Why checking the page failed, if it did.
+    pub error: Option<ResultError<'a>>,
+}
+
+/// The value a selector of a task extracted from the page.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtractedValue<'a> {
+    /// The name of the selector.
+    pub name: &'a str,
+    /// The extracted value.
+    pub value: &'a str,
+}
+
+/// Why checking the page of a task failed.
+#[derive(Debug, Clone)]
+pub struct ResultError<'a> {
+    /// The kind of failure, e.g. `navigation_failed`.
+    pub code: &'a str,
+    /// A description of the failure.
+    pub message: &'a str,
+    /// If checking the page again might succeed.
+    pub retryable: bool,
+}
+
+/// A result that has been stored, along with the new state of its task.
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    /// The unique id of the result.
+    pub id: i32,
+    /// The state of the task after the result was recorded.
+    pub task_state: String,
+    /// The snapshot the content of the page was captured in, if the check succeeded.
+    pub snapshot: Option<Captured>,
+    /// The ids of the changes the check detected, if it saw different text than the check before,
+    /// or new items in a feed.
+    pub change_ids: Vec<i32>,
+    /// If the check triggers a notification, because it detected changes of an alert without a
+    /// condition or it made the condition of the alert go from unmet to met.
+    pub triggered: bool,
+}
+
+/// The row returned when inserting a result.
+#[derive(Debug, Clone, FromRow)]
+struct Inserted {
+    id: i32,
+    alert_id: i32,
+    task_state: String,
+}
+
+/// Stores `result` for the task with the given `id`, if it's leased by the runner with the given
+/// `runner_id`, and finishes the task.
+///
+/// The task succeeds if the result has no error, in which case the content of the page is
+/// normalized with the profile of the alert, filtered with its ignore rules and captured in a
+/// snapshot of the alert, or compared to the baseline screenshot of the alert if it's a
+/// screenshot. If it starts a new snapshot, a change is detected, and either way the condition of
+/// the alert is evaluated. Otherwise the task is queued again if the error is
+/// retryable and the task has been attempted fewer than `max_attempts` times, and fails if not.
+///
+/// If the ignore rules of the alert are invalid, the result is stored with an
+/// `invalid_ignore_rules` error instead and the task fails, since checking the page again won't
+/// help.
+///
+/// Returns `Ok(None)` if the runner doesn't hold a lease on the task.
+pub async fn record(
+    pool: &DbPool,
+    task_id: i32,
+    runner_id: i32,
+    result: &NewResult<'_>,
+    max_attempts: i32,
+) -> Result<Option<Recorded>, Error> {
+    let mut tx = pool.begin().await?;
+    let rules_error;
+    let (filter, error) = match &result.error {
+        Some(error) => (None, Some(error.clone())),
+        None => match content_filter(&mut tx, task_id).await? {
+            Ok(filter) => (Some(filter), None),
+            Err(err) => {
+                rules_error = format!("invalid ignore rules: {}", err);
+
+                let error = ResultError {
+                    code: INVALID_IGNORE_RULES,
+                    message: &rules_error,
+                    retryable: false,
+                };
+
+                (None, Some(error))
+            }
+        },
+    };
+    let error = error.as_ref();
+
+    let inserted = sqlx::query_as::<_, Inserted>(
+        "WITH outcome AS ( \
+           SELECT id, attempts, CASE \
+             WHEN $4::TEXT IS NULL THEN 'succeeded' \
+             WHEN $6 AND attempts < $3 THEN 'queued' \
+             ELSE 'failed' \
+           END AS state \
+           FROM tasks WHERE id = $1 AND runner_id = $2 AND state = 'leased' \
+           FOR UPDATE \
+         ), finished AS ( \
+           UPDATE tasks SET state = outcome.state, \
+             finished_at = CASE WHEN outcome.state = 'queued' THEN NULL ELSE NOW() END, \
+             runner_id = CASE WHEN outcome.state = 'queued' THEN NULL ELSE tasks.runner_id END, \
+             lease_expires_at = NULL, last_error = $5, updated_at = NOW() \
+           FROM outcome WHERE tasks.id = outcome.id \
+           RETURNING tasks.id, tasks.alert_id, outcome.attempts, outcome.state \
+         ) \
+         INSERT INTO task_results \
+           (task_id, attempt, runner_id, final_url, http_status, content_hash, total_ms, \
+            navigation_ms, extraction_ms, extracted_values, error_code, error_message) \
+         SELECT finished.id, finished.attempts, $2, $7, $8, $9, $10, $11, $12, $13, $4, $5 \
+         FROM finished \
+         RETURNING id, (SELECT alert_id FROM finished) AS alert_id, \
+           (SELECT state FROM finished) AS task_state",
+    )
+    .bind(task_id)
+    .bind(runner_id)
+    .bind(max_attempts)
+    .bind(error.map(|error| error.code))
+    .bind(error.map(|error| error.message))
+    .bind(error.is_some_and(|error| error.retryable))
+    .bind(result.final_url)
+    .bind(result.http_status)
+    .bind(result.content_hash)
+    .bind(result.total_ms)
+    .bind(result.navigation_ms)
+    .bind(result.extraction_ms)
+    .bind(Json(&result.values))
+    .fetch_optional(&mut tx)
+    .await?;
+
+    let inserted = match inserted {
+        Some(inserted) => inserted,
+        None => return Ok(None),
+    };
+
+    let mut visual = None;
+    let snapshot = if let Some(filter) = filter {
+        let prepared = filter.apply(result.text, result.html);
+        let page = Page {
+            final_url: result.final_url,
+            http_status: result.http_status,
+            total_ms: result.total_ms,
+            text: &prepared.text,
+            html: &prepared.html,
+            screenshot: result.screenshot,
+            ignores_lines: filter.ignores_lines(),
+        };
+
+        if let Some(screenshot) = result.screenshot {
+            visual = changes::compare_screenshot(&mut tx, inserted.alert_id, screenshot).await?;
+        }
+
+        // Screenshots that look the same as the baseline are captured in its snapshot
+        let captured = match &visual {
+            Some(visual) if !visual.changed => {
+                snapshots::extend_latest(&mut tx, inserted.alert_id, inserted.id).await?
+            }
+            _ => snapshots::capture(&mut tx, inserted.alert_id, inserted.id, &page).await?,
+        };
+
+        Some(captured)
+    } else {
+        None
+    };
+
+    let (change_ids, triggered) = match snapshot {
+        Some(Captured { id, is_new }) => {
+            let change_ids = match &visual {
+                Some(visual) if is_new && visual.changed => {
+                    changes::record_visual(&mut tx, id, visual).await?
+                }
+                _ if is_new => changes::detect(&mut tx, id).await?,
+                _ => Vec::new(),
+            };
+            let evaluated = conditions::evaluate(&mut tx, id, is_new, &change_ids).await?;
+
+            (change_ids, evaluated.triggered)
+        }
+        None => (Vec::new(), false),
+    };
+
+    tx.commit().await?;
+
+    Ok(Some(Recorded {
+        id: inserted.id,
+        task_state: inserted.task_state,
+        snapshot,
+        change_ids,
+        triggered,
+    }))
+}
+
+/// Returns the filter the content of the page checked by the task with the given `task_id` is
+/// prepared with, or the error in the ignore rules of its alert.
+async fn content_filter(
+    conn: &mut PgConnection,
+    task_id: i32,
+) -> Result<Result<Filter, ignore::Error>, Error> {
+    let alert_id: Option<i32> = sqlx::query_scalar("SELECT alert_id FROM tasks WHERE id = $1")
+        .bind(task_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+    let alert_id = match alert_id {
+        Some(alert_id) => alert_id,
+        None => return Ok(Ok(Filter::default())),
+    };
+
+    let rules = match alerts::ignore_rules(conn, alert_id).await? {
+        Some(rules) => match rules.compile() {
+            Ok(rules) => rules,
+            Err(err) => return Ok(Err(err)),
+        },
+        None => Default::default(),
+    };
+    let pipeline = normalization_profiles::for_alert(conn, alert_id)
+        .await?
+        .map(Pipeline::from)
+        .unwrap_or_default();
+    let format = alerts::format(conn, alert_id).await?.unwrap_or_default();
+
+    Ok(Ok(Filter {
+        format,
+        pipeline,
+        rules,
+    }))
+}
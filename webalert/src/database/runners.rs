@@ -0,0 +1,211 @@
+//! Runners that have announced themselves to the server.
+
+use chrono::{DateTime, Utc};
+use sqlx::{Error, FromRow};
+
+use super::DbPool;
+
+/// The columns selected when querying for a [`Runner`].
+const RUNNER_COLUMNS: &str = "id, name, hostname, os, arch, version, token_id, session_id, \
+                              load, active_tasks, uptime_secs, created_at, updated_at, \
+                              last_seen_at, dead_at";
+
+/// A row in the `runners` table.
+#[derive(Debug, Clone, FromRow)]
+pub struct Runner {
+    /// The unique id of the runner.
+    pub id: i32,
+    /// The human-readable name of the runner.
+    pub name: String,
+    /// The hostname of the system the runner is running on.
+    pub hostname: String,
+    /// The operating system of the system the runner is running on.
+    pub os: String,
+    /// The architecture of the system the runner is running on.
+    pub arch: String,
+    /// The version of the runner software.
+    pub version: String,
+    /// The id of the token the runner announced itself with.
+    pub token_id: Option<i32>,
+    /// The id of the current session of the runner.
+    pub session_id: Option<String>,
+    /// The system load reported in the latest heartbeat.
+    pub load: Option<f64>,
+    /// The number of tasks the runner was running in the latest heartbeat.
+    pub active_tasks: Option<i32>,
+    /// The number of seconds the runner had been running in the latest heartbeat.
+    pub uptime_secs: Option<i64>,
+    /// The first time the runner announced itself.
+    pub created_at: Option<DateTime<Utc>>,
+    /// The last time the row was updated.
+    pub updated_at: Option<DateTime<Utc>>,
+    /// The last time the runner was seen alive.
+    pub last_seen_at: Option<DateTime<Utc>>,
+    /// The time the runner was declared dead, if it's dead.
+    pub dead_at: Option<DateTime<Utc>>,
+}
+
+impl Runner {
+    /// Returns true if the runner has been declared dead, or if it hasn't been seen since
+    /// `seen_since`.
+    pub fn is_dead(&self, seen_since: DateTime<Utc>) -> bool {
+        self.dead_at.is_some() || !matches!(self.last_seen_at, Some(seen) if seen >= seen_since)
+    }
+}
+
+/// The status a runner reports in a heartbeat.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    /// The system load of the runner.
+    pub load: f64,
+    /// The number of tasks the runner is running.
+    pub active_tasks: i32,
+    /// The number of seconds the runner has been running.
+    pub uptime_secs: i64,
+}
+
+/// The details a runner sends when it announces itself.
+#[derive(Debug, Clone)]
+pub struct Announcement<'a> {
+    /// The hostname of the system the runner is running on.
+    pub hostname: &'a str,
+    /// The operating system of the system the runner is running on.
+    pub os: &'a str,
+    /// The architecture of the system the runner is running on.
+    pub arch: &'a str,
+    /// The version of the runner software.
+    pub version: &'a str,
+    /// The session the runner is resuming, if any.
+    pub resumed_session_id: Option<&'a str>,
+}
+
+/// Creates or updates the runner that announced itself with the token `token_id`, and starts a
+/// new session with the id `session_id`, unless the runner resumes its current session.
+///
+/// Runners are identified by their hostname and token, so a runner that announces itself again
+/// keeps its id.
+pub async fn announce(
+    pool: &DbPool,
+    token_id: i32,
+    session_id: &str,
+    announcement: Announcement<'_>,
+) -> Result<Runner, Error> {
+    sqlx::query_as::<_, Runner>(&format!(
+        "INSERT INTO runners \
+           (name, hostname, os, arch, version, token_id, session_id, last_seen_at) \
+         VALUES ($1, $1, $2, $3, $4, $5, $6, NOW()) \
+         ON CONFLICT (token_id, hostname) DO UPDATE SET \
+           os = EXCLUDED.os, arch = EXCLUDED.arch, version = EXCLUDED.version, \
+           session_id = CASE WHEN runners.session_id = $7 THEN runners.session_id \
+             ELSE EXCLUDED.session_id END, \
+           dead_at = NULL, updated_at = NOW(), \
+           last_seen_at = NOW() \
+         RETURNING {}",
+        RUNNER_COLUMNS
+    ))
+    .bind(announcement.hostname)
+    .bind(announcement.os)
+    .bind(announcement.arch)
+    .bind(announcement.version)
+    .bind(token_id)
+    .bind(session_id)
+    .bind(announcement.resumed_session_id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Returns the runner with the given `id`, or `None` if there's no such runner.
+pub async fn find(pool: &DbPool, id: i32) -> Result<Option<Runner>, Error> {
+    sqlx::query_as::<_, Runner>(&format!(
+        "SELECT {} FROM runners WHERE id = $1",
+        RUNNER_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Records a heartbeat from the runner with the given `id` and marks it as seen.
+pub async fn heartbeat(pool: &DbPool, id: i32, heartbeat: &Heartbeat) -> Result<(), Error> {
+    sqlx::query(
+        "UPDATE runners SET load = $2, active_tasks = $3, uptime_secs = $4, \
+           last_seen_at = NOW(), updated_at = NOW() \
+         WHERE id = $1",
+    )
+    .bind(id)
+    .bind(heartbeat.load)
+    .bind(heartbeat.active_tasks)
+    .bind(heartbeat.uptime_secs)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Declares the runner with the given `id` dead and ends its session, because it's shutting down.
+pub async fn deregister(pool: &DbPool, id: i32) -> Result<(), Error> {
+    sqlx::query(
+        "UPDATE runners SET dead_at = NOW(), session_id = NULL, updated_at = NOW() \
+         WHERE id = $1",
+    )
+    .bind(id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Declares every runner that hasn't been seen since `seen_since` dead and ends their sessions.
+///
+/// Returns the ids of the runners that were declared dead.
+pub async fn reap(pool: &DbPool, seen_since: DateTime<Utc>) -> Result<Vec<i32>, Error> {
+    sqlx::query_scalar(
+        "UPDATE runners SET dead_at = NOW(), session_id = NULL, updated_at = NOW() \
+         WHERE dead_at IS NULL AND (last_seen_at IS NULL OR last_seen_at < $1) \
+         RETURNING id",
+    )
+    .bind(seen_since)
+    .fetch_all(pool)
+    .await
+}
+
+/// Filters for [`list`].
+#[derive(Debug, Clone, Default)]
+pub struct ListFilter<'a> {
+    /// Only return runners with an id greater than this.
+    pub after_id: Option<i32>,
+    /// Only return runners that are alive and have been seen since this time.
+    pub seen_since: Option<DateTime<Utc>>,
+    /// Only return runners with this hostname.
+    pub hostname: Option<&'a str>,
+    /// Only return runners with this operating system.
+    pub os: Option<&'a str>,
+    /// Only return runners with this architecture.
+    pub arch: Option<&'a str>,
+}
+
+/// Returns up to `limit` runners that match `filter`, ordered by their id.
+pub async fn list(
+    pool: &DbPool,
+    filter: &ListFilter<'_>,
+    limit: i64,
+) -> Result<Vec<Runner>, Error> {
+    sqlx::query_as::<_, Runner>(&format!(
+        "SELECT {} FROM runners \
+         WHERE ($1::INTEGER IS NULL OR id > $1) \
+           AND ($2::TIMESTAMPTZ IS NULL OR (dead_at IS NULL AND last_seen_at >= $2)) \
+           AND ($3::TEXT IS NULL OR hostname = $3) \
+           AND ($4::TEXT IS NULL OR os = $4) \
+           AND ($5::TEXT IS NULL OR arch = $5) \
+         ORDER BY id LIMIT $6",
+        RUNNER_COLUMNS
+    ))
+    .bind(filter.after_id)
+    .bind(filter.seen_since)
+    .bind(filter.hostname)
+    .bind(filter.os)
+    .bind(filter.arch)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
@@ -0,0 +1,49 @@
+//! What alerts extract from their pages.
+//!
+//! The selectors of an alert are stored in `alert_selectors`, and copied to every task of the
+//! alert when it's scheduled.
+
+use serde::{Deserialize, Serialize};
+
+/// The language a selector is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// A CSS selector.
+    Css,
+    /// An XPath expression.
+    Xpath,
+    /// A JavaScript expression that evaluates to an element, a list of elements or a value.
+    Javascript,
+    /// A JSONPath expression that selects values of a JSON document.
+    Jsonpath,
+}
+
+/// What is extracted from the elements matching a selector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Extraction {
+    /// The rendered text of the first matching element.
+    Text,
+    /// The outer HTML of the first matching element.
+    Html,
+    /// The value of an attribute of the first matching element.
+    Attribute,
+    /// The number of matching elements.
+    Count,
+}
+
+/// Something to extract from the page of an alert.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Selector {
+    /// The name of the selector, unique within the alert.
+    pub name: String,
+    /// The language the selector is written in.
+    pub kind: Kind,
+    /// The selector itself.
+    pub expression: String,
+    /// What is extracted from the matching elements.
+    pub extraction: Extraction,
+    /// The name of the attribute to extract, if `extraction` is [`Extraction::Attribute`].
+    pub attribute: Option<String>,
+}
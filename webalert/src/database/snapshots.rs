@@ -0,0 +1,306 @@
+//! What the pages of alerts looked like over time.
+//!
+//! Every successful check of an alert either starts a new snapshot, if the content it saw differs
+//! from the latest snapshot of the alert, or extends the latest snapshot. The content itself is
+//! stored once per distinct hash in `snapshot_contents`, so checks that don't see anything new
+//! only cost an update of the latest snapshot.
+//!
+//! Content is normalized with the profile of the alert and filtered with its ignore rules before
+//! it's captured, and hashed as it's stored: the text, the HTML and the screenshot together. Since
+//! line filters only apply to text, alerts with line filters tell content apart by its text alone.
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgConnection, Error, FromRow};
+
+use super::DbPool;
+
+/// The columns selected when querying for a [`Snapshot`].
+const SNAPSHOT_COLUMNS: &str = "id, alert_id, content_hash, final_url, http_status, total_ms, \
+                                checks, first_result_id, last_result_id, first_seen_at, \
+                                last_seen_at";
+
+/// A row in the `snapshots` table.
+#[derive(Debug, Clone, FromRow)]
+pub struct Snapshot {
+    /// The unique id of the snapshot.
+    pub id: i32,
+    /// The id of the alert whose page the snapshot is of.
+    pub alert_id: i32,
+    /// The hex-encoded SHA-256 hash of the content of the snapshot, see [`content_hash`].
+    pub content_hash: String,
+    /// The URL of the page after following redirects, as of the first check that saw the content.
+    pub final_url: String,
+    /// The HTTP status of the page as of the first check that saw the content, if known.
+    pub http_status: Option<i32>,
+    /// The number of milliseconds the first check that saw the content took.
+    pub total_ms: Option<i64>,
+    /// The number of consecutive checks that saw the content.
+    pub checks: i32,
+    /// The id of the first result that saw the content, unless it has been deleted.
+    pub first_result_id: Option<i32>,
+    /// The id of the latest result that saw the content, unless it has been deleted.
+    pub last_result_id: Option<i32>,
+    /// The time the content was first seen.
+    pub first_seen_at: DateTime<Utc>,
+    /// The last time the content was seen.
+    pub last_seen_at: DateTime<Utc>,
+}
+
+/// The content of a snapshot.
+#[derive(Debug, Clone, FromRow)]
+pub struct Content {
+    /// The values extracted by the selectors of the alert, as text.
+    pub text: String,
+    /// The HTML of the elements matched by the selectors of the alert.
+    pub html: String,
+    /// The PNG screenshot of the page, if the alert is a screenshot alert.
+    pub screenshot: Option<Vec<u8>>,
+}
+
+/// What a successful check saw.
+#[derive(Debug, Clone)]
+pub struct Page<'a> {
+    /// The URL of the page after following redirects.
+    pub final_url: &'a str,
+    /// The HTTP status of the page, if known.
+    pub http_status: Option<i32>,
+    /// The number of milliseconds checking the page took.
+    pub total_ms: Option<i64>,
+    /// The values extracted by the selectors of the alert, as text.
+    pub text: &'a str,
+    /// The HTML of the elements matched by the selectors of the alert.
+    pub html: &'a str,
+    /// The PNG screenshot of the page, if the alert is a screenshot alert.
+    pub screenshot: Option<&'a [u8]>,
+    /// True if the check saw the same content as the latest snapshot when it saw the same text,
+    /// whatever its HTML, because the alert ignores lines of text.
+    pub ignores_lines: bool,
+}
+
+/// The snapshot a check was captured in.
+#[derive(Debug, Clone, FromRow)]
+pub struct Captured {
+    /// The id of the snapshot.
+    pub id: i32,
+    /// True if the check saw new content and started the snapshot, false if it extended the
+    /// latest snapshot of the alert.
+    pub is_new: bool,
+}
+
+/// Returns the hex-encoded SHA-256 hash that the `text`, `html` and `screenshot` of a page are
+/// stored under.
+///
+/// Each part is hashed after its length, so that content can't be shifted from one part to the
+/// next without changing the hash.
+pub fn content_hash(text: &str, html: &str, screenshot: Option<&[u8]>) -> String {
+    let mut hasher = Sha256::new();
+
+    for part in [
+        text.as_bytes(),
+        html.as_bytes(),
+        screenshot.unwrap_or_default(),
+    ] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Captures what the check with the result with the given `result_id` saw on the page of the
+/// alert with the given `alert_id`.
+///
+/// The check extends the latest snapshot of the alert if it saw the same content, or the same text
+/// if the alert ignores lines of text, and starts a new snapshot otherwise. Content is only stored
+/// for new snapshots.
+///
+/// Takes a connection rather than a pool so that it can be part of the transaction that records
+/// the result.
+pub async fn capture(
+    conn: &mut PgConnection,
+    alert_id: i32,
+    result_id: i32,
+    page: &Page<'_>,
+) -> Result<Captured, Error> {
+    let content_hash = content_hash(page.text, page.html, page.screenshot);
+
+    let extended = sqlx::query_as::<_, Captured>(
+        "WITH latest AS ( \
+           SELECT snapshots.id, snapshots.content_hash, contents.text FROM snapshots \
+           JOIN snapshot_contents contents ON contents.content_hash = snapshots.content_hash \
+           WHERE snapshots.alert_id = $1 \
+           ORDER BY snapshots.id DESC LIMIT 1 \
+           FOR UPDATE OF snapshots \
+         ) \
+         UPDATE snapshots SET checks = checks + 1, last_result_id = $3, last_seen_at = NOW() \
+         FROM latest WHERE snapshots.id = latest.id \
+           AND (latest.content_hash = $2 OR ($4 AND latest.text = $5)) \
+         RETURNING snapshots.id, FALSE AS is_new",
+    )
+    .bind(alert_id)
+    .bind(&content_hash)
+    .bind(result_id)
+    .bind(page.ignores_lines)
+    .bind(page.text)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    if let Some(extended) = extended {
+        return Ok(extended);
+    }
+
+    sqlx::query(
+        "INSERT INTO snapshot_contents (content_hash, text, html, screenshot) \
+         VALUES ($1, $2, $3, $4) \
+         ON CONFLICT (content_hash) DO NOTHING",
+    )
+    .bind(&content_hash)
+    .bind(page.text)
+    .bind(page.html)
+    .bind(page.screenshot)
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query_as::<_, Captured>(
+        "INSERT INTO snapshots (alert_id, content_hash, final_url, http_status, total_ms, \
+                                first_result_id, last_result_id) \
+         VALUES ($1, $2, $4, $5, $6, $3, $3) \
+         RETURNING id, TRUE AS is_new",
+    )
+    .bind(alert_id)
+    .bind(&content_hash)
+    .bind(result_id)
+    .bind(page.final_url)
+    .bind(page.http_status)
+    .bind(page.total_ms)
+    .fetch_one(conn)
+    .await
+}
+
+/// Captures the check with the result with the given `result_id` in the latest snapshot of the
+/// alert with the given `alert_id`, without storing what the check saw.
+///
+/// This is for screenshots that look the same as the screenshot of the latest snapshot, so that
+/// it stays the baseline they're compared to, see [`changes::compare_screenshot`].
+///
+/// [`changes::compare_screenshot`]: super::changes::compare_screenshot
+pub async fn extend_latest(
+    conn: &mut PgConnection,
+    alert_id: i32,
+    result_id: i32,
+) -> Result<Captured, Error> {
+    sqlx::query_as::<_, Captured>(
+        "UPDATE snapshots SET checks = checks + 1, last_result_id = $2, last_seen_at = NOW() \
+         WHERE id = (SELECT MAX(id) FROM snapshots WHERE alert_id = $1) \
+         RETURNING id, FALSE AS is_new",
+    )
+    .bind(alert_id)
+    .bind(result_id)
+    .fetch_one(conn)
+    .await
+}
+
+/// Returns up to `limit` snapshots of the alert with the given `alert_id`, newest first, starting
+/// after the snapshot with the id `before_id` if given.
+pub async fn list(
+    pool: &DbPool,
+    alert_id: i32,
+    before_id: Option<i32>,
+    limit: i64,
+) -> Result<Vec<Snapshot>, Error> {
+    sqlx::query_as::<_, Snapshot>(&format!(
+        "SELECT {} FROM snapshots \
+         WHERE alert_id = $1 AND ($2::INTEGER IS NULL OR id < $2) \
+         ORDER BY id DESC LIMIT $3",
+        SNAPSHOT_COLUMNS
+    ))
+    .bind(alert_id)
+    .bind(before_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// Returns the snapshot with the given `id`, if it exists.
+pub async fn find(pool: &DbPool, id: i32) -> Result<Option<Snapshot>, Error> {
+    sqlx::query_as::<_, Snapshot>(&format!(
+        "SELECT {} FROM snapshots WHERE id = $1",
+        SNAPSHOT_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Returns the content stored under `content_hash`, if any.
+pub async fn content(pool: &DbPool, content_hash: &str) -> Result<Option<Content>, Error> {
+    sqlx::query_as::<_, Content>(
+        "SELECT text, html, screenshot FROM snapshot_contents WHERE content_hash = $1",
+    )
+    .bind(content_hash)
+    .fetch_optional(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::content::{Filter, Format};
+    use crate::ignore::Rules;
+
+    /// Returns the filter of an HTML alert that removes the lines of text matched by `line`.
+    fn filter(line: &str) -> Filter {
+        Filter {
+            format: Format::Html,
+            rules: Rules::new(&[], &[], &[line.to_string()]).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_should_hash_what_is_stored() {
+        let hash = content_hash("Hello", "<h1>Hello</h1>", None);
+
+        assert_eq!(hash.len(), 64);
+        assert_ne!(hash, content_hash("Hi", "<h1>Hello</h1>", None));
+        assert_ne!(hash, content_hash("Hello", "<h1>Hello</h1>", Some(b"png")));
+        assert_ne!(content_hash("ab", "c", None), content_hash("a", "bc", None));
+    }
+
+    #[test]
+    fn it_should_keep_the_content_of_alerts_with_different_line_filters_apart() {
+        let html = "<p>Price: 10</p><p>Updated 10:00</p><p>Views: 3</p>";
+        let text = "Price: 10\nUpdated 10:00\nViews: 3";
+        let updates = filter("^Updated");
+        let views = filter("^Views");
+
+        let first = updates.apply(text, html);
+        let second = views.apply(text, html);
+
+        // The same page is stored apart for each alert, with the text each of them sees
+        assert_eq!(first.text, "Price: 10\nViews: 3");
+        assert_eq!(second.text, "Price: 10\nUpdated 10:00");
+        assert_ne!(
+            content_hash(&first.text, &first.html, None),
+            content_hash(&second.text, &second.html, None)
+        );
+
+        // A page that only changed in an ignored line has the same text, which is what tells
+        // the content of alerts with line filters apart
+        let later = updates.apply(
+            "Price: 10\nUpdated 11:00\nViews: 3",
+            "<p>Price: 10</p><p>Updated 11:00</p><p>Views: 3</p>",
+        );
+
+        assert!(updates.ignores_lines());
+        assert_eq!(later.text, first.text);
+        assert_ne!(later.html, first.html);
+        assert!(!Filter::default().ignores_lines());
+    }
+}
@@ -0,0 +1,276 @@
+//! A durable queue of alert checks that are handed out to runners.
+//!
+//! Tasks start out `queued`. A runner takes a task by leasing it, which moves it to `leased` until
+//! the lease expires. The runner then either acknowledges the task, which moves it to `succeeded`,
+//! or rejects it, which queues it again. Tasks whose leases expire, or whose runners go away, are
+//! queued again too, until they have been attempted too many times and are moved to `failed`.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use sqlx::postgres::PgListener;
+use sqlx::types::Json;
+use sqlx::{Error, FromRow};
+
+use super::selectors::Selector;
+use super::DbPool;
+
+/// The channel that is notified whenever a task is queued or a lease on a task ends.
+pub const QUEUE_CHANNEL: &str = "task_queue";
+
+/// The columns selected when querying for a [`Task`].
+const TASK_COLUMNS: &str = "id, alert_id, url, fetch_mode, format, selectors, due_at, state, \
+                            attempts, runner_id, leased_at, lease_expires_at, finished_at, \
+                            last_error, created_at, updated_at";
+
+/// The assignments that hand a leased task back to the queue, or fail it if it has run out of
+/// attempts. Binds the maximum number of attempts to `$1` and the reason to `$2`.
+const REQUEUE_ASSIGNMENTS: &str =
+    "state = CASE WHEN attempts >= $1 THEN 'failed' ELSE 'queued' END, \
+     finished_at = CASE WHEN attempts >= $1 THEN NOW() END, \
+     runner_id = NULL, lease_expires_at = NULL, last_error = $2, updated_at = NOW()";
+
+/// A row in the `tasks` table.
+#[derive(Debug, Clone, FromRow)]
+pub struct Task {
+    /// The unique id of the task.
+    pub id: i32,
+    /// The id of the alert to check.
+    pub alert_id: i32,
+    /// The URL of the page to check.
+    pub url: String,
+    /// Either `browser` or `http`, as of when the task was scheduled.
+    pub fetch_mode: String,
+    /// Either `html`, `json`, `feed` or `screenshot`, as of when the task was scheduled.
+    pub format: String,
+    /// What to extract from the page, as of when the task was scheduled.
+    pub selectors: Json<Vec<Selector>>,
+    /// The time the check was due.
+    pub due_at: DateTime<Utc>,
+    /// Either `queued`, `leased`, `succeeded` or `failed`.
+    pub state: String,
+    /// The number of times the task has been leased.
+    pub attempts: i32,
+    /// The id of the runner that holds the lease on the task, if any.
+    pub runner_id: Option<i32>,
+    /// The last time the task was leased, if ever.
+    pub leased_at: Option<DateTime<Utc>>,
+    /// The time the current lease expires, if the task is leased.
+    pub lease_expires_at: Option<DateTime<Utc>>,
+    /// The time the task succeeded or failed, if it has.
+    pub finished_at: Option<DateTime<Utc>>,
+    /// The reason the latest attempt failed, if it did.
+    pub last_error: Option<String>,
+    /// The time the task was created.
+    pub created_at: Option<DateTime<Utc>>,
+    /// The last time the task was updated.
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl Task {
+    /// Returns true if the task has run out of attempts.
+    pub fn is_failed(&self) -> bool {
+        self.state == "failed"
+    }
+}
+
+/// Returns a listener on its own connection that receives a notification on [`QUEUE_CHANNEL`]
+/// whenever a task is queued or a lease on a task ends.
+pub async fn listen(pool: &DbPool) -> Result<PgListener, Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(QUEUE_CHANNEL).await?;
+
+    Ok(listener)
+}
+
+/// Enqueues a task for up to `limit` alerts whose next check is due, and moves their next check to
+/// the first time after now that is a whole number of check intervals later.
+///
+/// Alerts that still have a task queued or leased are skipped, so checks don't pile up while no
+/// runners are polling, and so are alerts without selectors other than feed and screenshot alerts,
+/// as there's nothing to check. Tasks get a copy of the selectors, the fetch mode and the format
+/// of their alert. Enqueueing a task and moving the next check happen in a single statement, and
+/// tasks are unique per alert and due time, so a check is never scheduled twice nor lost if the
+/// server stops halfway.
+///
+/// Returns the ids of the tasks that were enqueued.
+pub async fn enqueue_due(pool: &DbPool, limit: i64) -> Result<Vec<i32>, Error> {
+    sqlx::query_scalar(
+        "WITH due AS ( \
+           SELECT id, url, fetch_mode, format, next_check_at, check_interval_secs FROM alerts \
+           WHERE next_check_at <= NOW() \
+             AND (format IN ('feed', 'screenshot') \
+               OR EXISTS (SELECT 1 FROM alert_selectors WHERE alert_id = alerts.id)) \
+             AND NOT EXISTS ( \
+               SELECT 1 FROM tasks \
+               WHERE tasks.alert_id = alerts.id AND tasks.state IN ('queued', 'leased') \
+             ) \
+           ORDER BY next_check_at LIMIT $1 \
+           FOR UPDATE SKIP LOCKED \
+         ), scheduled AS ( \
+           UPDATE alerts SET next_check_at = due.next_check_at + make_interval( \
+             secs => due.check_interval_secs * (FLOOR( \
+               EXTRACT(EPOCH FROM NOW() - due.next_check_at) / due.check_interval_secs \
+             ) + 1) \
+           ) \
+           FROM due WHERE alerts.id = due.id \
+         ) \
+         INSERT INTO tasks (alert_id, url, fetch_mode, format, selectors, due_at) \
+         SELECT id, url, fetch_mode, format, COALESCE(( \
+           SELECT jsonb_agg(jsonb_build_object( \
+             'name', name, 'kind', kind, 'expression', expression, 'extraction', extraction, \
+             'attribute', attribute \
+           ) ORDER BY position, id) \
+           FROM alert_selectors WHERE alert_id = due.id \
+         ), '[]'), next_check_at FROM due \
+         ON CONFLICT (alert_id, due_at) DO NOTHING \
+         RETURNING id",
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// Leases the queued task that has been due the longest to the runner with the given `runner_id`
+/// for `lease`, unless the runner already holds `max_leased` leases.
+///
+/// Returns `None` if there are no tasks queued or the runner can't take any more.
+pub async fn lease_next(
+    pool: &DbPool,
+    runner_id: i32,
+    max_leased: i64,
+    lease: Duration,
+) -> Result<Option<Task>, Error> {
+    sqlx::query_as::<_, Task>(&format!(
+        "UPDATE tasks SET state = 'leased', runner_id = $1, attempts = attempts + 1, \
+           leased_at = NOW(), lease_expires_at = NOW() + make_interval(secs => $3), \
+           updated_at = NOW() \
+         WHERE id = ( \
+           SELECT id FROM tasks WHERE state = 'queued' \
+           ORDER BY due_at LIMIT 1 \
+           FOR UPDATE SKIP LOCKED \
+         ) AND ( \
+           SELECT COUNT(*) FROM tasks WHERE runner_id = $1 AND state = 'leased' \
+         ) < $2 \
+         RETURNING {}",
+        TASK_COLUMNS
+    ))
+    .bind(runner_id)
+    .bind(max_leased)
+    .bind(lease.as_secs_f64())
+    .fetch_optional(pool)
+    .await
+}
+
+/// Marks the task with the given `id` as succeeded, if it's leased by the runner with the given
+/// `runner_id`.
+///
+/// Returns `Ok(false)` if the runner doesn't hold a lease on the task.
+pub async fn ack(pool: &DbPool, id: i32, runner_id: i32) -> Result<bool, Error> {
+    let result = sqlx::query(
+        "UPDATE tasks SET state = 'succeeded', finished_at = NOW(), lease_expires_at = NULL, \
+           updated_at = NOW() \
+         WHERE id = $1 AND runner_id = $2 AND state = 'leased'",
+    )
+    .bind(id)
+    .bind(runner_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Queues the task with the given `id` again because the runner with the given `runner_id` failed
+/// to run it, or fails it if it has been attempted `max_attempts` times.
+///
+/// Returns `Ok(None)` if the runner doesn't hold a lease on the task.
+pub async fn nack(
+    pool: &DbPool,
+    id: i32,
+    runner_id: i32,
+    reason: &str,
+    max_attempts: i32,
+) -> Result<Option<Task>, Error> {
+    sqlx::query_as::<_, Task>(&format!(
+        "UPDATE tasks SET {} WHERE id = $3 AND runner_id = $4 AND state = 'leased' RETURNING {}",
+        REQUEUE_ASSIGNMENTS, TASK_COLUMNS
+    ))
+    .bind(max_attempts)
+    .bind(reason)
+    .bind(id)
+    .bind(runner_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Extends the lease of the runner with the given `runner_id` on the task with the given `id` to
+/// `lease` from now.
+///
+/// Returns `Ok(None)` if the runner doesn't hold a lease on the task.
+pub async fn extend_lease(
+    pool: &DbPool,
+    id: i32,
+    runner_id: i32,
+    lease: Duration,
+) -> Result<Option<Task>, Error> {
+    sqlx::query_as::<_, Task>(&format!(
+        "UPDATE tasks SET lease_expires_at = NOW() + make_interval(secs => $3), \
+           updated_at = NOW() \
+         WHERE id = $1 AND runner_id = $2 AND state = 'leased' \
+         RETURNING {}",
+        TASK_COLUMNS
+    ))
+    .bind(id)
+    .bind(runner_id)
+    .bind(lease.as_secs_f64())
+    .fetch_optional(pool)
+    .await
+}
+
+/// Returns the ids of the tasks among `ids` that aren't leased by the runner with the given
+/// `runner_id`, e.g. because their leases expired and they were handed out again.
+pub async fn not_leased_by(pool: &DbPool, runner_id: i32, ids: &[i32]) -> Result<Vec<i32>, Error> {
+    sqlx::query_scalar(
+        "SELECT id FROM tasks \
+         WHERE id = ANY($2) AND (state <> 'leased' OR runner_id IS DISTINCT FROM $1)",
+    )
+    .bind(runner_id)
+    .bind(ids)
+    .fetch_all(pool)
+    .await
+}
+
+/// Queues every task whose lease has expired again, or fails it if it has been attempted
+/// `max_attempts` times.
+///
+/// Returns the ids of the tasks whose leases had expired.
+pub async fn requeue_expired(pool: &DbPool, max_attempts: i32) -> Result<Vec<i32>, Error> {
+    sqlx::query_scalar(&format!(
+        "UPDATE tasks SET {} WHERE state = 'leased' AND lease_expires_at < NOW() RETURNING id",
+        REQUEUE_ASSIGNMENTS
+    ))
+    .bind(max_attempts)
+    .bind("lease expired")
+    .fetch_all(pool)
+    .await
+}
+
+/// Queues every task leased by the runners with the given `runner_ids` again, or fails them if
+/// they have been attempted `max_attempts` times, because the runners have gone away.
+///
+/// Returns the ids of the tasks that were leased by the runners.
+pub async fn release(
+    pool: &DbPool,
+    runner_ids: &[i32],
+    max_attempts: i32,
+) -> Result<Vec<i32>, Error> {
+    sqlx::query_scalar(&format!(
+        "UPDATE tasks SET {} WHERE state = 'leased' AND runner_id = ANY($3) RETURNING id",
+        REQUEUE_ASSIGNMENTS
+    ))
+    .bind(max_attempts)
+    .bind("runner went away")
+    .bind(runner_ids)
+    .fetch_all(pool)
+    .await
+}
@@ -0,0 +1,197 @@
+//! Access tokens that authorize clients of the gRPC server.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use sqlx::{Error, FromRow};
+
+use super::DbPool;
+use crate::scope::Scope;
+
+/// The columns selected when querying for a [`Token`].
+const TOKEN_COLUMNS: &str = "id, name, prefix, salt, secret_hash, scopes, created_at, \
+                             updated_at, expires_at, revoked_at, last_used_at";
+
+/// A row in the `tokens` table.
+#[derive(Debug, Clone, FromRow)]
+pub struct Token {
+    /// The unique id of the token.
+    pub id: i32,
+    /// The human-readable name of the token.
+    pub name: String,
+    /// The public prefix of the token.
+    pub prefix: String,
+    /// The salt used when hashing the token.
+    pub salt: Vec<u8>,
+    /// The salted hash of the token.
+    pub secret_hash: Vec<u8>,
+    /// The names of the scopes granted to the token.
+    pub scopes: Vec<String>,
+    /// The time the token was created.
+    pub created_at: DateTime<Utc>,
+    /// The last time the token was updated.
+    pub updated_at: DateTime<Utc>,
+    /// The time after which the token can no longer be used, if any.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The time the token was revoked, if it has been.
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// The last time the token was used to authorize a request, if ever.
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl Token {
+    /// Returns true if the token has expired at `now`.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+
+    /// Returns true if the token has been revoked.
+    pub fn is_revoked(&self) -> bool {
+        self.revoked_at.is_some()
+    }
+}
+
+/// The hashed secret of a new or rotated token.
+#[derive(Debug, Clone)]
+pub struct NewSecret<'a> {
+    /// The public prefix of the token.
+    pub prefix: &'a str,
+    /// The salt used when hashing the token.
+    pub salt: &'a [u8],
+    /// The salted hash of the token.
+    pub secret_hash: &'a [u8],
+}
+
+/// Returns all the tokens with the public prefix `prefix`.
+pub async fn find_by_prefix(pool: &DbPool, prefix: &str) -> Result<Vec<Token>, Error> {
+    sqlx::query_as::<_, Token>(&format!(
+        "SELECT {} FROM tokens WHERE prefix = $1",
+        TOKEN_COLUMNS
+    ))
+    .bind(prefix)
+    .fetch_all(pool)
+    .await
+}
+
+/// Returns all the tokens, ordered by the time they were created.
+pub async fn list(pool: &DbPool) -> Result<Vec<Token>, Error> {
+    sqlx::query_as::<_, Token>(&format!(
+        "SELECT {} FROM tokens ORDER BY created_at",
+        TOKEN_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await
+}
+
+/// Creates a new token with the given `name`, `secret` and `scopes`, optionally expiring at
+/// `expires_at`.
+pub async fn create(
+    pool: &DbPool,
+    name: &str,
+    secret: NewSecret<'_>,
+    scopes: &[Scope],
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<Token, Error> {
+    let scopes: Vec<&str> = scopes.iter().map(Scope::as_str).collect();
+
+    sqlx::query_as::<_, Token>(&format!(
+        "INSERT INTO tokens (name, prefix, salt, secret_hash, scopes, expires_at) \
+         VALUES ($1, $2, $3, $4, $5, $6) RETURNING {}",
+        TOKEN_COLUMNS
+    ))
+    .bind(name)
+    .bind(secret.prefix)
+    .bind(secret.salt)
+    .bind(secret.secret_hash)
+    .bind(scopes)
+    .bind(expires_at)
+    .fetch_one(pool)
+    .await
+}
+
+/// Revokes the token with the given `name`.
+///
+/// Returns `Ok(false)` if there's no such token or if it's already revoked.
+pub async fn revoke(pool: &DbPool, name: &str) -> Result<bool, Error> {
+    let result = sqlx::query(
+        "UPDATE tokens SET revoked_at = NOW(), updated_at = NOW() \
+         WHERE name = $1 AND revoked_at IS NULL",
+    )
+    .bind(name)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Replaces the secret of the token with the given `name` with `secret`.
+///
+/// Returns `Ok(None)` if there's no such token or if it has been revoked.
+pub async fn rotate(
+    pool: &DbPool,
+    name: &str,
+    secret: NewSecret<'_>,
+) -> Result<Option<Token>, Error> {
+    sqlx::query_as::<_, Token>(&format!(
+        "UPDATE tokens SET prefix = $2, salt = $3, secret_hash = $4, updated_at = NOW() \
+         WHERE name = $1 AND revoked_at IS NULL RETURNING {}",
+        TOKEN_COLUMNS
+    ))
+    .bind(name)
+    .bind(secret.prefix)
+    .bind(secret.salt)
+    .bind(secret.secret_hash)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Records when tokens were last used and writes them to the database in batches, so that
+/// authorizing a request doesn't require a write.
+///
+/// The timestamps are informational, so updates that fail to be written are dropped.
+#[derive(Debug, Clone, Default)]
+pub struct LastUsedTracker {
+    pending: Arc<Mutex<HashMap<i32, DateTime<Utc>>>>,
+}
+
+impl LastUsedTracker {
+    /// Creates a new tracker with no pending updates.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the token with the given `id` was used at `time`.
+    pub fn touch(&self, id: i32, time: DateTime<Utc>) {
+        let mut pending = self.pending.lock().unwrap();
+        let last_used = pending.entry(id).or_insert(time);
+
+        if *last_used < time {
+            *last_used = time;
+        }
+    }
+
+    /// Writes all the pending updates to the database.
+    ///
+    /// Returns the number of tokens that were updated.
+    pub async fn flush(&self, pool: &DbPool) -> Result<u64, Error> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let (ids, times): (Vec<i32>, Vec<DateTime<Utc>>) = pending.into_iter().unzip();
+        let result = sqlx::query(
+            "UPDATE tokens SET last_used_at = GREATEST(tokens.last_used_at, used.time) \
+             FROM UNNEST($1::INTEGER[], $2::TIMESTAMPTZ[]) AS used(id, time) \
+             WHERE tokens.id = used.id",
+        )
+        .bind(ids)
+        .bind(times)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
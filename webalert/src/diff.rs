@@ -0,0 +1,210 @@
+//! Comparison of the content of snapshots.
+//!
+//! Content is compared at line granularity to produce a unified diff that's easy to read for
+//! humans, and at word granularity to produce structured segments and a change ratio that are
+//! precise enough to show and reason about small edits within a line.
+
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+
+/// The number of unchanged lines shown around each hunk of a unified diff.
+pub const CONTEXT_LINES: usize = 3;
+
+/// The unit content is split into before it's compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granularity {
+    /// Compare whole lines, including their line endings.
+    Line,
+    /// Compare words and the whitespace between them.
+    Word,
+}
+
+/// What happened to a segment of content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    /// The segment is in both the old and the new content.
+    Unchanged,
+    /// The segment is only in the new content.
+    Added,
+    /// The segment is only in the old content.
+    Removed,
+}
+
+/// A run of content that was added, removed or left unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub text: String,
+}
+
+/// The differences between two versions of some content.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    /// The line differences as a unified diff, or an empty string if the lines are the same.
+    pub unified_diff: String,
+    /// The content split into word segments, in order.
+    pub segments: Vec<Segment>,
+    /// The fraction of words that changed, from 0.0 when nothing changed to 1.0 when everything
+    /// did.
+    pub change_ratio: f64,
+    /// The number of lines that were added.
+    pub lines_added: usize,
+    /// The number of lines that were removed.
+    pub lines_removed: usize,
+}
+
+impl Comparison {
+    /// Returns true if any content was added or removed.
+    pub fn is_changed(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| segment.kind != SegmentKind::Unchanged)
+    }
+}
+
+impl From<ChangeTag> for SegmentKind {
+    fn from(tag: ChangeTag) -> Self {
+        match tag {
+            ChangeTag::Equal => SegmentKind::Unchanged,
+            ChangeTag::Insert => SegmentKind::Added,
+            ChangeTag::Delete => SegmentKind::Removed,
+        }
+    }
+}
+
+/// Splits `old` and `new` into units of the given `granularity` and compares them.
+fn text_diff<'a>(
+    old: &'a str,
+    new: &'a str,
+    granularity: Granularity,
+) -> TextDiff<'a, 'a, 'a, str> {
+    match granularity {
+        Granularity::Line => TextDiff::from_lines(old, new),
+        Granularity::Word => TextDiff::from_words(old, new),
+    }
+}
+
+/// Returns the segments of `old` and `new` at the given `granularity`, with adjacent segments of
+/// the same kind merged.
+pub fn segments(old: &str, new: &str, granularity: Granularity) -> Vec<Segment> {
+    let diff = text_diff(old, new, granularity);
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for change in diff.iter_all_changes() {
+        let kind = SegmentKind::from(change.tag());
+
+        match segments.last_mut() {
+            Some(last) if last.kind == kind => last.text.push_str(change.value()),
+            _ => segments.push(Segment {
+                kind,
+                text: change.value().to_string(),
+            }),
+        }
+    }
+
+    segments
+}
+
+/// Returns the fraction of units of the given `granularity` that differ between `old` and `new`.
+pub fn change_ratio(old: &str, new: &str, granularity: Granularity) -> f64 {
+    let diff = text_diff(old, new, granularity);
+    // Units that are in both versions count once for each of them
+    let (changed, total) =
+        diff.iter_all_changes()
+            .fold((0usize, 0usize), |(changed, total), change| {
+                match change.tag() {
+                    ChangeTag::Equal => (changed, total + 2),
+                    _ => (changed + 1, total + 1),
+                }
+            });
+
+    if total == 0 {
+        0.0
+    } else {
+        changed as f64 / total as f64
+    }
+}
+
+/// Returns the line differences between `old` and `new` as a unified diff with the given file
+/// names in its header, or an empty string if there are none.
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(CONTEXT_LINES)
+        .header(old_name, new_name)
+        .to_string()
+}
+
+/// Compares the `old` and `new` versions of some content.
+pub fn compare(old: &str, new: &str) -> Comparison {
+    let lines = TextDiff::from_lines(old, new);
+    let count = |tag| lines.iter_all_changes().filter(|c| c.tag() == tag).count();
+
+    Comparison {
+        unified_diff: unified(old, new, "previous", "current"),
+        segments: segments(old, new, Granularity::Word),
+        change_ratio: change_ratio(old, new, Granularity::Word),
+        lines_added: count(ChangeTag::Insert),
+        lines_removed: count(ChangeTag::Delete),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_segment_words() {
+        let segments = segments("price: 10 EUR", "price: 12 EUR", Granularity::Word);
+
+        assert_eq!(
+            segments,
+            vec![
+                Segment {
+                    kind: SegmentKind::Unchanged,
+                    text: "price: ".to_string()
+                },
+                Segment {
+                    kind: SegmentKind::Removed,
+                    text: "10".to_string()
+                },
+                Segment {
+                    kind: SegmentKind::Added,
+                    text: "12".to_string()
+                },
+                Segment {
+                    kind: SegmentKind::Unchanged,
+                    text: " EUR".to_string()
+                },
+            ]
+        );
+        // One of the five words and spaces in each version changed
+        assert_eq!(
+            change_ratio("price: 10 EUR", "price: 12 EUR", Granularity::Word),
+            0.2
+        );
+    }
+
+    #[test]
+    fn it_should_compare_lines() {
+        let comparison = compare("a\nb\nc\n", "a\nB\nc\nd\n");
+
+        assert!(comparison.is_changed());
+        assert_eq!(comparison.lines_added, 2);
+        assert_eq!(comparison.lines_removed, 1);
+        assert_eq!(
+            comparison.unified_diff,
+            "--- previous\n+++ current\n@@ -1,3 +1,4 @@\n a\n-b\n+B\n c\n+d\n"
+        );
+    }
+
+    #[test]
+    fn it_should_not_change_identical_content() {
+        let comparison = compare("same\n", "same\n");
+
+        assert!(!comparison.is_changed());
+        assert_eq!(comparison.unified_diff, "");
+        assert_eq!(comparison.change_ratio, 0.0);
+    }
+}
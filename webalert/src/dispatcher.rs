@@ -0,0 +1,85 @@
+//! Background task that wakes up the streams handing out tasks whenever the task queue changes.
+//!
+//! The server holds a single Postgres listener for the whole process. Every notification it
+//! receives is passed on through a [`watch`] channel, so streams that are waiting for tasks cost
+//! nothing until there might be a task for them, and notifications that arrive while a stream is
+//! busy are never lost.
+
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tracing::{debug, error, instrument, trace, warn};
+
+use crate::database::{self, DbPool};
+use crate::shutdown;
+
+/// The time to wait before trying to listen for notifications again after failing to.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Listens for changes to the task queue and wakes up the receivers of `waiters` on every change,
+/// until `shutdown` is set to `true`.
+///
+/// The receivers are also woken up whenever the listener (re)connects, since changes may have been
+/// missed while it wasn't listening.
+#[instrument(skip(pool, waiters, shutdown))]
+pub async fn run(pool: DbPool, waiters: watch::Sender<()>, mut shutdown: watch::Receiver<bool>) {
+    debug!("Starting task dispatcher");
+
+    'listen: loop {
+        let mut listener = match database::tasks::listen(&pool).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!(?err, "Could not listen for changes to the task queue");
+
+                tokio::select! {
+                    _ = tokio::time::sleep(RETRY_DELAY) => continue,
+                    _ = shutdown::requested(&mut shutdown) => break,
+                }
+            }
+        };
+
+        // Changes may have been missed while we weren't listening
+        let _ = waiters.send(());
+
+        loop {
+            let notification = tokio::select! {
+                notification = listener.try_recv() => notification,
+                _ = shutdown::requested(&mut shutdown) => break 'listen,
+            };
+
+            match notification {
+                Ok(Some(_)) => {
+                    trace!("Task queue changed");
+
+                    let _ = waiters.send(());
+                }
+                Ok(None) => {
+                    // The listener reconnects on the next receive, but the notifications sent in
+                    // the meantime are lost
+                    warn!("Lost connection while listening for changes to the task queue");
+
+                    let _ = waiters.send(());
+                }
+                Err(err) => {
+                    error!(?err, "Could not receive changes to the task queue");
+
+                    tokio::select! {
+                        _ = tokio::time::sleep(RETRY_DELAY) => continue 'listen,
+                        _ = shutdown::requested(&mut shutdown) => break 'listen,
+                    }
+                }
+            }
+        }
+    }
+
+    debug!("Stopped task dispatcher");
+}
+
+/// Resolves once the task queue might have changed since `waiter` last saw it.
+///
+/// Never resolves if the dispatcher has stopped.
+pub async fn changed(waiter: &mut watch::Receiver<()>) {
+    if waiter.changed().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,61 @@
+//! The items of the RSS and Atom feeds that feed alerts watch.
+//!
+//! Runners parse feeds and send their items as a JSON document of the form
+//! `{"items": [...]}` in place of the HTML of the page. Rather than diffing that document, the
+//! server remembers the items each alert has seen, and records a change for every item it hasn't
+//! seen before.
+
+use serde::{Deserialize, Serialize};
+
+/// An item of a feed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeedItem {
+    /// The GUID of the item, or its link if it doesn't have one.
+    pub id: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub link: String,
+    /// The description of the item, or its summary or content in Atom feeds.
+    #[serde(default)]
+    pub summary: String,
+    /// The publication date of the item as written in the feed, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<String>,
+}
+
+/// The items of a feed, in the order the feed lists them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Feed {
+    pub items: Vec<FeedItem>,
+}
+
+impl Feed {
+    /// Parses the document a runner sent for a feed.
+    pub fn parse(document: &str) -> Result<Feed, serde_json::Error> {
+        serde_json::from_str(document)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_feeds() {
+        let feed = Feed::parse(
+            r#"{"items": [
+                 {"id": "urn:1", "title": "Release 1.1", "link": "https://example.com/1.1",
+                  "summary": "Fixes", "published": "Mon, 16 Aug 2021 10:00:00 GMT"},
+                 {"id": "https://example.com/1.0"}
+               ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(feed.items.len(), 2);
+        assert_eq!(feed.items[0].title, "Release 1.1");
+        assert_eq!(feed.items[1].title, "");
+        assert_eq!(feed.items[1].published, None);
+        assert!(Feed::parse("<rss>").is_err());
+    }
+}
@@ -1,91 +1,50 @@
 use std::iter;
-use std::task::{Context, Poll};
+use std::time::Duration;
 
 use crate::cli;
-use crate::database::DbPool;
+use crate::database::{tokens::LastUsedTracker, DbPool};
+use crate::shutdown;
 
-use http::{header, StatusCode};
-use hyper::{Request, Response};
-use tonic::{
-    body::BoxBody,
-    transport::{Body, Server},
-};
-use tower::{Layer, Service};
+use http::header;
+use tokio::sync::watch;
+use tonic::transport::{self, Server};
 use tower_http::{
     sensitive_headers::SetSensitiveRequestHeadersLayer,
     trace::{DefaultMakeSpan, TraceLayer},
 };
-use tracing::{debug, instrument, warn};
+use tracing::{debug, info, instrument, warn};
 
+pub mod auth;
 pub mod v1;
 
-#[derive(Debug, Clone)]
-struct RequireBearerAuthorizationLayer {
-    pool: DbPool,
-}
-
-impl<S> Layer<S> for RequireBearerAuthorizationLayer {
-    type Service = RequireBearerAuthorization<S>;
-
-    fn layer(&self, service: S) -> Self::Service {
-        RequireBearerAuthorization { inner: service }
-    }
-}
-
-#[derive(Debug, Clone)]
-struct RequireBearerAuthorization<S> {
-    inner: S,
-}
-
-impl<S> Service<Request<Body>> for RequireBearerAuthorization<S>
-where
-    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
-    S::Future: Send + 'static,
-{
-    type Response = S::Response;
-    type Error = S::Error;
-    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
-
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
-    }
-
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
-        // This is necessary because tonic internally uses `tower::buffer::Buffer`.
-        // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
-        // for details on why this is necessary
-        let clone = self.inner.clone();
-        let mut inner = std::mem::replace(&mut self.inner, clone);
-
-        Box::pin(async move {
-            // Do extra async work here...
-            let response = if let Some(_auth) = req.headers().get(header::AUTHORIZATION) {
-                let response = inner.call(req).await?;
-                response
-            } else {
-                warn!("Refusing request because it doesn't have an authorization header");
-
-                //let mut response = Response::new(tonic::body::empty_body());
-                Response::builder()
-                    .header(header::CONTENT_TYPE, "application/grpc")
-                    .status(StatusCode::UNAUTHORIZED)
-                    .body(tonic::body::empty_body())
-                    .unwrap()
-            };
-
-            Ok(response)
-        })
-    }
-}
-
-#[instrument(skip(opts, db_pool), fields(host = %opts.grpc_host))]
-pub async fn start_server(opts: &cli::ServerOpts, db_pool: DbPool) {
+use auth::RequireBearerAuthorizationLayer;
+
+/// Runs the gRPC server until `shutdown` is set to `true`.
+///
+/// Streams waiting for tasks look for them again whenever `task_queue` changes.
+///
+/// Once shutdown is requested the server stops accepting new connections and tells runners to
+/// reconnect elsewhere, then waits up to `opts.drain_timeout_secs` for in-flight requests to
+/// finish.
+#[instrument(skip(opts, db_pool, task_queue, shutdown), fields(host = %opts.grpc_host))]
+pub async fn start_server(
+    opts: &cli::ServerOpts,
+    db_pool: DbPool,
+    task_queue: watch::Receiver<()>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<(), transport::Error> {
     debug!("Starting gRPC server");
 
     // Build the bearer token authorization layer
-    let auth_layer = RequireBearerAuthorizationLayer {
-        pool: db_pool.clone(),
-    };
+    let last_used = LastUsedTracker::new();
+    let auth_layer = RequireBearerAuthorizationLayer::new(db_pool.clone(), last_used.clone());
+
+    // Write the last-used time of tokens in the background
+    let flusher = tokio::spawn(auth::flush_last_used(
+        db_pool.clone(),
+        last_used,
+        shutdown.clone(),
+    ));
 
     let layer = tower::ServiceBuilder::new()
         // Mark the `Authorization` request header as sensitive so it doesn't show in logs
@@ -106,11 +65,48 @@ pub async fn start_server(opts: &cli::ServerOpts, db_pool: DbPool) {
         ref_svc = ref_svc.register_encoded_file_descriptor_set(fds);
     }
 
-    Server::builder()
+    let mut server_shutdown = shutdown.clone();
+    let server = Server::builder()
         .layer(layer)
         .add_service(ref_svc.build().unwrap())
-        .add_service(v1::create_runners_service())
-        .serve(opts.grpc_host)
-        .await
-        .unwrap();
+        .add_service(v1::alerts::create_alerts_service(db_pool.clone()))
+        .add_service(v1::create_runners_service(
+            db_pool,
+            v1::Settings {
+                dead_after: Duration::from_secs(opts.runner_dead_after_secs),
+                task_lease: Duration::from_secs(opts.task_lease_secs),
+                task_max_attempts: opts.task_max_attempts,
+            },
+            task_queue,
+            shutdown.clone(),
+        ))
+        .serve_with_shutdown(opts.grpc_host, async move {
+            shutdown::requested(&mut server_shutdown).await;
+
+            info!("Stopping gRPC server");
+        });
+    tokio::pin!(server);
+
+    let mut drain_shutdown = shutdown;
+    let drain_timeout = Duration::from_secs(opts.drain_timeout_secs);
+    let drain_expired = async {
+        shutdown::requested(&mut drain_shutdown).await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+
+    let result = tokio::select! {
+        result = &mut server => result,
+        _ = drain_expired => {
+            warn!(?drain_timeout, "Stopping gRPC server without waiting for in-flight requests");
+
+            Ok(())
+        }
+    };
+
+    // Write the last-used times that are still pending
+    if let Err(err) = flusher.await {
+        warn!(?err, "Could not stop writing the last-used time of tokens");
+    }
+
+    result
 }
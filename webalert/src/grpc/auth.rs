@@ -0,0 +1,355 @@
+//! Bearer token authorization for the gRPC server
+//!
+//! Every request must carry an `Authorization: Bearer <token>` header where `<token>` matches the
+//! hash of a token in the `tokens` table that is neither expired nor revoked. Requests that don't
+//! are refused with a gRPC status of `UNAUTHENTICATED`.
+//!
+//! Each gRPC method additionally requires the token to hold a [`Scope`], see [`required_scope`].
+//! Requests from tokens without it are refused with a gRPC status of `PERMISSION_DENIED`.
+//!
+//! Once a token has been verified, its [`TokenIdentity`] is attached to the request extensions so
+//! handlers can tell who is calling, e.g.:
+//!
+//! ```ignore
+//! let identity = request.extensions().get::<TokenIdentity>();
+//! ```
+
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use chrono::Utc;
+use http::{header, HeaderValue};
+use hyper::{Request, Response};
+use tokio::sync::watch;
+use tonic::{body::BoxBody, transport::Body, Status};
+use tower::{Layer, Service};
+use tracing::{debug, error, warn};
+
+use crate::database::{tokens, DbPool};
+use crate::scope::Scope;
+use crate::{shutdown, token};
+
+/// The interval at which the last-used times of tokens are written to the database.
+pub const LAST_USED_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The identity of the token that authorized a request.
+#[derive(Debug, Clone)]
+pub struct TokenIdentity {
+    /// The unique id of the token.
+    pub id: i32,
+    /// The human-readable name of the token.
+    pub name: String,
+    /// The scopes granted to the token.
+    pub scopes: Vec<Scope>,
+}
+
+impl TokenIdentity {
+    /// Returns true if the token has been granted `scope`.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|granted| granted.grants(scope))
+    }
+}
+
+/// Returns the scope a token must hold to call the gRPC method at `path`, or `None` if any valid
+/// token may call it.
+///
+/// Methods that aren't known here require [`Scope::Admin`].
+pub fn required_scope(path: &str) -> Option<Scope> {
+    if path.starts_with("/grpc.reflection.") {
+        return None;
+    }
+
+    let scope = match path {
+        "/webalert.runner.v1.Runner/Announce"
+        | "/webalert.runner.v1.Runner/Heartbeat"
+        | "/webalert.runner.v1.Runner/Deregister" => Scope::RunnerAnnounce,
+        "/webalert.runner.v1.Runner/Poll"
+        | "/webalert.runner.v1.Runner/Session"
+        | "/webalert.runner.v1.Runner/Ack"
+        | "/webalert.runner.v1.Runner/Nack"
+        | "/webalert.runner.v1.Runner/ExtendLease"
+        | "/webalert.runner.v1.Runner/ReportResult" => Scope::RunnerPoll,
+        "/webalert.runner.v1.Runner/List" => Scope::RunnersRead,
+        "/webalert.alerts.v1.Alerts/ListSnapshots"
+        | "/webalert.alerts.v1.Alerts/GetSnapshot"
+        | "/webalert.alerts.v1.Alerts/ListChanges"
+        | "/webalert.alerts.v1.Alerts/GetChange"
+        | "/webalert.alerts.v1.Alerts/PreviewContent" => Scope::AlertsRead,
+        _ => Scope::Admin,
+    };
+
+    Some(scope)
+}
+
+/// [`Layer`] that wraps services in [`RequireBearerAuthorization`].
+#[derive(Debug, Clone)]
+pub struct RequireBearerAuthorizationLayer {
+    pool: DbPool,
+    last_used: tokens::LastUsedTracker,
+}
+
+impl RequireBearerAuthorizationLayer {
+    /// Creates a new layer that verifies tokens against the database in `pool` and records their
+    /// use in `last_used`.
+    pub fn new(pool: DbPool, last_used: tokens::LastUsedTracker) -> Self {
+        RequireBearerAuthorizationLayer { pool, last_used }
+    }
+}
+
+impl<S> Layer<S> for RequireBearerAuthorizationLayer {
+    type Service = RequireBearerAuthorization<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        RequireBearerAuthorization {
+            inner: service,
+            pool: self.pool.clone(),
+            last_used: self.last_used.clone(),
+        }
+    }
+}
+
+/// Authorization [`Service`] that refuses requests without a valid bearer token.
+#[derive(Debug, Clone)]
+pub struct RequireBearerAuthorization<S> {
+    inner: S,
+    pool: DbPool,
+    last_used: tokens::LastUsedTracker,
+}
+
+impl<S> Service<Request<Body>> for RequireBearerAuthorization<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        // This is necessary because tonic internally uses `tower::buffer::Buffer`.
+        // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
+        // for details on why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let pool = self.pool.clone();
+        let last_used = self.last_used.clone();
+
+        Box::pin(async move {
+            let identity = match authenticate(&pool, req.headers().get(header::AUTHORIZATION)).await
+            {
+                Ok(identity) => identity,
+                Err(status) => return Ok(status.to_http()),
+            };
+
+            last_used.touch(identity.id, Utc::now());
+
+            if let Some(scope) = required_scope(req.uri().path()) {
+                if !identity.has_scope(scope) {
+                    warn!(token.name = %identity.name, %scope, path = %req.uri().path(),
+                        "Refusing request because the token is missing a scope");
+
+                    let status = Status::permission_denied(format!("missing scope {}", scope));
+
+                    return Ok(status.to_http());
+                }
+            }
+
+            req.extensions_mut().insert(identity);
+
+            inner.call(req).await
+        })
+    }
+}
+
+/// Verifies the given `Authorization` header value against the tokens in the database.
+async fn authenticate(pool: &DbPool, value: Option<&HeaderValue>) -> Result<TokenIdentity, Status> {
+    let value = value.ok_or_else(|| {
+        warn!("Refusing request because it doesn't have an authorization header");
+
+        Status::unauthenticated("missing authorization header")
+    })?;
+
+    let secret = parse_bearer_token(value).ok_or_else(|| {
+        warn!("Refusing request because the authorization header is malformed");
+
+        Status::unauthenticated("malformed authorization header")
+    })?;
+
+    let candidates = tokens::find_by_prefix(pool, token::prefix(secret))
+        .await
+        .map_err(|err| {
+            error!(?err, "Could not look up bearer token");
+
+            Status::internal("could not verify bearer token")
+        })?;
+
+    let token = candidates
+        .into_iter()
+        .find(|candidate| token::verify(&candidate.salt, &candidate.secret_hash, secret))
+        .ok_or_else(|| {
+            warn!("Refusing request because the bearer token is unknown");
+
+            Status::unauthenticated("invalid bearer token")
+        })?;
+
+    if token.is_revoked() {
+        warn!(token.name = %token.name, "Refusing request because the bearer token is revoked");
+
+        return Err(Status::unauthenticated("bearer token has been revoked"));
+    }
+
+    if token.is_expired(Utc::now()) {
+        warn!(token.name = %token.name, "Refusing request because the bearer token has expired");
+
+        return Err(Status::unauthenticated("bearer token has expired"));
+    }
+
+    let scopes = token
+        .scopes
+        .iter()
+        .filter_map(|name| match name.parse::<Scope>() {
+            Ok(scope) => Some(scope),
+            Err(err) => {
+                warn!(token.name = %token.name, %err, "Ignoring unknown scope");
+
+                None
+            }
+        })
+        .collect();
+
+    Ok(TokenIdentity {
+        id: token.id,
+        name: token.name,
+        scopes,
+    })
+}
+
+/// Periodically writes the last-used times recorded in `last_used` to the database, until
+/// `shutdown` is set to `true`.
+///
+/// The pending times are written one last time before returning.
+pub async fn flush_last_used(
+    pool: DbPool,
+    last_used: tokens::LastUsedTracker,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(LAST_USED_FLUSH_INTERVAL);
+
+    loop {
+        let stopping = tokio::select! {
+            _ = interval.tick() => false,
+            _ = shutdown::requested(&mut shutdown) => true,
+        };
+
+        match last_used.flush(&pool).await {
+            Ok(0) => {}
+            Ok(num_updated) => debug!(%num_updated, "Updated last-used time of tokens"),
+            Err(err) => error!(?err, "Could not update last-used time of tokens"),
+        }
+
+        if stopping {
+            break;
+        }
+    }
+}
+
+/// Extracts the token from an `Authorization` header value on the form `Bearer <token>`.
+///
+/// Returns `None` if the value isn't a bearer token or if the token is empty.
+fn parse_bearer_token(value: &HeaderValue) -> Option<&str> {
+    let value = value.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim();
+
+    if !scheme.eq_ignore_ascii_case("bearer") || token.is_empty() {
+        return None;
+    }
+
+    Some(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_bearer_token() {
+        let value = HeaderValue::from_static("Bearer abc123");
+
+        assert_eq!(parse_bearer_token(&value), Some("abc123"));
+    }
+
+    #[test]
+    fn it_should_ignore_scheme_case() {
+        let value = HeaderValue::from_static("bearer abc123");
+
+        assert_eq!(parse_bearer_token(&value), Some("abc123"));
+    }
+
+    #[test]
+    fn it_should_reject_other_schemes() {
+        let value = HeaderValue::from_static("Basic dXNlcjpwYXNz");
+
+        assert_eq!(parse_bearer_token(&value), None);
+    }
+
+    #[test]
+    fn it_should_require_scopes_for_runner_methods() {
+        assert_eq!(
+            required_scope("/webalert.runner.v1.Runner/Poll"),
+            Some(Scope::RunnerPoll)
+        );
+        assert_eq!(
+            required_scope("/webalert.runner.v1.Runner/Session"),
+            Some(Scope::RunnerPoll)
+        );
+        assert_eq!(
+            required_scope("/webalert.runner.v1.Runner/ExtendLease"),
+            Some(Scope::RunnerPoll)
+        );
+        assert_eq!(
+            required_scope("/webalert.runner.v1.Runner/ReportResult"),
+            Some(Scope::RunnerPoll)
+        );
+        assert_eq!(
+            required_scope("/webalert.runner.v1.Runner/Deregister"),
+            Some(Scope::RunnerAnnounce)
+        );
+        assert_eq!(
+            required_scope("/webalert.runner.v1.Runner/List"),
+            Some(Scope::RunnersRead)
+        );
+        assert_eq!(
+            required_scope("/webalert.alerts.v1.Alerts/GetSnapshot"),
+            Some(Scope::AlertsRead)
+        );
+        assert_eq!(
+            required_scope("/webalert.alerts.v1.Alerts/ListChanges"),
+            Some(Scope::AlertsRead)
+        );
+        assert_eq!(
+            required_scope("/webalert.runner.v1.Runner/Unknown"),
+            Some(Scope::Admin)
+        );
+        assert_eq!(
+            required_scope("/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo"),
+            None
+        );
+    }
+
+    #[test]
+    fn it_should_reject_empty_token() {
+        assert_eq!(
+            parse_bearer_token(&HeaderValue::from_static("Bearer ")),
+            None
+        );
+        assert_eq!(
+            parse_bearer_token(&HeaderValue::from_static("Bearer")),
+            None
+        );
+    }
+}
@@ -1,11 +1,36 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::pin::Pin;
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
 use futures::Stream;
-use tonic::{Request, Response, Status};
-use tracing::{instrument, trace};
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{debug, error, instrument, trace, warn};
 
+use super::auth::TokenIdentity;
+use crate::database::selectors::{Extraction, Kind as SelectorKind};
+use crate::database::{self, DbPool};
+use crate::{dispatcher, shutdown};
+use runners::poll_response::Event;
 use runners::runner_server::{Runner, RunnerServer};
-use runners::{AnnounceRequest, ListRequest, ListResponse, PollResponse};
+use runners::selector;
+use runners::session_request::Event as RunnerEvent;
+use runners::session_response::Event as ServerEvent;
+use runners::task;
+use runners::task_error::Code as TaskErrorCode;
+use runners::task_progress::Stage;
+use runners::{
+    AckRequest, AckResponse, AnnounceRequest, AnnounceResponse, CancelTask, DeregisterRequest,
+    DeregisterResponse, DrainNotice, ExtendLeaseRequest, ExtendLeaseResponse, HeartbeatRequest,
+    HeartbeatResponse, ListRequest, ListResponse, NackRequest, NackResponse, PollRequest,
+    PollResponse, ReportResultRequest, ReportResultResponse, RunnerInfo, RunnerStatus, Selector,
+    SessionRequest, SessionResponse, Task,
+};
+
+pub mod alerts;
 
 pub mod runners {
     tonic::include_proto!("webalert.runner.v1");
@@ -14,64 +39,1048 @@ pub mod runners {
         tonic::include_file_descriptor_set!("runners_descriptor");
 }
 
-#[derive(Default, Debug)]
-pub struct RunnerService;
+/// The number of runners returned by [Runner.List] when the client doesn't ask for a page size.
+const DEFAULT_PAGE_SIZE: i32 = 50;
+
+/// The maximum number of runners returned by [Runner.List].
+const MAX_PAGE_SIZE: i32 = 1000;
+
+/// The interval at which [Runner.Session] streams check that the runner still holds the leases on
+/// the tasks it's working on.
+const LEASE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings that control the behaviour of the [`RunnerService`].
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// The time a runner can go unseen before it's assumed dead.
+    pub dead_after: Duration,
+    /// The time a runner has to finish a task before it's handed out again.
+    pub task_lease: Duration,
+    /// The number of times a task is handed out before it's given up on.
+    pub task_max_attempts: i32,
+}
+
+#[derive(Debug)]
+pub struct RunnerService {
+    pool: DbPool,
+    /// The time a runner can go unseen before it's assumed dead.
+    dead_after: chrono::Duration,
+    /// The time a runner has to finish a task before it's handed out again.
+    task_lease: Duration,
+    /// The number of times a task is handed out before it's given up on.
+    task_max_attempts: i32,
+    /// Changes whenever a task might have become available.
+    task_queue: watch::Receiver<()>,
+    /// Set to `true` when the server is shutting down.
+    shutdown: watch::Receiver<bool>,
+}
+
+impl RunnerService {
+    /// Creates a new runner service that stores runners and tasks in the database in `pool`.
+    ///
+    /// Streams that are waiting for tasks look for them again whenever `task_queue` changes. Open
+    /// streams are drained once `shutdown` is set to `true`.
+    pub fn new(
+        pool: DbPool,
+        settings: Settings,
+        task_queue: watch::Receiver<()>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        let dead_after = chrono::Duration::from_std(settings.dead_after)
+            .unwrap_or_else(|_| chrono::Duration::max_value());
+
+        RunnerService {
+            pool,
+            dead_after,
+            task_lease: settings.task_lease,
+            task_max_attempts: settings.task_max_attempts,
+            task_queue,
+            shutdown,
+        }
+    }
+
+    /// Returns what a stream needs to hand out tasks to the runner with the given `runner_id`,
+    /// which can hold leases on up to `max_tasks` tasks at a time, or 1 if `max_tasks` is 0.
+    fn dispatch(&self, runner_id: i32, max_tasks: u32) -> Dispatch {
+        Dispatch {
+            pool: self.pool.clone(),
+            runner_id,
+            max_tasks: max_tasks.max(1),
+            lease: self.task_lease,
+            task_queue: self.task_queue.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    /// Returns the details of `runner` as of `now`.
+    fn runner_info(&self, runner: database::runners::Runner, now: DateTime<Utc>) -> RunnerInfo {
+        let status = if runner.is_dead(now - self.dead_after) {
+            RunnerStatus::Dead
+        } else {
+            RunnerStatus::Alive
+        };
+
+        RunnerInfo {
+            id: runner.id.into(),
+            name: runner.name,
+            hostname: runner.hostname,
+            os: runner.os,
+            arch: runner.arch,
+            version: runner.version,
+            create_time: runner.created_at.map(to_timestamp),
+            update_time: runner.last_seen_at.map(to_timestamp),
+            status: status as i32,
+            load: runner.load.unwrap_or_default(),
+            active_tasks: runner.active_tasks.unwrap_or_default().max(0) as u32,
+            uptime: runner
+                .uptime_secs
+                .map(|seconds| prost_types::Duration { seconds, nanos: 0 }),
+        }
+    }
+
+    /// Looks up the runner with the given `runner_id` and verifies that it belongs to the token in
+    /// `identity` and that `session_id` is its current session.
+    async fn authorize_runner(
+        &self,
+        identity: &TokenIdentity,
+        runner_id: i64,
+        session_id: &str,
+    ) -> Result<database::runners::Runner, Status> {
+        let runner_id =
+            i32::try_from(runner_id).map_err(|_| Status::invalid_argument("invalid runner id"))?;
+        let runner = database::runners::find(&self.pool, runner_id)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not look up runner");
+
+                Status::internal("could not look up runner")
+            })?
+            .ok_or_else(|| Status::not_found("unknown runner, announce the runner first"))?;
+
+        if runner.token_id != Some(identity.id) {
+            return Err(Status::permission_denied(
+                "runner was announced with a different token",
+            ));
+        }
+
+        if runner.session_id.as_deref() != Some(session_id) {
+            return Err(Status::failed_precondition(
+                "runner session has ended, announce the runner again",
+            ));
+        }
+
+        Ok(runner)
+    }
+
+    /// Hands the tasks leased by the runner with the given `runner_id` out again, because the
+    /// runner has gone away.
+    async fn release_tasks(&self, runner_id: i32) -> Result<(), Status> {
+        let task_ids = database::tasks::release(&self.pool, &[runner_id], self.task_max_attempts)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not release tasks");
+
+                Status::internal("could not release tasks")
+            })?;
+
+        if !task_ids.is_empty() {
+            debug!(runner.id = runner_id, ?task_ids, "Released tasks of runner");
+        }
+
+        Ok(())
+    }
+}
+
+/// Converts the task id in a request to a database id.
+fn task_id(id: i64) -> Result<i32, Status> {
+    i32::try_from(id).map_err(|_| Status::invalid_argument("invalid task id"))
+}
+
+/// Returns the error for requests about tasks that the runner doesn't hold a lease on.
+fn not_leased() -> Status {
+    Status::failed_precondition("task is not leased by this runner")
+}
+
+/// Returns the identity of the token that authorized `request`.
+fn token_identity<T>(request: &Request<T>) -> Result<TokenIdentity, Status> {
+    request
+        .extensions()
+        .get::<TokenIdentity>()
+        .cloned()
+        .ok_or_else(|| Status::unauthenticated("missing token identity"))
+}
+
+/// What a stream needs to hand out tasks to a runner.
+#[derive(Debug)]
+struct Dispatch {
+    pool: DbPool,
+    /// The id of the runner.
+    runner_id: i32,
+    /// The maximum number of tasks the runner can hold leases on at a time.
+    max_tasks: u32,
+    /// The time a runner has to finish a task before it's handed out again.
+    lease: Duration,
+    /// Changes whenever a task might have become available.
+    task_queue: watch::Receiver<()>,
+    /// Set to `true` when the server is shutting down.
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Dispatch {
+    /// Leases the next queued task to the runner, unless it already holds `max_tasks` leases.
+    async fn lease_next(&self) -> Result<Option<database::tasks::Task>, sqlx::Error> {
+        let task = database::tasks::lease_next(
+            &self.pool,
+            self.runner_id,
+            i64::from(self.max_tasks),
+            self.lease,
+        )
+        .await?;
+
+        if let Some(ref task) = task {
+            trace!(
+                runner.id = self.runner_id,
+                task.id,
+                task.attempts,
+                "Leased task"
+            );
+        }
+
+        Ok(task)
+    }
+}
+
+/// Leases tasks to the runner over `tx` until the runner disconnects or the server shuts down, in
+/// which case the runner is sent a drain notice.
+///
+/// A task is only taken from the queue once the runner is ready to receive it and holds fewer
+/// than `max_tasks` leases. While there are none, the stream waits for the task queue to change.
+async fn dispatch_tasks(mut dispatch: Dispatch, tx: mpsc::Sender<Result<PollResponse, Status>>) {
+    while !*dispatch.shutdown.borrow() {
+        let permit = tokio::select! {
+            permit = tx.reserve() => match permit {
+                Ok(permit) => permit,
+                Err(_) => return,
+            },
+            _ = shutdown::requested(&mut dispatch.shutdown) => break,
+        };
+
+        match dispatch.lease_next().await {
+            Ok(Some(task)) => {
+                permit.send(Ok(PollResponse {
+                    event: Some(Event::Task(to_task(task))),
+                }));
+            }
+            Ok(None) => {
+                drop(permit);
+
+                tokio::select! {
+                    _ = dispatcher::changed(&mut dispatch.task_queue) => {}
+                    _ = tx.closed() => return,
+                    _ = shutdown::requested(&mut dispatch.shutdown) => {}
+                }
+            }
+            Err(err) => {
+                error!(?err, "Could not lease task");
+
+                permit.send(Err(Status::internal("could not lease task")));
+
+                return;
+            }
+        }
+    }
+
+    debug!(runner.id = dispatch.runner_id, "Draining polling stream");
+
+    // Tell the runner to reconnect elsewhere before the stream ends
+    let drain = PollResponse {
+        event: Some(Event::Drain(DrainNotice {})),
+    };
+    let _ = tx.send(Ok(drain)).await;
+}
+
+/// The credit a runner has granted over a [Runner.Session] stream, and the tasks it was handed.
+#[derive(Debug)]
+struct Credit {
+    /// The number of tasks the runner is ready to receive.
+    available: u32,
+    /// The maximum number of tasks the runner can hold leases on at a time.
+    max_tasks: u32,
+    /// The tasks handed out to the runner that it hasn't finished yet.
+    in_flight: HashSet<i32>,
+}
+
+impl Credit {
+    /// Returns the credit of a runner that holds at most `max_tasks` leases and hasn't granted any.
+    fn new(max_tasks: u32) -> Self {
+        Credit {
+            available: 0,
+            max_tasks,
+            in_flight: HashSet::new(),
+        }
+    }
+
+    /// Adds the credit for `tasks` more tasks, up to `max_tasks`.
+    fn grant(&mut self, tasks: u32) {
+        self.available = self.available.saturating_add(tasks).min(self.max_tasks);
+    }
+
+    /// Returns true if the runner is ready to receive another task.
+    fn has_credit(&self) -> bool {
+        self.available > 0
+    }
+
+    /// Uses up a unit of credit on handing out the task with the given `task_id`.
+    fn hand_out(&mut self, task_id: i32) {
+        self.available = self.available.saturating_sub(1);
+        self.in_flight.insert(task_id);
+    }
+
+    /// Forgets the task with the given `task_id`, because the runner finished or lost it.
+    ///
+    /// Returns true if the task was in flight.
+    fn finish(&mut self, task_id: i32) -> bool {
+        self.in_flight.remove(&task_id)
+    }
+
+    /// Returns the ids of the tasks in flight.
+    fn in_flight(&self) -> Vec<i32> {
+        self.in_flight.iter().copied().collect()
+    }
+}
+
+/// Returns the tasks in flight that the runner with the given `runner_id` no longer holds the
+/// lease on.
+async fn lost_tasks(pool: &DbPool, runner_id: i32, credit: &Credit) -> Vec<i32> {
+    let task_ids = credit.in_flight();
+
+    if task_ids.is_empty() {
+        return task_ids;
+    }
+
+    match database::tasks::not_leased_by(pool, runner_id, &task_ids).await {
+        Ok(lost) => lost,
+        Err(err) => {
+            error!(?err, "Could not look up leases of runner");
+
+            Vec::new()
+        }
+    }
+}
+
+/// Exchanges tasks with the runner over a [Runner.Session] stream until the runner disconnects or
+/// the server shuts down, in which case the runner is sent a drain notice.
+///
+/// A task is leased for every unit of credit the runner has granted, as long as it holds fewer
+/// than `max_tasks` leases, whenever the runner grants credit or the task queue changes. The
+/// queue changes whenever a task is queued or a lease ends, so the stream doesn't poll it. Tasks
+/// that the runner loses the lease on before it has finished them are cancelled.
+async fn run_session(
+    mut dispatch: Dispatch,
+    mut inbound: Streaming<SessionRequest>,
+    tx: mpsc::Sender<Result<SessionResponse, Status>>,
+) {
+    let runner_id = dispatch.runner_id;
+    let mut credit = Credit::new(dispatch.max_tasks);
+    let mut lease_check = tokio::time::interval(LEASE_CHECK_INTERVAL);
+
+    while !*dispatch.shutdown.borrow() {
+        // Tasks are only looked for when the runner grants credit or the task queue changes
+        let woken = tokio::select! {
+            message = inbound.message() => match message {
+                Ok(Some(SessionRequest { event: Some(RunnerEvent::Credit(grant)) })) => {
+                    credit.grant(grant.tasks);
+
+                    trace!(runner.id = runner_id, credit = credit.available,
+                        "Runner granted credit");
+
+                    true
+                }
+                Ok(Some(SessionRequest { event: Some(RunnerEvent::Progress(progress)) })) => {
+                    trace!(runner.id = runner_id, task.id = progress.task_id,
+                        stage = ?progress.stage(), "Task progressed");
+
+                    if progress.stage() == Stage::Finished {
+                        if let Ok(task_id) = i32::try_from(progress.task_id) {
+                            credit.finish(task_id);
+                        }
+                    }
+
+                    false
+                }
+                Ok(Some(message)) => {
+                    debug!(?message, "Ignoring unexpected session message");
+
+                    false
+                }
+                Ok(None) => return,
+                Err(status) => {
+                    debug!(runner.id = runner_id, ?status, "Session stream failed");
+
+                    return;
+                }
+            },
+            _ = dispatcher::changed(&mut dispatch.task_queue), if credit.has_credit() => true,
+            _ = lease_check.tick(), if !credit.in_flight.is_empty() => {
+                for task_id in lost_tasks(&dispatch.pool, runner_id, &credit).await {
+                    debug!(runner.id = runner_id, task.id = task_id, "Cancelling lost task");
+
+                    credit.finish(task_id);
+
+                    let cancel = SessionResponse {
+                        event: Some(ServerEvent::Cancel(CancelTask {
+                            task_id: task_id.into(),
+                            reason: "task is no longer leased by this runner".to_string(),
+                        })),
+                    };
+
+                    if tx.send(Ok(cancel)).await.is_err() {
+                        return;
+                    }
+                }
+
+                false
+            }
+            _ = shutdown::requested(&mut dispatch.shutdown) => break,
+        };
+
+        // Hand out a task for every unit of credit, for as long as there are tasks queued
+        while woken && credit.has_credit() {
+            match dispatch.lease_next().await {
+                Ok(Some(task)) => {
+                    credit.hand_out(task.id);
+
+                    let task = SessionResponse {
+                        event: Some(ServerEvent::Task(to_task(task))),
+                    };
+
+                    if tx.send(Ok(task)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    error!(?err, "Could not lease task");
+
+                    let _ = tx.send(Err(Status::internal("could not lease task"))).await;
+
+                    return;
+                }
+            }
+        }
+    }
+
+    debug!(runner.id = runner_id, "Draining session");
+
+    // Tell the runner to reconnect elsewhere before the stream ends
+    let drain = SessionResponse {
+        event: Some(ServerEvent::Drain(DrainNotice {})),
+    };
+    let _ = tx.send(Ok(drain)).await;
+}
+
+/// Converts a database selector to its protobuf representation.
+fn to_selector(selector: database::selectors::Selector) -> Selector {
+    let kind = match selector.kind {
+        SelectorKind::Css => selector::Kind::Css,
+        SelectorKind::Xpath => selector::Kind::Xpath,
+        SelectorKind::Javascript => selector::Kind::Javascript,
+        SelectorKind::Jsonpath => selector::Kind::Jsonpath,
+    };
+    let extraction = match selector.extraction {
+        Extraction::Text => selector::Extraction::Text,
+        Extraction::Html => selector::Extraction::Html,
+        Extraction::Attribute => selector::Extraction::Attribute,
+        Extraction::Count => selector::Extraction::Count,
+    };
+
+    Selector {
+        name: selector.name,
+        kind: kind as i32,
+        expression: selector.expression,
+        extraction: extraction as i32,
+        attribute: selector.attribute.unwrap_or_default(),
+    }
+}
+
+/// Converts a database task to its protobuf representation.
+fn to_task(task: database::tasks::Task) -> Task {
+    Task {
+        id: task.id.into(),
+        alert_id: task.alert_id.into(),
+        fetch_mode: to_fetch_mode(&task.fetch_mode) as i32,
+        format: to_format(&task.format) as i32,
+        url: task.url,
+        selectors: task.selectors.0.into_iter().map(to_selector).collect(),
+        due_time: Some(to_timestamp(task.due_at)),
+        attempt: task.attempts.max(0) as u32,
+        lease_expire_time: task.lease_expires_at.map(to_timestamp),
+    }
+}
+
+/// Converts the fetch mode of a task to its protobuf representation.
+fn to_fetch_mode(fetch_mode: &str) -> task::FetchMode {
+    match fetch_mode {
+        "http" => task::FetchMode::Http,
+        _ => task::FetchMode::Browser,
+    }
+}
+
+/// Converts the format of a task to its protobuf representation.
+fn to_format(format: &str) -> task::Format {
+    match format {
+        "json" => task::Format::Json,
+        "feed" => task::Format::Feed,
+        "screenshot" => task::Format::Screenshot,
+        _ => task::Format::Html,
+    }
+}
+
+/// Converts `time` to a protobuf timestamp.
+fn to_timestamp(time: DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    }
+}
+
+/// Returns the whole number of seconds in `duration`, or 0 if it's negative.
+fn duration_secs(duration: Option<prost_types::Duration>) -> i64 {
+    duration.map_or(0, |duration| duration.seconds.max(0))
+}
+
+/// Returns the whole number of milliseconds in `duration`, or 0 if it's negative.
+fn duration_millis(duration: &prost_types::Duration) -> i64 {
+    let millis = duration
+        .seconds
+        .saturating_mul(1000)
+        .saturating_add(i64::from(duration.nanos / 1_000_000));
+
+    millis.max(0)
+}
+
+/// Returns the name a task error code is stored under in the database.
+fn task_error_code(code: i32) -> &'static str {
+    match TaskErrorCode::from_i32(code) {
+        Some(TaskErrorCode::NavigationFailed) => "navigation_failed",
+        Some(TaskErrorCode::ElementNotFound) => "element_not_found",
+        Some(TaskErrorCode::ExtractionFailed) => "extraction_failed",
+        Some(TaskErrorCode::Unspecified) | None => "unspecified",
+    }
+}
+
+/// Returns `value` if it's not empty.
+fn non_empty(value: &str) -> Option<&str> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Generates a new random session id.
+fn generate_session_id() -> String {
+    let bytes: [u8; 16] = rand::random();
+
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
 
 #[tonic::async_trait]
 impl Runner for RunnerService {
     type PollStream =
         Pin<Box<dyn Stream<Item = Result<PollResponse, Status>> + Send + Sync + 'static>>;
+    type SessionStream =
+        Pin<Box<dyn Stream<Item = Result<SessionResponse, Status>> + Send + Sync + 'static>>;
 
-    #[instrument]
-    async fn announce(&self, request: Request<AnnounceRequest>) -> Result<Response<()>, Status> {
-        let _announce_req = request.into_inner();
+    #[instrument(skip(self))]
+    async fn announce(
+        &self,
+        request: Request<AnnounceRequest>,
+    ) -> Result<Response<AnnounceResponse>, Status> {
+        let identity = token_identity(&request)?;
+        let announce_req = request.into_inner();
 
-        trace!("Received runner announcement");
+        trace!(?identity, "Received runner announcement");
 
-        Ok(Response::new(()))
+        let session_id = generate_session_id();
+        let announcement = database::runners::Announcement {
+            hostname: &announce_req.hostname,
+            os: &announce_req.os,
+            arch: &announce_req.arch,
+            version: &announce_req.version,
+            resumed_session_id: non_empty(&announce_req.session_id),
+        };
+        let runner =
+            database::runners::announce(&self.pool, identity.id, &session_id, announcement)
+                .await
+                .map_err(|err| {
+                    error!(?err, "Could not store runner announcement");
+
+                    Status::internal("could not store runner announcement")
+                })?;
+
+        // A runner that resumes its session is still working on the tasks it leased in it
+        let session_id = match runner.session_id {
+            Some(current) if current != session_id => {
+                trace!(runner.id, session_id = %current, "Resumed runner session");
+
+                current
+            }
+            _ => {
+                trace!(runner.id, %session_id, "Started new runner session");
+
+                // Tasks leased in a previous session won't be finished by this one
+                self.release_tasks(runner.id).await?;
+
+                session_id
+            }
+        };
+
+        Ok(Response::new(AnnounceResponse {
+            runner_id: runner.id.into(),
+            session_id,
+        }))
     }
 
-    #[instrument]
+    #[instrument(skip(self))]
     async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
-        let _list_req = request.into_inner();
+        let list_req = request.into_inner();
 
         trace!("Received runner list request");
 
-        Err(Status::unimplemented("This method is not implemented yet"))
+        let page_size = match list_req.page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            size if size < 0 => return Err(Status::invalid_argument("negative page size")),
+            size => size.min(MAX_PAGE_SIZE),
+        };
+        let after_id = match non_empty(&list_req.page_token) {
+            Some(token) => Some(
+                token
+                    .parse::<i32>()
+                    .map_err(|_| Status::invalid_argument("invalid page token"))?,
+            ),
+            None => None,
+        };
+
+        let now = Utc::now();
+        let filter = database::runners::ListFilter {
+            after_id,
+            seen_since: if list_req.include_dead {
+                None
+            } else {
+                Some(now - self.dead_after)
+            },
+            hostname: non_empty(&list_req.hostname),
+            os: non_empty(&list_req.os),
+            arch: non_empty(&list_req.arch),
+        };
+
+        // Fetch one runner more than requested to find out if there's another page
+        let mut runners = database::runners::list(&self.pool, &filter, i64::from(page_size) + 1)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not list runners");
+
+                Status::internal("could not list runners")
+            })?;
+
+        let next_page_token = if runners.len() > page_size as usize {
+            runners.truncate(page_size as usize);
+            runners
+                .last()
+                .map(|runner| runner.id.to_string())
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        let runners = runners
+            .into_iter()
+            .map(|runner| self.runner_info(runner, now))
+            .collect();
+
+        Ok(Response::new(ListResponse {
+            runners,
+            next_page_token,
+        }))
     }
 
-    #[instrument(fields(request.remote_addr = ?request.remote_addr()))]
-    async fn poll(&self, request: Request<()>) -> Result<Response<Self::PollStream>, Status> {
-        trace!(?request);
+    #[instrument(skip(self))]
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let identity = token_identity(&request)?;
+        let heartbeat_req = request.into_inner();
+        let runner = self
+            .authorize_runner(
+                &identity,
+                heartbeat_req.runner_id,
+                &heartbeat_req.session_id,
+            )
+            .await?;
 
-        let output = async_stream::try_stream! {
-            loop {
-                let response = PollResponse { url: "hello".to_string() };
+        trace!(runner.id, "Received runner heartbeat");
 
-                //trace!(?response,
-                //    request.remote_addr = ?request.remote_addr(),
-                //    "response");
+        let heartbeat = database::runners::Heartbeat {
+            load: heartbeat_req.load,
+            active_tasks: i32::try_from(heartbeat_req.active_tasks).unwrap_or(i32::MAX),
+            uptime_secs: duration_secs(heartbeat_req.uptime),
+        };
 
-                if false {
-                    yield response;
-                }
+        database::runners::heartbeat(&self.pool, runner.id, &heartbeat)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not store runner heartbeat");
+
+                Status::internal("could not store runner heartbeat")
+            })?;
+
+        Ok(Response::new(HeartbeatResponse {}))
+    }
+
+    #[instrument(skip(self))]
+    async fn deregister(
+        &self,
+        request: Request<DeregisterRequest>,
+    ) -> Result<Response<DeregisterResponse>, Status> {
+        let identity = token_identity(&request)?;
+        let deregister_req = request.into_inner();
+        let runner = self
+            .authorize_runner(
+                &identity,
+                deregister_req.runner_id,
+                &deregister_req.session_id,
+            )
+            .await?;
 
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        database::runners::deregister(&self.pool, runner.id)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not deregister runner");
+
+                Status::internal("could not deregister runner")
+            })?;
+
+        self.release_tasks(runner.id).await?;
+
+        trace!(runner.id, "Runner deregistered");
+
+        Ok(Response::new(DeregisterResponse {}))
+    }
+
+    #[instrument(skip(self))]
+    async fn ack(&self, request: Request<AckRequest>) -> Result<Response<AckResponse>, Status> {
+        let identity = token_identity(&request)?;
+        let ack_req = request.into_inner();
+        let runner = self
+            .authorize_runner(&identity, ack_req.runner_id, &ack_req.session_id)
+            .await?;
+        let task_id = task_id(ack_req.task_id)?;
+
+        let acked = database::tasks::ack(&self.pool, task_id, runner.id)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not acknowledge task");
+
+                Status::internal("could not acknowledge task")
+            })?;
+
+        if !acked {
+            return Err(not_leased());
+        }
+
+        trace!(runner.id, task.id = task_id, "Task succeeded");
+
+        Ok(Response::new(AckResponse {}))
+    }
+
+    #[instrument(skip(self))]
+    async fn nack(&self, request: Request<NackRequest>) -> Result<Response<NackResponse>, Status> {
+        let identity = token_identity(&request)?;
+        let nack_req = request.into_inner();
+        let runner = self
+            .authorize_runner(&identity, nack_req.runner_id, &nack_req.session_id)
+            .await?;
+        let task_id = task_id(nack_req.task_id)?;
+
+        let task = database::tasks::nack(
+            &self.pool,
+            task_id,
+            runner.id,
+            &nack_req.reason,
+            self.task_max_attempts,
+        )
+        .await
+        .map_err(|err| {
+            error!(?err, "Could not reject task");
+
+            Status::internal("could not reject task")
+        })?
+        .ok_or_else(not_leased)?;
+
+        if task.is_failed() {
+            warn!(runner.id, task.id, task.attempts, reason = %nack_req.reason,
+                "Giving up on task");
+        } else {
+            debug!(runner.id, task.id, task.attempts, reason = %nack_req.reason,
+                "Task failed, handing it out again");
+        }
+
+        Ok(Response::new(NackResponse {
+            will_retry: !task.is_failed(),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn extend_lease(
+        &self,
+        request: Request<ExtendLeaseRequest>,
+    ) -> Result<Response<ExtendLeaseResponse>, Status> {
+        let identity = token_identity(&request)?;
+        let extend_req = request.into_inner();
+        let runner = self
+            .authorize_runner(&identity, extend_req.runner_id, &extend_req.session_id)
+            .await?;
+        let task_id = task_id(extend_req.task_id)?;
+
+        let task = database::tasks::extend_lease(&self.pool, task_id, runner.id, self.task_lease)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not extend task lease");
+
+                Status::internal("could not extend task lease")
+            })?
+            .ok_or_else(not_leased)?;
+
+        trace!(runner.id, task.id, "Extended task lease");
+
+        Ok(Response::new(ExtendLeaseResponse {
+            lease_expire_time: task.lease_expires_at.map(to_timestamp),
+        }))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn report_result(
+        &self,
+        request: Request<ReportResultRequest>,
+    ) -> Result<Response<ReportResultResponse>, Status> {
+        let identity = token_identity(&request)?;
+        let report_req = request.into_inner();
+        let runner = self
+            .authorize_runner(&identity, report_req.runner_id, &report_req.session_id)
+            .await?;
+        let task_id = task_id(report_req.task_id)?;
+        let result = report_req
+            .result
+            .ok_or_else(|| Status::invalid_argument("missing result"))?;
+
+        let timings = result.timings.unwrap_or_default();
+        let error = result
+            .error
+            .as_ref()
+            .map(|error| database::results::ResultError {
+                code: task_error_code(error.code),
+                message: &error.message,
+                retryable: error.retryable,
+            });
+        let new_result = database::results::NewResult {
+            final_url: &result.final_url,
+            http_status: match result.http_status {
+                0 => None,
+                status => i32::try_from(status).ok(),
+            },
+            text: &result.text,
+            html: &result.html,
+            content_hash: &result.content_hash,
+            screenshot: Some(&result.screenshot[..]).filter(|screenshot| !screenshot.is_empty()),
+            total_ms: timings.total.as_ref().map(duration_millis),
+            navigation_ms: timings.navigation.as_ref().map(duration_millis),
+            extraction_ms: timings.extraction.as_ref().map(duration_millis),
+            values: result
+                .values
+                .iter()
+                .map(|value| database::results::ExtractedValue {
+                    name: &value.name,
+                    value: &value.value,
+                })
+                .collect(),
+            error,
+        };
+
+        let recorded = database::results::record(
+            &self.pool,
+            task_id,
+            runner.id,
+            &new_result,
+            self.task_max_attempts,
+        )
+        .await
+        .map_err(|err| {
+            error!(?err, "Could not record task result");
+
+            Status::internal("could not record task result")
+        })?
+        .ok_or_else(not_leased)?;
+
+        match &new_result.error {
+            Some(error) if recorded.task_state == "failed" => {
+                warn!(
+                    runner.id,
+                    task.id = task_id,
+                    error.code,
+                    error.message,
+                    "Giving up on task"
+                );
             }
+            Some(error) => {
+                debug!(
+                    runner.id,
+                    task.id = task_id,
+                    error.code,
+                    error.message,
+                    "Task failed, handing it out again"
+                );
+            }
+            None => trace!(
+                runner.id,
+                task.id = task_id,
+                result.id = recorded.id,
+                "Recorded task result"
+            ),
+        }
+
+        for &change_id in &recorded.change_ids {
+            debug!(
+                task.id = task_id,
+                change.id = change_id,
+                change.triggered = recorded.triggered,
+                "Detected change"
+            );
+        }
+
+        if recorded.triggered {
+            debug!(
+                task.id = task_id,
+                result.id = recorded.id,
+                "Result triggered a notification"
+            );
+        }
+
+        Ok(Response::new(ReportResultResponse {
+            will_retry: recorded.task_state == "queued",
+        }))
+    }
+
+    #[instrument(skip(self), fields(request.remote_addr = ?request.remote_addr()))]
+    async fn poll(
+        &self,
+        request: Request<PollRequest>,
+    ) -> Result<Response<Self::PollStream>, Status> {
+        trace!(?request);
+
+        let identity = token_identity(&request)?;
+        let poll_req = request.into_inner();
+        let runner = self
+            .authorize_runner(&identity, poll_req.runner_id, &poll_req.session_id)
+            .await?;
+
+        trace!(runner.id, "Runner started polling");
+
+        let (tx, rx) = mpsc::channel(1);
+
+        tokio::spawn(dispatch_tasks(
+            self.dispatch(runner.id, poll_req.max_tasks),
+            tx,
+        ));
+
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as Self::PollStream
+        ))
+    }
+
+    #[instrument(skip(self, request), fields(request.remote_addr = ?request.remote_addr()))]
+    async fn session(
+        &self,
+        request: Request<Streaming<SessionRequest>>,
+    ) -> Result<Response<Self::SessionStream>, Status> {
+        let identity = token_identity(&request)?;
+        let mut inbound = request.into_inner();
+        let hello = match inbound.message().await? {
+            Some(SessionRequest {
+                event: Some(RunnerEvent::Hello(hello)),
+            }) => hello,
+            _ => return Err(Status::invalid_argument("session must start with a hello")),
         };
+        let runner = self
+            .authorize_runner(&identity, hello.runner_id, &hello.session_id)
+            .await?;
+
+        trace!(
+            runner.id,
+            max_tasks = hello.max_tasks,
+            "Runner opened a session"
+        );
+
+        let (tx, rx) = mpsc::channel(1);
 
-        Ok(Response::new(Box::pin(output) as Self::PollStream))
+        tokio::spawn(run_session(
+            self.dispatch(runner.id, hello.max_tasks),
+            inbound,
+            tx,
+        ));
+
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as Self::SessionStream
+        ))
     }
 }
 
 /// Returns a list of reflection descriptor sets for this api.
 pub(crate) fn file_descriptor_sets<'a>() -> Vec<&'a [u8]> {
-    vec![runners::FILE_DESCRIPTOR_SET]
+    vec![
+        runners::FILE_DESCRIPTOR_SET,
+        alerts::proto::FILE_DESCRIPTOR_SET,
+    ]
 }
 
 /// Creates and returns the gRPC `Runners` service.
-pub(crate) fn create_runners_service() -> RunnerServer<RunnerService> {
-    let runner_svc = RunnerService::default();
+pub(crate) fn create_runners_service(
+    pool: DbPool,
+    settings: Settings,
+    task_queue: watch::Receiver<()>,
+    shutdown: watch::Receiver<bool>,
+) -> RunnerServer<RunnerService> {
+    let runner_svc = RunnerService::new(pool, settings, task_queue, shutdown);
 
     RunnerServer::new(runner_svc)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_account_for_credit_and_tasks_in_flight() {
+        let mut credit = Credit::new(2);
+        assert!(!credit.has_credit());
+
+        // Credit beyond the maximum number of leases is dropped
+        credit.grant(5);
+        assert_eq!(credit.available, 2);
+
+        credit.hand_out(1);
+        credit.hand_out(2);
+        assert!(!credit.has_credit());
+        assert_eq!(credit.in_flight().len(), 2);
+
+        // Finishing a task doesn't grant credit, the runner does that once it has room
+        assert!(credit.finish(1));
+        assert!(!credit.finish(1));
+        assert!(!credit.has_credit());
+        assert_eq!(credit.in_flight(), vec![2]);
+
+        credit.grant(u32::MAX);
+        assert_eq!(credit.available, 2);
+    }
+}
@@ -0,0 +1,413 @@
+//! The gRPC service that gives access to alerts and their snapshots.
+
+use std::convert::TryFrom;
+
+use sqlx::types::Json;
+use tonic::{Request, Response, Status};
+use tracing::{error, instrument};
+
+use super::{non_empty, to_timestamp};
+use crate::content::Filter;
+use crate::database::{self, DbPool};
+use crate::diff::SegmentKind;
+use crate::json::ValueChangeKind;
+use crate::normalize::Pipeline;
+use proto::alerts_server::{Alerts, AlertsServer};
+use proto::preview_content_request::Source;
+use proto::{
+    segment, value_change, Change, FeedItem, GetChangeRequest, GetChangeResponse,
+    GetSnapshotRequest, GetSnapshotResponse, ListChangesRequest, ListChangesResponse,
+    ListSnapshotsRequest, ListSnapshotsResponse, PreviewContentRequest, PreviewContentResponse,
+    Segment, Snapshot, SnapshotContent, ValueChange,
+};
+
+pub mod proto {
+    tonic::include_proto!("webalert.alerts.v1");
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("alerts_descriptor");
+}
+
+/// The number of snapshots or changes returned by [Alerts.ListSnapshots] and
+/// [Alerts.ListChanges] when the client doesn't ask for a page size.
+const DEFAULT_PAGE_SIZE: i32 = 50;
+
+/// The maximum number of snapshots or changes returned by [Alerts.ListSnapshots] and
+/// [Alerts.ListChanges].
+const MAX_PAGE_SIZE: i32 = 1000;
+
+#[derive(Debug)]
+pub struct AlertService {
+    pool: DbPool,
+}
+
+impl AlertService {
+    /// Creates a new alert service that reads alerts from the database in `pool`.
+    pub fn new(pool: DbPool) -> Self {
+        AlertService { pool }
+    }
+
+    /// Returns an error if there's no alert with the given `alert_id`.
+    async fn require_alert(&self, alert_id: i32) -> Result<(), Status> {
+        let exists = database::alerts::exists(&self.pool, alert_id)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not look up alert");
+
+                Status::internal("could not look up alert")
+            })?;
+
+        if exists {
+            Ok(())
+        } else {
+            Err(Status::not_found("unknown alert"))
+        }
+    }
+
+    /// Returns the filter the content of the pages of the alert with the given `alert_id` is
+    /// prepared with.
+    async fn content_filter(&self, alert_id: i32) -> Result<Filter, Status> {
+        let internal = |err| {
+            error!(?err, "Could not look up alert");
+
+            Status::internal("could not look up alert")
+        };
+        let mut conn = self.pool.acquire().await.map_err(internal)?;
+
+        let rules = database::alerts::ignore_rules(&mut conn, alert_id)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::not_found("unknown alert"))?
+            .compile()
+            .map_err(|err| Status::failed_precondition(format!("invalid ignore rules: {}", err)))?;
+        let pipeline = database::normalization_profiles::for_alert(&mut conn, alert_id)
+            .await
+            .map_err(internal)?
+            .map(Pipeline::from)
+            .unwrap_or_default();
+        let format = database::alerts::format(&mut conn, alert_id)
+            .await
+            .map_err(internal)?
+            .unwrap_or_default();
+
+        Ok(Filter {
+            format,
+            pipeline,
+            rules,
+        })
+    }
+
+    /// Returns the stored content of the snapshot with the given `snapshot_id` of the alert with
+    /// the given `alert_id`.
+    async fn snapshot_content(
+        &self,
+        alert_id: i32,
+        snapshot_id: i32,
+    ) -> Result<SnapshotContent, Status> {
+        let snapshot = database::snapshots::find(&self.pool, snapshot_id)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not look up snapshot");
+
+                Status::internal("could not look up snapshot")
+            })?
+            .filter(|snapshot| snapshot.alert_id == alert_id)
+            .ok_or_else(|| Status::not_found("unknown snapshot"))?;
+
+        self.stored_content(&snapshot.content_hash).await
+    }
+
+    /// Returns the content stored under `content_hash`.
+    async fn stored_content(&self, content_hash: &str) -> Result<SnapshotContent, Status> {
+        let content = database::snapshots::content(&self.pool, content_hash)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not look up snapshot content");
+
+                Status::internal("could not look up snapshot content")
+            })?
+            .ok_or_else(|| Status::internal("snapshot content is missing"))?;
+
+        Ok(SnapshotContent {
+            text: content.text,
+            html: content.html,
+            screenshot: content.screenshot.unwrap_or_default(),
+        })
+    }
+}
+
+/// Converts the id of an alert, snapshot or change in a request to a database id.
+fn database_id(id: i64, what: &str) -> Result<i32, Status> {
+    i32::try_from(id).map_err(|_| Status::invalid_argument(format!("invalid {} id", what)))
+}
+
+/// Returns the page size and the id to continue listing before for a list request with the
+/// given `page_size` and `page_token`.
+fn page(page_size: i32, page_token: &str) -> Result<(i32, Option<i32>), Status> {
+    let page_size = match page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        size if size < 0 => return Err(Status::invalid_argument("negative page size")),
+        size => size.min(MAX_PAGE_SIZE),
+    };
+    let before_id = match non_empty(page_token) {
+        Some(token) => Some(
+            token
+                .parse::<i32>()
+                .map_err(|_| Status::invalid_argument("invalid page token"))?,
+        ),
+        None => None,
+    };
+
+    Ok((page_size, before_id))
+}
+
+/// Truncates `rows`, which were fetched with one row more than `page_size` to find out if
+/// there's another page, and returns the token for the next page, if there is one.
+fn next_page_token<T>(rows: &mut Vec<T>, page_size: i32, id: impl Fn(&T) -> i32) -> String {
+    if rows.len() > page_size as usize {
+        rows.truncate(page_size as usize);
+        rows.last()
+            .map(|row| id(row).to_string())
+            .unwrap_or_default()
+    } else {
+        String::new()
+    }
+}
+
+/// Converts a database snapshot to its protobuf representation.
+fn to_snapshot(snapshot: database::snapshots::Snapshot) -> Snapshot {
+    Snapshot {
+        id: snapshot.id.into(),
+        alert_id: snapshot.alert_id.into(),
+        content_hash: snapshot.content_hash,
+        final_url: snapshot.final_url,
+        http_status: snapshot.http_status.unwrap_or_default().max(0) as u32,
+        fetch_duration: snapshot.total_ms.map(|ms| prost_types::Duration {
+            seconds: ms / 1000,
+            nanos: (ms % 1000) as i32 * 1_000_000,
+        }),
+        checks: snapshot.checks.max(0) as u32,
+        first_seen_time: Some(to_timestamp(snapshot.first_seen_at)),
+        last_seen_time: Some(to_timestamp(snapshot.last_seen_at)),
+    }
+}
+
+/// Converts a database change to its protobuf representation.
+fn to_change(change: database::changes::Change) -> Change {
+    let segments = change
+        .segments
+        .0
+        .into_iter()
+        .map(|segment| {
+            let kind = match segment.kind {
+                SegmentKind::Unchanged => segment::Kind::Unchanged,
+                SegmentKind::Added => segment::Kind::Added,
+                SegmentKind::Removed => segment::Kind::Removed,
+            };
+
+            Segment {
+                kind: kind as i32,
+                text: segment.text,
+            }
+        })
+        .collect();
+
+    let value_changes = change
+        .value_changes
+        .map(|value_changes| value_changes.0)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|value_change| {
+            let kind = match value_change.kind {
+                ValueChangeKind::Added => value_change::Kind::Added,
+                ValueChangeKind::Removed => value_change::Kind::Removed,
+                ValueChangeKind::Changed => value_change::Kind::Changed,
+            };
+            let to_json = |value: Option<serde_json::Value>| {
+                value.map(|value| value.to_string()).unwrap_or_default()
+            };
+
+            ValueChange {
+                path: value_change.path,
+                kind: kind as i32,
+                old_value: to_json(value_change.old),
+                new_value: to_json(value_change.new),
+            }
+        })
+        .collect();
+
+    Change {
+        id: change.id.into(),
+        alert_id: change.alert_id.into(),
+        snapshot_id: change.snapshot_id.into(),
+        previous_snapshot_id: change.previous_snapshot_id.into(),
+        change_ratio: change.change_ratio,
+        lines_added: change.lines_added.max(0) as u32,
+        lines_removed: change.lines_removed.max(0) as u32,
+        unified_diff: change.unified_diff,
+        segments,
+        create_time: Some(to_timestamp(change.created_at)),
+        condition_met: change.condition_met.unwrap_or_default(),
+        triggered: change.triggered,
+        value_changes,
+        feed_item: change.feed_item.map(|Json(item)| FeedItem {
+            id: item.id,
+            title: item.title,
+            link: item.link,
+            summary: item.summary,
+            published: item.published.unwrap_or_default(),
+        }),
+    }
+}
+
+#[tonic::async_trait]
+impl Alerts for AlertService {
+    #[instrument(skip(self))]
+    async fn list_snapshots(
+        &self,
+        request: Request<ListSnapshotsRequest>,
+    ) -> Result<Response<ListSnapshotsResponse>, Status> {
+        let list_req = request.into_inner();
+        let alert_id = database_id(list_req.alert_id, "alert")?;
+        let (page_size, before_id) = page(list_req.page_size, &list_req.page_token)?;
+
+        self.require_alert(alert_id).await?;
+
+        // Fetch one snapshot more than requested to find out if there's another page
+        let mut snapshots =
+            database::snapshots::list(&self.pool, alert_id, before_id, i64::from(page_size) + 1)
+                .await
+                .map_err(|err| {
+                    error!(?err, "Could not list snapshots");
+
+                    Status::internal("could not list snapshots")
+                })?;
+
+        let next_page_token = next_page_token(&mut snapshots, page_size, |snapshot| snapshot.id);
+
+        Ok(Response::new(ListSnapshotsResponse {
+            snapshots: snapshots.into_iter().map(to_snapshot).collect(),
+            next_page_token,
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn get_snapshot(
+        &self,
+        request: Request<GetSnapshotRequest>,
+    ) -> Result<Response<GetSnapshotResponse>, Status> {
+        let snapshot_id = database_id(request.into_inner().snapshot_id, "snapshot")?;
+
+        let snapshot = database::snapshots::find(&self.pool, snapshot_id)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not look up snapshot");
+
+                Status::internal("could not look up snapshot")
+            })?
+            .ok_or_else(|| Status::not_found("unknown snapshot"))?;
+
+        let content = self.stored_content(&snapshot.content_hash).await?;
+
+        Ok(Response::new(GetSnapshotResponse {
+            snapshot: Some(to_snapshot(snapshot)),
+            content: Some(content),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn list_changes(
+        &self,
+        request: Request<ListChangesRequest>,
+    ) -> Result<Response<ListChangesResponse>, Status> {
+        let list_req = request.into_inner();
+        let alert_id = database_id(list_req.alert_id, "alert")?;
+        let (page_size, before_id) = page(list_req.page_size, &list_req.page_token)?;
+
+        self.require_alert(alert_id).await?;
+
+        // Fetch one change more than requested to find out if there's another page
+        let mut changes =
+            database::changes::list(&self.pool, alert_id, before_id, i64::from(page_size) + 1)
+                .await
+                .map_err(|err| {
+                    error!(?err, "Could not list changes");
+
+                    Status::internal("could not list changes")
+                })?;
+
+        let next_page_token = next_page_token(&mut changes, page_size, |change| change.id);
+
+        Ok(Response::new(ListChangesResponse {
+            changes: changes.into_iter().map(to_change).collect(),
+            next_page_token,
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn get_change(
+        &self,
+        request: Request<GetChangeRequest>,
+    ) -> Result<Response<GetChangeResponse>, Status> {
+        let change_id = database_id(request.into_inner().change_id, "change")?;
+
+        let change = database::changes::find(&self.pool, change_id)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not look up change");
+
+                Status::internal("could not look up change")
+            })?
+            .ok_or_else(|| Status::not_found("unknown change"))?;
+        let diff_image = database::changes::diff_image(&self.pool, change_id)
+            .await
+            .map_err(|err| {
+                error!(?err, "Could not look up diff image");
+
+                Status::internal("could not look up diff image")
+            })?;
+
+        Ok(Response::new(GetChangeResponse {
+            change: Some(to_change(change)),
+            diff_image: diff_image.unwrap_or_default(),
+        }))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn preview_content(
+        &self,
+        request: Request<PreviewContentRequest>,
+    ) -> Result<Response<PreviewContentResponse>, Status> {
+        let preview_req = request.into_inner();
+        let alert_id = database_id(preview_req.alert_id, "alert")?;
+        let filter = self.content_filter(alert_id).await?;
+
+        let content = match preview_req.source {
+            Some(Source::Content(content)) => content,
+            Some(Source::SnapshotId(snapshot_id)) => {
+                self.snapshot_content(alert_id, database_id(snapshot_id, "snapshot")?)
+                    .await?
+            }
+            None => return Err(Status::invalid_argument("missing content to preview")),
+        };
+
+        let prepared = filter.apply(&content.text, &content.html);
+
+        let screenshot = Some(&content.screenshot[..]).filter(|screenshot| !screenshot.is_empty());
+        let content_hash =
+            database::snapshots::content_hash(&prepared.text, &prepared.html, screenshot);
+
+        Ok(Response::new(PreviewContentResponse {
+            content_hash,
+            content: Some(SnapshotContent {
+                text: prepared.text,
+                html: prepared.html,
+                screenshot: content.screenshot,
+            }),
+        }))
+    }
+}
+
+/// Creates and returns the gRPC `Alerts` service.
+pub(crate) fn create_alerts_service(pool: DbPool) -> AlertsServer<AlertService> {
+    AlertsServer::new(AlertService::new(pool))
+}
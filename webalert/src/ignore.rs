@@ -0,0 +1,264 @@
+//! Rules that make change detection ignore the parts of a page that change on every check.
+//!
+//! Pages often carry timestamps, view counters or rotating ads inside the watched region. An alert
+//! can exclude such elements by CSS selector, mask text matching regexes, and drop whole lines of
+//! text matching regexes.
+
+use std::fmt;
+
+use regex::Regex;
+use scraper::{Html, Node, Selector};
+
+/// The text that the matches of masking patterns are replaced with.
+pub const MASK: &str = "[ignored]";
+
+/// The elements that start a new line in the text derived from HTML.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "br",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "tr",
+    "ul",
+];
+
+/// The elements whose text isn't shown on the page.
+const HIDDEN_ELEMENTS: &[&str] = &["noscript", "script", "style", "template"];
+
+/// Error returned when an ignore rule is invalid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The CSS selector of an exclusion can't be parsed.
+    InvalidSelector(String),
+    /// The regex of a masking pattern or line filter can't be parsed.
+    InvalidPattern(String, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidSelector(selector) => write!(f, "invalid selector {:?}", selector),
+            Error::InvalidPattern(pattern, err) => {
+                write!(f, "invalid pattern {:?}: {}", pattern, err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The compiled ignore rules of an alert.
+///
+/// The default rules don't ignore anything.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    /// The elements to remove.
+    selectors: Vec<Selector>,
+    /// The patterns whose matches are replaced with [`MASK`].
+    patterns: Vec<Regex>,
+    /// The patterns that remove every line of text they match.
+    lines: Vec<Regex>,
+}
+
+/// Compiles each of `patterns` as a regex.
+fn compile(patterns: &[String]) -> Result<Vec<Regex>, Error> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern)
+                .map_err(|err| Error::InvalidPattern(pattern.clone(), err.to_string()))
+        })
+        .collect()
+}
+
+/// Returns the visible text of a fragment of HTML, with block elements on lines of their own.
+pub fn text_of(html: &str) -> String {
+    let fragment = Html::parse_fragment(html);
+    let mut text = String::new();
+
+    for edge in fragment.root_element().traverse() {
+        let (node, opening) = match edge {
+            ego_tree::iter::Edge::Open(node) => (node, true),
+            ego_tree::iter::Edge::Close(node) => (node, false),
+        };
+
+        match node.value() {
+            Node::Text(contents) if opening => {
+                let hidden = node.ancestors().any(|ancestor| match ancestor.value() {
+                    Node::Element(element) => HIDDEN_ELEMENTS.contains(&element.name()),
+                    _ => false,
+                });
+
+                if !hidden {
+                    text.push_str(contents);
+                }
+            }
+            // Both the start and the end of a block element end the current line
+            Node::Element(element)
+                if BLOCK_ELEMENTS.contains(&element.name())
+                    && !text.is_empty()
+                    && !text.ends_with('\n') =>
+            {
+                text.push('\n');
+            }
+            _ => {}
+        }
+    }
+
+    text.trim_end_matches('\n').to_string()
+}
+
+impl Rules {
+    /// Compiles the given CSS `selectors` of elements to exclude, regex `patterns` to mask and
+    /// regex `lines` to drop.
+    pub fn new(selectors: &[String], patterns: &[String], lines: &[String]) -> Result<Self, Error> {
+        let selectors = selectors
+            .iter()
+            .map(|selector| {
+                Selector::parse(selector).map_err(|_| Error::InvalidSelector(selector.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Rules {
+            selectors,
+            patterns: compile(patterns)?,
+            lines: compile(lines)?,
+        })
+    }
+
+    /// Returns true if the rules exclude elements, in which case the text of a page has to be
+    /// derived from its HTML with [`text_of`] for the excluded elements to be left out of it.
+    pub fn excludes_elements(&self) -> bool {
+        !self.selectors.is_empty()
+    }
+
+    /// Returns true if the rules remove lines of text, which the HTML of a page still has.
+    pub fn filters_lines(&self) -> bool {
+        !self.lines.is_empty()
+    }
+
+    /// Removes the excluded elements from a fragment of HTML and masks it.
+    ///
+    /// Line filters only apply to text, as the lines of HTML don't follow the structure of the
+    /// page.
+    pub fn html(&self, html: &str) -> String {
+        let html = if self.excludes_elements() {
+            let mut fragment = Html::parse_fragment(html);
+            let excluded: Vec<_> = self
+                .selectors
+                .iter()
+                .flat_map(|selector| fragment.select(selector).map(|element| element.id()))
+                .collect();
+
+            for id in excluded {
+                if let Some(mut node) = fragment.tree.get_mut(id) {
+                    node.detach();
+                }
+            }
+
+            fragment.root_element().inner_html()
+        } else {
+            html.to_string()
+        };
+
+        self.mask(html)
+    }
+
+    /// Masks the text of a page and removes the lines matched by line filters.
+    pub fn text(&self, text: &str) -> String {
+        let text = self.mask(text.to_string());
+
+        if self.lines.is_empty() {
+            return text;
+        }
+
+        text.lines()
+            .filter(|line| !self.lines.iter().any(|filter| filter.is_match(line)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Replaces the matches of every masking pattern in `content` with [`MASK`].
+    fn mask(&self, content: String) -> String {
+        self.patterns.iter().fold(content, |content, pattern| {
+            pattern.replace_all(&content, MASK).into_owned()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> Rules {
+        Rules::new(
+            &[".ad".to_string(), "#views".to_string()],
+            &[r"\d{2}:\d{2}".to_string()],
+            &["^Updated".to_string()],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_should_exclude_elements_and_mask_html() {
+        let html = "<div><p>Open at 09:00</p><p class=\"ad\">Buy now</p>\
+                    <span id=\"views\">1234 views</span></div>";
+
+        assert_eq!(rules().html(html), "<div><p>Open at [ignored]</p></div>");
+    }
+
+    #[test]
+    fn it_should_mask_and_filter_text() {
+        assert_eq!(
+            rules().text("Open at 09:00\nUpdated 2 minutes ago\nClosed"),
+            "Open at [ignored]\nClosed"
+        );
+    }
+
+    #[test]
+    fn it_should_derive_text_from_html() {
+        assert_eq!(
+            text_of(
+                "<div><h1>Title</h1>Some <b>bold</b> text<script>x()</script><p>More</p></div>"
+            ),
+            "Title\nSome bold text\nMore"
+        );
+    }
+
+    #[test]
+    fn it_should_reject_invalid_rules() {
+        assert_eq!(
+            Rules::new(&["<".to_string()], &[], &[]).unwrap_err(),
+            Error::InvalidSelector("<".to_string())
+        );
+        assert!(matches!(
+            Rules::new(&[], &["(".to_string()], &[]),
+            Err(Error::InvalidPattern(..))
+        ));
+    }
+}
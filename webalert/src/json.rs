@@ -0,0 +1,265 @@
+//! Canonicalization and comparison of the content of JSON alerts.
+//!
+//! JSON alerts watch APIs rather than pages. Their content is a JSON document that's
+//! canonicalized before it's hashed, so the order of keys and formatting don't start new
+//! snapshots, and changes to it are described by the JSON pointers of the values that were added,
+//! removed or changed rather than by lines of text.
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+/// What happened to a value in a JSON document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueChangeKind {
+    /// The value is only in the new document.
+    Added,
+    /// The value is only in the old document.
+    Removed,
+    /// The value is in both documents, but differs.
+    Changed,
+}
+
+/// A value that was added, removed or changed between two versions of a JSON document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValueChange {
+    /// The JSON pointer to the value, e.g. `/releases/0/version`.
+    pub path: String,
+    pub kind: ValueChangeKind,
+    /// The old value, unless it was added.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "present"
+    )]
+    pub old: Option<Value>,
+    /// The new value, unless it was removed.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "present"
+    )]
+    pub new: Option<Value>,
+}
+
+/// Deserializes a value that's present, even if it's `null`, which would otherwise be taken for
+/// a missing value.
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+/// Returns `value` with the keys of every object sorted.
+pub fn canonicalize(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, canonicalize(value)))
+                    .collect::<Map<_, _>>(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonicalize).collect()),
+        value => value,
+    }
+}
+
+/// Parses `document` and returns it canonicalized, both compactly and pretty-printed.
+pub fn canonical(document: &str) -> Result<(String, String), serde_json::Error> {
+    let value = canonicalize(serde_json::from_str(document)?);
+
+    Ok((value.to_string(), serde_json::to_string_pretty(&value)?))
+}
+
+/// Returns `key` escaped for use as a token of a JSON pointer.
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Returns the number of scalar values in `value`, counting empty objects and arrays as one.
+fn leaves(value: &Value) -> usize {
+    match value {
+        Value::Object(object) if !object.is_empty() => object.values().map(leaves).sum(),
+        Value::Array(values) if !values.is_empty() => values.iter().map(leaves).sum(),
+        _ => 1,
+    }
+}
+
+/// Appends the differences between the `old` and `new` values at `path` to `changes`.
+fn diff_at(path: &str, old: &Value, new: &Value, changes: &mut Vec<ValueChange>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old {
+                let path = format!("{}/{}", path, escape(key));
+
+                match new.get(key) {
+                    Some(new_value) => diff_at(&path, old_value, new_value, changes),
+                    None => changes.push(ValueChange {
+                        path,
+                        kind: ValueChangeKind::Removed,
+                        old: Some(old_value.clone()),
+                        new: None,
+                    }),
+                }
+            }
+
+            for (key, new_value) in new {
+                if !old.contains_key(key) {
+                    changes.push(ValueChange {
+                        path: format!("{}/{}", path, escape(key)),
+                        kind: ValueChangeKind::Added,
+                        old: None,
+                        new: Some(new_value.clone()),
+                    });
+                }
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            for (index, old_value) in old.iter().enumerate() {
+                let path = format!("{}/{}", path, index);
+
+                match new.get(index) {
+                    Some(new_value) => diff_at(&path, old_value, new_value, changes),
+                    None => changes.push(ValueChange {
+                        path,
+                        kind: ValueChangeKind::Removed,
+                        old: Some(old_value.clone()),
+                        new: None,
+                    }),
+                }
+            }
+
+            for (index, new_value) in new.iter().enumerate().skip(old.len()) {
+                changes.push(ValueChange {
+                    path: format!("{}/{}", path, index),
+                    kind: ValueChangeKind::Added,
+                    old: None,
+                    new: Some(new_value.clone()),
+                });
+            }
+        }
+        (old, new) if old != new => changes.push(ValueChange {
+            path: path.to_string(),
+            kind: ValueChangeKind::Changed,
+            old: Some(old.clone()),
+            new: Some(new.clone()),
+        }),
+        _ => {}
+    }
+}
+
+/// Returns the values that were added, removed or changed between the `old` and `new` versions
+/// of a JSON document, in document order with removals before additions.
+///
+/// Arrays are compared by index, so inserting an element near the start of an array changes the
+/// elements after it.
+pub fn diff(old: &Value, new: &Value) -> Vec<ValueChange> {
+    let mut changes = Vec::new();
+
+    diff_at("", old, new, &mut changes);
+
+    changes
+}
+
+/// Returns the fraction of scalar values that differ between the `old` and `new` versions of a
+/// JSON document with the given `changes`.
+pub fn change_ratio(old: &Value, new: &Value, changes: &[ValueChange]) -> f64 {
+    // Values that are in both versions count once for each of them, like words in text do
+    let changed: usize = changes
+        .iter()
+        .map(|change| change.old.as_ref().map_or(0, leaves) + change.new.as_ref().map_or(0, leaves))
+        .sum();
+    let total = leaves(old) + leaves(new);
+
+    if total == 0 {
+        0.0
+    } else {
+        changed as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn it_should_canonicalize_documents() {
+        let (compact, pretty) =
+            canonical("{ \"b\": [1, {\"d\": 1, \"c\": 2}],\n \"a\": null }").unwrap();
+
+        assert_eq!(compact, "{\"a\":null,\"b\":[1,{\"c\":2,\"d\":1}]}");
+        assert_eq!(
+            canonical("{\"a\":null,\"b\":[1,{\"c\":2,\"d\":1}]}")
+                .unwrap()
+                .0,
+            compact
+        );
+        assert!(pretty.starts_with("{\n  \"a\": null,\n"));
+        assert!(canonical("<html>").is_err());
+    }
+
+    #[test]
+    fn it_should_diff_documents() {
+        let old = canonicalize(json!({"version": "1.0", "assets": ["a", "b"], "gone": true}));
+        let new = canonicalize(json!({"version": "1.1", "assets": ["a", "b", "c"], "new": {}}));
+
+        let changes = diff(&old, &new);
+
+        assert_eq!(
+            changes,
+            vec![
+                ValueChange {
+                    path: "/assets/2".to_string(),
+                    kind: ValueChangeKind::Added,
+                    old: None,
+                    new: Some(json!("c")),
+                },
+                ValueChange {
+                    path: "/gone".to_string(),
+                    kind: ValueChangeKind::Removed,
+                    old: Some(json!(true)),
+                    new: None,
+                },
+                ValueChange {
+                    path: "/version".to_string(),
+                    kind: ValueChangeKind::Changed,
+                    old: Some(json!("1.0")),
+                    new: Some(json!("1.1")),
+                },
+                ValueChange {
+                    path: "/new".to_string(),
+                    kind: ValueChangeKind::Added,
+                    old: None,
+                    new: Some(json!({})),
+                },
+            ]
+        );
+        // The changed version counts twice, and the three values added or removed once each
+        assert_eq!(change_ratio(&old, &new, &changes), 5.0 / 9.0);
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn it_should_keep_null_values() {
+        let changes = diff(&json!({"a": null}), &json!({}));
+        let stored = serde_json::to_string(&changes).unwrap();
+
+        assert_eq!(stored, r#"[{"path":"/a","kind":"removed","old":null}]"#);
+        assert_eq!(
+            serde_json::from_str::<Vec<ValueChange>>(&stored).unwrap(),
+            changes
+        );
+    }
+
+    #[test]
+    fn it_should_escape_pointers() {
+        let changes = diff(&json!({"a/b": {"c~d": 1}}), &json!({"a/b": {"c~d": 2}}));
+
+        assert_eq!(changes[0].path, "/a~1b/c~0d");
+    }
+}
@@ -1,3 +1,20 @@
+// `tonic::Status` is the error type of every gRPC handler, and it's large
+#![allow(clippy::result_large_err)]
+
 pub mod cli;
+pub mod condition;
+pub mod content;
 pub mod database;
+pub mod diff;
+pub mod dispatcher;
+pub mod feed;
 pub mod grpc;
+pub mod ignore;
+pub mod json;
+pub mod normalize;
+pub mod reaper;
+pub mod scheduler;
+pub mod scope;
+pub mod screenshot;
+pub mod shutdown;
+pub mod token;
@@ -1,24 +1,162 @@
 use std::env;
+use std::error::Error;
+use std::process;
+use std::time::Duration;
 
-use webalert::{cli, database, grpc};
+use chrono::{DateTime, Utc};
+use webalert::{
+    cli, database, database::tokens, dispatcher, grpc, reaper, scheduler, shutdown, token,
+};
 
 use structopt::StructOpt;
 use tokio::runtime::Runtime;
-use tracing::{debug, error};
+use tokio::sync::watch;
+use tracing::{debug, error, info};
 use tracing_subscriber::EnvFilter;
 
-async fn async_main(opts: cli::Opts) -> Result<(), Box<dyn std::error::Error>> {
+/// Generates a new token and returns it along with its salt and salted hash.
+fn generate_token() -> (String, Vec<u8>, Vec<u8>) {
+    let secret = token::generate();
+    let salt = token::generate_salt();
+    let hash = token::hash(&salt, &secret);
+
+    (secret, salt, hash)
+}
+
+/// Formats an optional timestamp for the token list.
+fn format_time(time: Option<DateTime<Utc>>) -> String {
+    time.map_or_else(|| "-".to_string(), |time| time.to_rfc3339())
+}
+
+async fn token_command(opts: &cli::TokenOpts) -> Result<(), Box<dyn Error>> {
+    debug!("Connecting to the database");
+    let pool = database::connect(opts.database_url.as_str()).await?;
+
+    match &opts.command {
+        cli::TokenCommand::Create {
+            name,
+            scopes,
+            expires_in_days,
+        } => {
+            let (secret, salt, hash) = generate_token();
+            let new_secret = tokens::NewSecret {
+                prefix: token::prefix(&secret),
+                salt: &salt,
+                secret_hash: &hash,
+            };
+            let expires_at =
+                expires_in_days.map(|days| Utc::now() + chrono::Duration::days(days.into()));
+
+            tokens::create(&pool, name, new_secret, scopes, expires_at).await?;
+
+            println!("{}", secret);
+        }
+        cli::TokenCommand::List => {
+            for token in tokens::list(&pool).await? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    token.id,
+                    token.name,
+                    token.prefix,
+                    token.scopes.join(","),
+                    token.created_at.to_rfc3339(),
+                    format_time(token.expires_at),
+                    format_time(token.revoked_at),
+                    format_time(token.last_used_at)
+                );
+            }
+        }
+        cli::TokenCommand::Revoke { name } => {
+            if !tokens::revoke(&pool, name).await? {
+                return Err(format!("no active token named {:?}", name).into());
+            }
+        }
+        cli::TokenCommand::Rotate { name } => {
+            let (secret, salt, hash) = generate_token();
+            let new_secret = tokens::NewSecret {
+                prefix: token::prefix(&secret),
+                salt: &salt,
+                secret_hash: &hash,
+            };
+
+            if tokens::rotate(&pool, name, new_secret).await?.is_none() {
+                return Err(format!("no active token named {:?}", name).into());
+            }
+
+            println!("{}", secret);
+        }
+    }
+
+    pool.close().await;
+
+    Ok(())
+}
+
+async fn async_main(opts: cli::Opts) -> Result<(), Box<dyn Error>> {
     match &opts.command {
         cli::Command::Server(ref server_opts) => {
             // Connect to the PostgreSQL database
             debug!("Connecting to the database");
             let pool = database::connect(server_opts.database_url.as_str()).await?;
 
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+            // Check for dead runners a few times within the time it takes to declare one dead
+            let dead_after = Duration::from_secs(server_opts.runner_dead_after_secs);
+            let reaper = tokio::spawn(reaper::run(
+                pool.clone(),
+                dead_after,
+                (dead_after / 4).max(Duration::from_secs(1)),
+                server_opts.task_max_attempts,
+                shutdown_rx.clone(),
+            ));
+
+            // Wake up the streams waiting for tasks whenever the task queue changes
+            let (task_queue_tx, task_queue_rx) = watch::channel(());
+            let dispatcher = tokio::spawn(dispatcher::run(
+                pool.clone(),
+                task_queue_tx,
+                shutdown_rx.clone(),
+            ));
+
+            let scheduler = tokio::spawn(scheduler::run(
+                pool.clone(),
+                Duration::from_secs(server_opts.scheduler_interval_secs),
+                shutdown_rx.clone(),
+            ));
+
             debug!("Starting server");
-            let grpc_server = grpc::start_server(server_opts, pool.clone());
+            let grpc_server =
+                grpc::start_server(server_opts, pool.clone(), task_queue_rx, shutdown_rx);
+            tokio::pin!(grpc_server);
+
+            let result = tokio::select! {
+                result = &mut grpc_server => result,
+                signal = shutdown::wait_for_signal() => {
+                    match signal {
+                        Ok(()) => {
+                            info!("Shutting down");
+                            let _ = shutdown_tx.send(true);
+                        }
+                        Err(err) => error!(?err, "Could not listen for shutdown signals"),
+                    }
+
+                    grpc_server.await
+                }
+            };
 
-            tokio::join!(grpc_server);
+            // Stop the background tasks if the server stopped on its own
+            let _ = shutdown_tx.send(true);
+            reaper.await?;
+            scheduler.await?;
+            dispatcher.await?;
+
+            debug!("Closing database connections");
+            pool.close().await;
+
+            result?;
         }
+        cli::Command::Token(ref token_opts) => token_command(token_opts).await?,
     }
 
     Ok(())
@@ -30,8 +168,10 @@ fn main() {
         env::set_var("RUST_LOG", "webalert=trace,tower_http=trace");
     }
 
+    // Log to stderr so the output of commands can be used in scripts
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
         .init();
 
     // Set up the async runtime
@@ -41,5 +181,7 @@ fn main() {
 
     if let Err(err) = rt.block_on(async_main(opts)) {
         error!("runtime error: {}", err);
+
+        process::exit(1);
     }
 }
@@ -0,0 +1,301 @@
+//! Normalization of the content of pages before it's hashed and compared.
+//!
+//! Pages often differ between checks in ways nobody cares about: whitespace is reflowed,
+//! attributes are reordered, inline scripts embed nonces and URLs carry cache-busting query
+//! parameters. Running the content through a [`Pipeline`] first means that only meaningful
+//! differences start a new snapshot.
+
+use html5ever::serialize::{serialize, SerializeOpts, TraversalScope};
+use html5ever::tendril::{StrTendril, TendrilSink};
+use html5ever::{local_name, namespace_url, ns, Attribute, ParseOpts, QualName};
+use markup5ever_rcdom::{Handle, NodeData, RcDom, SerializableHandle};
+
+/// The attributes whose values are URLs that query parameters are dropped from.
+const URL_ATTRIBUTES: &[&str] = &[
+    "action",
+    "cite",
+    "data",
+    "formaction",
+    "href",
+    "poster",
+    "src",
+];
+
+/// The elements whose whitespace is significant.
+const PREFORMATTED_ELEMENTS: &[&str] = &["listing", "pre", "textarea"];
+
+/// The steps content is normalized with.
+///
+/// The default pipeline leaves content as it is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pipeline {
+    /// Collapse runs of whitespace into a single space, except inside `<pre>` and `<textarea>`.
+    pub collapse_whitespace: bool,
+    /// Remove `<script>` elements.
+    pub strip_scripts: bool,
+    /// Remove `<style>` elements.
+    pub strip_styles: bool,
+    /// Remove comments.
+    pub strip_comments: bool,
+    /// Sort the attributes of elements by name.
+    pub sort_attributes: bool,
+    /// The names of attributes to remove. Names ending in `*` match any attribute starting with
+    /// the rest of the name.
+    pub dropped_attributes: Vec<String>,
+    /// The names of query parameters to remove from URLs in attributes like `href` and `src`.
+    /// Names ending in `*` match any parameter starting with the rest of the name.
+    pub dropped_query_params: Vec<String>,
+}
+
+/// Returns true if `name` matches `pattern`, which matches by prefix if it ends in `*`.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+/// Returns `text` with every run of whitespace replaced by a single space.
+fn collapse(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut in_whitespace = false;
+
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !in_whitespace {
+                collapsed.push(' ');
+            }
+
+            in_whitespace = true;
+        } else {
+            collapsed.push(c);
+            in_whitespace = false;
+        }
+    }
+
+    collapsed
+}
+
+/// Returns `url` without the query parameters whose names match any of `patterns`.
+///
+/// Works on relative URLs as well, and leaves the rest of the URL untouched.
+pub fn strip_query_params(url: &str, patterns: &[String]) -> String {
+    let (url, fragment) = match url.find('#') {
+        Some(pos) => url.split_at(pos),
+        None => (url, ""),
+    };
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => return format!("{}{}", url, fragment),
+    };
+
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or_default();
+
+            !patterns.iter().any(|pattern| matches(pattern, name))
+        })
+        .collect();
+
+    if kept.is_empty() {
+        format!("{}{}", path, fragment)
+    } else {
+        format!("{}?{}{}", path, kept.join("&"), fragment)
+    }
+}
+
+impl Pipeline {
+    /// Returns true if the pipeline leaves content as it is.
+    pub fn is_noop(&self) -> bool {
+        *self == Pipeline::default()
+    }
+
+    /// Normalizes a fragment of HTML.
+    pub fn html(&self, html: &str) -> String {
+        if self.is_noop() {
+            return html.to_string();
+        }
+
+        let dom = html5ever::parse_fragment(
+            RcDom::default(),
+            ParseOpts::default(),
+            QualName::new(None, ns!(html), local_name!("body")),
+            vec![],
+        )
+        .one(html);
+
+        // The fragment is parsed into the children of an `<html>` element in the document
+        let root = match dom.document.children.borrow().first() {
+            Some(root) => root.clone(),
+            None => return String::new(),
+        };
+
+        self.normalize_children(&root, false);
+
+        let mut serialized = Vec::new();
+        let opts = SerializeOpts {
+            traversal_scope: TraversalScope::ChildrenOnly(None),
+            ..Default::default()
+        };
+
+        // Writing to a `Vec` can't fail
+        serialize(&mut serialized, &SerializableHandle::from(root), opts)
+            .expect("could not serialize html");
+
+        let html = String::from_utf8_lossy(&serialized);
+
+        if self.collapse_whitespace {
+            html.trim().to_string()
+        } else {
+            html.into_owned()
+        }
+    }
+
+    /// Normalizes the text of a page.
+    ///
+    /// Only whitespace is normalized, with every line trimmed and collapsed and blank lines
+    /// removed, as text doesn't contain markup.
+    pub fn text(&self, text: &str) -> String {
+        if !self.collapse_whitespace {
+            return text.to_string();
+        }
+
+        text.lines()
+            .map(|line| collapse(line.trim()))
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Returns true if `node` should be removed from the document.
+    fn is_stripped(&self, node: &Handle) -> bool {
+        match &node.data {
+            NodeData::Element { name, .. } => {
+                (self.strip_scripts && name.local == local_name!("script"))
+                    || (self.strip_styles && name.local == local_name!("style"))
+            }
+            NodeData::Comment { .. } => self.strip_comments,
+            _ => false,
+        }
+    }
+
+    /// Normalizes the children of `node`, which is inside a preformatted element if
+    /// `preformatted` is true.
+    fn normalize_children(&self, node: &Handle, preformatted: bool) {
+        let children = node.children.take();
+        let mut kept: Vec<Handle> = Vec::with_capacity(children.len());
+
+        for child in children {
+            if self.is_stripped(&child) {
+                continue;
+            }
+
+            if let NodeData::Element { name, attrs, .. } = &child.data {
+                self.normalize_attributes(&mut attrs.borrow_mut());
+
+                let preformatted =
+                    preformatted || PREFORMATTED_ELEMENTS.contains(&name.local.as_ref());
+
+                self.normalize_children(&child, preformatted);
+            }
+
+            // Merge text that's adjacent after removing the nodes between it
+            if let (NodeData::Text { contents }, Some(last)) = (&child.data, kept.last()) {
+                if let NodeData::Text {
+                    contents: last_contents,
+                } = &last.data
+                {
+                    last_contents.borrow_mut().push_tendril(&contents.borrow());
+
+                    continue;
+                }
+            }
+
+            kept.push(child);
+        }
+
+        if self.collapse_whitespace && !preformatted {
+            for child in &kept {
+                if let NodeData::Text { contents } = &child.data {
+                    let collapsed = collapse(&contents.borrow());
+
+                    *contents.borrow_mut() = StrTendril::from(collapsed);
+                }
+            }
+        }
+
+        *node.children.borrow_mut() = kept;
+    }
+
+    /// Drops, rewrites and sorts `attrs` of an element.
+    fn normalize_attributes(&self, attrs: &mut Vec<Attribute>) {
+        attrs.retain(|attr| {
+            !self
+                .dropped_attributes
+                .iter()
+                .any(|pattern| matches(pattern, &attr.name.local))
+        });
+
+        if !self.dropped_query_params.is_empty() {
+            for attr in attrs.iter_mut() {
+                if URL_ATTRIBUTES.contains(&attr.name.local.as_ref()) {
+                    let url = strip_query_params(&attr.value, &self.dropped_query_params);
+
+                    attr.value = StrTendril::from(url);
+                }
+            }
+        }
+
+        if self.sort_attributes {
+            attrs.sort_by(|a, b| a.name.local.cmp(&b.name.local));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline() -> Pipeline {
+        Pipeline {
+            collapse_whitespace: true,
+            strip_scripts: true,
+            strip_styles: true,
+            strip_comments: true,
+            sort_attributes: true,
+            dropped_attributes: vec!["nonce".to_string(), "data-csrf*".to_string()],
+            dropped_query_params: vec!["v".to_string(), "utm_*".to_string()],
+        }
+    }
+
+    #[test]
+    fn it_should_normalize_html() {
+        let html = "<div  id=\"a\" class=\"b\" data-csrf-token=\"x\">\n  Hello\n  <script \
+                    nonce=\"1\">track()</script> <style>p {}</style>\n  <!-- 12ms -->\n  \
+                    <a href=\"/p?id=1&amp;v=2&amp;utm_source=feed#top\">world</a>\
+                    <pre>  kept  </pre></div>";
+
+        assert_eq!(
+            pipeline().html(html),
+            "<div class=\"b\" id=\"a\"> Hello <a href=\"/p?id=1#top\">world</a>\
+             <pre>  kept  </pre></div>"
+        );
+    }
+
+    #[test]
+    fn it_should_normalize_text() {
+        assert_eq!(
+            pipeline().text("  Price:\t10 EUR \n\n  In   stock\n"),
+            "Price: 10 EUR\nIn stock"
+        );
+    }
+
+    #[test]
+    fn it_should_leave_content_alone_by_default() {
+        let html = "<p b=\"1\" a=\"2\">  x  </p>";
+
+        assert_eq!(Pipeline::default().html(html), html);
+        assert_eq!(Pipeline::default().text("  x  "), "  x  ");
+    }
+}
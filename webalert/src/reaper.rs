@@ -0,0 +1,60 @@
+//! Background task that declares runners dead once they stop sending heartbeats, and hands out
+//! their tasks again along with tasks whose leases have expired.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::sync::watch;
+use tracing::{debug, error, instrument, warn};
+
+use crate::database::{self, DbPool};
+use crate::shutdown;
+
+/// Periodically declares runners that haven't been seen for `dead_after` dead, checking every
+/// `interval` until `shutdown` is set to `true`.
+///
+/// Tasks leased by dead runners and tasks whose leases have expired are handed out again, unless
+/// they have been attempted `task_max_attempts` times.
+#[instrument(skip(pool, shutdown))]
+pub async fn run(
+    pool: DbPool,
+    dead_after: Duration,
+    interval: Duration,
+    task_max_attempts: i32,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let dead_after =
+        chrono::Duration::from_std(dead_after).unwrap_or_else(|_| chrono::Duration::max_value());
+    let mut interval = tokio::time::interval(interval);
+
+    debug!("Starting runner reaper");
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown::requested(&mut shutdown) => break,
+        }
+
+        match database::runners::reap(&pool, Utc::now() - dead_after).await {
+            Ok(runner_ids) if runner_ids.is_empty() => {}
+            Ok(runner_ids) => {
+                warn!(?runner_ids, "Declared unresponsive runners dead");
+
+                match database::tasks::release(&pool, &runner_ids, task_max_attempts).await {
+                    Ok(task_ids) if task_ids.is_empty() => {}
+                    Ok(task_ids) => debug!(?task_ids, "Released tasks of dead runners"),
+                    Err(err) => error!(?err, "Could not release tasks of dead runners"),
+                }
+            }
+            Err(err) => error!(?err, "Could not reap dead runners"),
+        }
+
+        match database::tasks::requeue_expired(&pool, task_max_attempts).await {
+            Ok(task_ids) if task_ids.is_empty() => {}
+            Ok(task_ids) => warn!(?task_ids, "Task leases expired"),
+            Err(err) => error!(?err, "Could not requeue tasks with expired leases"),
+        }
+    }
+
+    debug!("Stopped runner reaper");
+}
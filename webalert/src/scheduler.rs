@@ -0,0 +1,52 @@
+//! Background task that turns alerts into tasks for runners once their checks are due.
+
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tracing::{debug, error, instrument};
+
+use crate::database::{self, DbPool};
+use crate::shutdown;
+
+/// The maximum number of tasks enqueued in one go.
+const BATCH_SIZE: i64 = 100;
+
+/// Enqueues a task for every alert whose check is due, checking every `interval` until `shutdown`
+/// is set to `true`.
+///
+/// The schedule is kept in the database, so checks that became due while the server was down are
+/// enqueued once it's back up.
+#[instrument(skip(pool, shutdown))]
+pub async fn run(pool: DbPool, interval: Duration, mut shutdown: watch::Receiver<bool>) {
+    let mut interval = tokio::time::interval(interval);
+
+    debug!("Starting alert scheduler");
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown::requested(&mut shutdown) => break,
+        }
+
+        // Keep going while there are more due alerts than fit in a batch
+        loop {
+            match database::tasks::enqueue_due(&pool, BATCH_SIZE).await {
+                Ok(task_ids) => {
+                    if !task_ids.is_empty() {
+                        debug!(?task_ids, "Enqueued due alert checks");
+                    }
+
+                    if (task_ids.len() as i64) < BATCH_SIZE {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    error!(?err, "Could not enqueue due alert checks");
+                    break;
+                }
+            }
+        }
+    }
+
+    debug!("Stopped alert scheduler");
+}
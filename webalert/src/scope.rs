@@ -0,0 +1,110 @@
+//! Scopes that limit what a token is allowed to do.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// A permission granted to a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// Allows a runner to announce itself to the server.
+    RunnerAnnounce,
+    /// Allows a runner to receive tasks from the server.
+    RunnerPoll,
+    /// Allows listing the runners known to the server.
+    RunnersRead,
+    /// Allows reading alerts and what their pages looked like over time.
+    AlertsRead,
+    /// Allows creating, changing and removing alerts.
+    AlertsWrite,
+    /// Allows everything.
+    Admin,
+}
+
+impl Scope {
+    /// All the known scopes.
+    pub const ALL: [Scope; 6] = [
+        Scope::RunnerAnnounce,
+        Scope::RunnerPoll,
+        Scope::RunnersRead,
+        Scope::AlertsRead,
+        Scope::AlertsWrite,
+        Scope::Admin,
+    ];
+
+    /// Returns the name of the scope as it's stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::RunnerAnnounce => "runner:announce",
+            Scope::RunnerPoll => "runner:poll",
+            Scope::RunnersRead => "runners:read",
+            Scope::AlertsRead => "alerts:read",
+            Scope::AlertsWrite => "alerts:write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    /// Returns true if holding this scope grants `other`.
+    pub fn grants(&self, other: Scope) -> bool {
+        *self == Scope::Admin || *self == other
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error returned when parsing an unknown scope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownScope(pub String);
+
+impl fmt::Display for UnknownScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown scope {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownScope {}
+
+impl FromStr for Scope {
+    type Err = UnknownScope;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .iter()
+            .copied()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| UnknownScope(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_round_trip_names() {
+        for scope in Scope::ALL.iter() {
+            assert_eq!(scope.as_str().parse::<Scope>(), Ok(*scope));
+        }
+    }
+
+    #[test]
+    fn it_should_reject_unknown_scopes() {
+        assert_eq!(
+            "runner:*".parse::<Scope>(),
+            Err(UnknownScope("runner:*".to_string()))
+        );
+    }
+
+    #[test]
+    fn admin_should_grant_everything() {
+        for scope in Scope::ALL.iter() {
+            assert!(Scope::Admin.grants(*scope));
+        }
+
+        assert!(!Scope::RunnerPoll.grants(Scope::RunnersRead));
+        assert!(Scope::RunnerPoll.grants(Scope::RunnerPoll));
+    }
+}
@@ -0,0 +1,371 @@
+//! Comparing the screenshots of screenshot alerts.
+//!
+//! Screenshot alerts watch pages whose changes don't show up in their text, like pages drawn on a
+//! canvas. Runners send a PNG screenshot of the page with every result, and the server compares
+//! each new screenshot pixel by pixel to a baseline: the first screenshot, until a screenshot
+//! differs enough from it to be a change and takes its place.
+//!
+//! The comparison is perceptual: pixels are compared by how different their colors look, in the
+//! YIQ color space, rather than by their exact values, so rendering noise like anti-aliasing
+//! doesn't count as a change unless it's beyond the pixel threshold of the alert.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The maximum number of bytes a decoded screenshot can take up.
+const MAX_DECODED_SIZE: usize = 256 * 1024 * 1024;
+
+/// The largest possible difference between two colors in the YIQ color space.
+const MAX_DELTA: f64 = 35215.0;
+
+/// The opacity of the new screenshot behind the highlighted pixels of a diff image.
+const BACKGROUND_ALPHA: f64 = 0.1;
+
+/// The color of the pixels that differ in a diff image.
+const CHANGED_COLOR: [u8; 4] = [255, 0, 0, 255];
+
+/// Error returned when screenshots can't be compared.
+#[derive(Debug)]
+pub enum Error {
+    /// A screenshot isn't a valid PNG image.
+    Decode(png::DecodingError),
+    /// The diff image couldn't be encoded.
+    Encode(png::EncodingError),
+    /// The diff image of screenshots of these sizes, as wide and as tall as the largest of them,
+    /// would take up more than [`MAX_DECODED_SIZE`] bytes.
+    TooLarge { width: u32, height: u32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Decode(err) => write!(f, "invalid screenshot: {}", err),
+            Error::Encode(err) => write!(f, "could not encode diff image: {}", err),
+            Error::TooLarge { width, height } => {
+                write!(f, "diff image of {}x{} pixels is too large", width, height)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A rectangle of screenshots that isn't compared, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// Returns true if the pixel at `x`, `y` is inside the region.
+    fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x
+            && y >= self.y
+            && u64::from(x) < u64::from(self.x) + u64::from(self.width)
+            && u64::from(y) < u64::from(self.y) + u64::from(self.height)
+    }
+}
+
+/// How screenshots are compared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// How different the colors of a pixel must look, from 0 to 1, for the pixel to differ.
+    pub pixel_threshold: f64,
+    /// The rectangles that aren't compared.
+    pub ignore_regions: Vec<Region>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            pixel_threshold: 0.1,
+            ignore_regions: Vec::new(),
+        }
+    }
+}
+
+/// The outcome of comparing two screenshots.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    /// The number of pixels that differ.
+    pub pixels_changed: u64,
+    /// The number of pixels that were compared, which excludes the ignored regions.
+    pub pixels_compared: u64,
+    /// A PNG image of the new screenshot, faded, with the pixels that differ highlighted.
+    pub diff_image: Vec<u8>,
+}
+
+impl Comparison {
+    /// Returns the fraction of the compared pixels that differ.
+    pub fn change_ratio(&self) -> f64 {
+        if self.pixels_compared == 0 {
+            0.0
+        } else {
+            self.pixels_changed as f64 / self.pixels_compared as f64
+        }
+    }
+}
+
+/// A decoded image with 8-bit RGBA pixels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Returns the pixel at `x`, `y`, if it's inside the image.
+    fn pixel(&self, x: u32, y: u32) -> Option<&[u8]> {
+        if x < self.width && y < self.height {
+            let start = (y as usize * self.width as usize + x as usize) * 4;
+
+            Some(&self.pixels[start..start + 4])
+        } else {
+            None
+        }
+    }
+}
+
+/// Decodes the PNG image `data` to RGBA pixels.
+fn decode(data: &[u8]) -> Result<Image, Error> {
+    let limits = png::Limits {
+        bytes: MAX_DECODED_SIZE,
+    };
+    let (info, mut reader) = png::Decoder::new_with_limits(data, limits)
+        .read_info()
+        .map_err(Error::Decode)?;
+    let mut buffer = vec![0; info.buffer_size()];
+
+    reader.next_frame(&mut buffer).map_err(Error::Decode)?;
+
+    // Decoding expands palettes and strips 16-bit samples, so only the channels can differ
+    let (color_type, _) = reader.output_color_type();
+    let pixels = match color_type {
+        png::ColorType::RGBA => buffer,
+        png::ColorType::RGB => buffer
+            .chunks_exact(3)
+            .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buffer
+            .chunks_exact(2)
+            .flat_map(|ga| [ga[0], ga[0], ga[0], ga[1]])
+            .collect(),
+        png::ColorType::Grayscale | png::ColorType::Indexed => {
+            buffer.iter().flat_map(|&g| [g, g, g, 255]).collect()
+        }
+    };
+
+    Ok(Image {
+        width: info.width,
+        height: info.height,
+        pixels,
+    })
+}
+
+/// Encodes `image` as a PNG image.
+fn encode(image: &Image) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, image.width, image.height);
+
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&image.pixels))
+        .map_err(Error::Encode)?;
+
+    Ok(data)
+}
+
+/// Returns the YIQ components of `pixel` blended onto a white background.
+fn yiq(pixel: &[u8]) -> (f64, f64, f64) {
+    let alpha = f64::from(pixel[3]) / 255.0;
+    let blend = |channel: u8| 255.0 + (f64::from(channel) - 255.0) * alpha;
+    let (r, g, b) = (blend(pixel[0]), blend(pixel[1]), blend(pixel[2]));
+
+    (
+        r * 0.298_895_31 + g * 0.586_622_47 + b * 0.114_482_23,
+        r * 0.595_977_99 - g * 0.274_176_10 - b * 0.321_801_89,
+        r * 0.211_470_17 - g * 0.522_617_11 + b * 0.311_146_94,
+    )
+}
+
+/// Returns how different the colors of pixels `a` and `b` look, from 0 to [`MAX_DELTA`].
+fn delta(a: &[u8], b: &[u8]) -> f64 {
+    let (y1, i1, q1) = yiq(a);
+    let (y2, i2, q2) = yiq(b);
+
+    0.5053 * (y1 - y2).powi(2) + 0.299 * (i1 - i2).powi(2) + 0.1957 * (q1 - q2).powi(2)
+}
+
+/// Returns `pixel` as a faded gray, for the background of a diff image.
+fn faded(pixel: &[u8]) -> [u8; 4] {
+    let (y, _, _) = yiq(pixel);
+    let gray = (255.0 + (y - 255.0) * BACKGROUND_ALPHA).round() as u8;
+
+    [gray, gray, gray, 255]
+}
+
+/// Compares the `old` and `new` PNG screenshots.
+///
+/// Screenshots of different sizes are compared as if they were both as large as the largest of
+/// them, with every pixel that only one or neither of them has counting as changed. The diff image
+/// is as large too, so it's subject to the same limit as the screenshots.
+pub fn compare(old: &[u8], new: &[u8], options: &Options) -> Result<Comparison, Error> {
+    let old = decode(old)?;
+    let new = decode(new)?;
+    let width = old.width.max(new.width);
+    let height = old.height.max(new.height);
+
+    if u64::from(width) * u64::from(height) * 4 > MAX_DECODED_SIZE as u64 {
+        return Err(Error::TooLarge { width, height });
+    }
+
+    let max_delta = MAX_DELTA * options.pixel_threshold.powi(2);
+
+    let mut diff = Image {
+        width,
+        height,
+        pixels: Vec::with_capacity(width as usize * height as usize * 4),
+    };
+    let mut pixels_changed = 0;
+    let mut pixels_compared = 0;
+
+    for y in 0..height {
+        for x in 0..width {
+            let (old_pixel, new_pixel) = (old.pixel(x, y), new.pixel(x, y));
+            let background = new_pixel.or(old_pixel).map_or([255; 4], faded);
+
+            if options.ignore_regions.iter().any(|r| r.contains(x, y)) {
+                diff.pixels.extend_from_slice(&background);
+
+                continue;
+            }
+
+            let changed = match (old_pixel, new_pixel) {
+                (Some(old_pixel), Some(new_pixel)) => delta(old_pixel, new_pixel) > max_delta,
+                _ => true,
+            };
+
+            pixels_compared += 1;
+
+            if changed {
+                pixels_changed += 1;
+                diff.pixels.extend_from_slice(&CHANGED_COLOR);
+            } else {
+                diff.pixels.extend_from_slice(&background);
+            }
+        }
+    }
+
+    Ok(Comparison {
+        pixels_changed,
+        pixels_compared,
+        diff_image: encode(&diff)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a PNG image of the given size filled with `color`, with the pixels at `dots` set to
+    /// `dot_color`.
+    fn screenshot(
+        width: u32,
+        height: u32,
+        color: [u8; 4],
+        dots: &[(u32, u32)],
+        dot_color: [u8; 4],
+    ) -> Vec<u8> {
+        let mut image = Image {
+            width,
+            height,
+            pixels: color.repeat(width as usize * height as usize),
+        };
+
+        for &(x, y) in dots {
+            let start = (y * width + x) as usize * 4;
+            image.pixels[start..start + 4].copy_from_slice(&dot_color);
+        }
+
+        encode(&image).unwrap()
+    }
+
+    #[test]
+    fn it_should_count_changed_pixels() {
+        let white = [255, 255, 255, 255];
+        let old = screenshot(10, 10, white, &[], white);
+        let new = screenshot(10, 10, white, &[(1, 1), (8, 2)], [0, 0, 0, 255]);
+
+        let comparison = compare(&old, &new, &Options::default()).unwrap();
+
+        assert_eq!(comparison.pixels_changed, 2);
+        assert_eq!(comparison.pixels_compared, 100);
+        assert_eq!(comparison.change_ratio(), 0.02);
+
+        let diff = decode(&comparison.diff_image).unwrap();
+
+        assert_eq!(diff.pixel(1, 1), Some(&CHANGED_COLOR[..]));
+        assert_eq!(diff.pixel(0, 0), Some(&[255, 255, 255, 255][..]));
+    }
+
+    #[test]
+    fn it_should_tolerate_small_color_differences() {
+        let old = screenshot(4, 4, [200, 200, 200, 255], &[], [0; 4]);
+        let new = screenshot(4, 4, [203, 200, 198, 255], &[], [0; 4]);
+
+        let comparison = compare(&old, &new, &Options::default()).unwrap();
+        assert_eq!(comparison.pixels_changed, 0);
+
+        let strict = Options {
+            pixel_threshold: 0.0,
+            ..Default::default()
+        };
+        let comparison = compare(&old, &new, &strict).unwrap();
+        assert_eq!(comparison.pixels_changed, 16);
+    }
+
+    #[test]
+    fn it_should_skip_ignored_regions_and_count_resized_areas() {
+        let white = [255, 255, 255, 255];
+        let old = screenshot(4, 4, white, &[], white);
+        let new = screenshot(4, 5, white, &[(0, 0), (1, 1)], [0, 0, 0, 255]);
+        let options = Options {
+            ignore_regions: vec![Region {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1,
+            }],
+            ..Default::default()
+        };
+
+        let comparison = compare(&old, &new, &options).unwrap();
+
+        // The dot at 1,1 and the row the new screenshot added
+        assert_eq!(comparison.pixels_changed, 5);
+        assert_eq!(comparison.pixels_compared, 19);
+        assert!(compare(b"not a png", &new, &options).is_err());
+    }
+
+    #[test]
+    fn it_should_reject_diff_images_beyond_the_size_limit() {
+        let white = [255, 255, 255, 255];
+        let wide = screenshot(10_000, 1, white, &[], white);
+        let tall = screenshot(1, 10_000, white, &[], white);
+
+        assert!(matches!(
+            compare(&wide, &tall, &Options::default()),
+            Err(Error::TooLarge {
+                width: 10_000,
+                height: 10_000
+            })
+        ));
+    }
+}
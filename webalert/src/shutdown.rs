@@ -0,0 +1,36 @@
+//! Graceful shutdown of the server.
+//!
+//! Long-running parts of the server hold a [`watch::Receiver`] that is set to `true` once the
+//! server should shut down, and use [`requested`] to find out when that happens.
+
+use std::io;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::info;
+
+/// Waits for the process to receive either SIGTERM or SIGINT.
+pub async fn wait_for_signal() -> io::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            info!("Received SIGINT");
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves once `shutdown` has been set to `true`.
+///
+/// Never resolves if the sender has been dropped without requesting shutdown.
+pub async fn requested(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
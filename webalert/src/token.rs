@@ -0,0 +1,122 @@
+//! Generation and verification of secret access tokens.
+//!
+//! A token is a random alphanumeric string whose first [`PREFIX_LENGTH`] characters are its public
+//! prefix. The prefix is stored in plain text so a presented token can be looked up, while the
+//! token itself is only ever stored as a salted SHA-256 hash.
+//!
+//! A fast hash is sufficient here since the tokens are long and random, unlike passwords.
+
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+
+/// The number of characters in a generated token.
+pub const TOKEN_LENGTH: usize = 48;
+
+/// The number of leading characters of a token that make up its public prefix.
+pub const PREFIX_LENGTH: usize = 8;
+
+/// The number of bytes in a salt.
+pub const SALT_LENGTH: usize = 16;
+
+/// Generates a new cryptographically random token.
+pub fn generate() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Generates a new random salt.
+pub fn generate_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; SALT_LENGTH];
+    rand::thread_rng().fill(&mut salt[..]);
+
+    salt
+}
+
+/// Returns the public prefix of `token`.
+///
+/// Tokens that are shorter than [`PREFIX_LENGTH`] are their own prefix.
+pub fn prefix(token: &str) -> &str {
+    match token.char_indices().nth(PREFIX_LENGTH) {
+        Some((end, _)) => &token[..end],
+        None => token,
+    }
+}
+
+/// Returns the salted hash of `token`.
+pub fn hash(salt: &[u8], token: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(token.as_bytes());
+
+    hasher.finalize().to_vec()
+}
+
+/// Returns true if `token` matches the salted hash `expected`.
+///
+/// The comparison is done in constant time to avoid leaking how much of the hash matched.
+pub fn verify(salt: &[u8], expected: &[u8], token: &str) -> bool {
+    let actual = hash(salt, token);
+
+    actual.len() == expected.len()
+        && actual
+            .iter()
+            .zip(expected)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_generate_alphanumeric_tokens() {
+        let token = generate();
+
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+
+    #[test]
+    fn it_should_generate_unique_tokens() {
+        assert_ne!(generate(), generate());
+    }
+
+    #[test]
+    fn it_should_return_prefix() {
+        assert_eq!(prefix("abcdefghijkl"), "abcdefgh");
+        assert_eq!(prefix("abc"), "abc");
+        assert_eq!(prefix("æøåæøåæøåæøå"), "æøåæøåæø");
+    }
+
+    #[test]
+    fn it_should_verify_hashed_token() {
+        let token = generate();
+        let salt = generate_salt();
+        let hash = hash(&salt, &token);
+
+        assert!(verify(&salt, &hash, &token));
+        assert!(!verify(&salt, &hash, &generate()));
+        assert!(!verify(&generate_salt(), &hash, &token));
+    }
+
+    #[test]
+    fn it_should_match_postgres_sha256() {
+        // The migration that hashed the existing tokens computed the hashes with:
+        // SELECT sha256(salt || convert_to(token, 'UTF8'))
+        let expected =
+            hex_decode("6c71317dc482e04bde8aac8d2120657e5a2b7d22e266be2dbe630e58931d608a");
+
+        assert_eq!(hash(b"salt", "token"), expected);
+    }
+
+    fn hex_decode(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}